@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
@@ -14,6 +15,12 @@ pub struct ServerConfig {
     pub vad: VadSettings,
     #[serde(default)]
     pub chat: ChatSettings,
+    #[serde(default)]
+    pub endpoint: EndpointSettings,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    #[serde(default)]
+    pub echo: EchoSettings,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,20 +33,103 @@ pub struct ServerSettings {
 pub struct AuthSettings {
     pub enable: bool,
     pub signature_key: String,
+    // WebSocket bearer token check: "none", "static" (`ota.websocket_token`)
+    // or "device" (signed per device and client id, handed out by OTA)
+    #[serde(default = "default_token_mode")]
+    pub token_mode: String,
+    // Lifetime of "device" tokens; devices pick up a fresh one on every OTA check
+    #[serde(default = "default_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    // Bearer token for the `/admin` API; the API is off without one
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+fn default_token_mode() -> String {
+    "none".to_string()
+}
+
+fn default_token_ttl_secs() -> u64 {
+    30 * 24 * 60 * 60
 }
 
 #[derive(Debug, Deserialize)]
 pub struct OtaSettings {
+    // Reported back to devices that have no newer build to fetch
     pub firmware_version: String,
     pub websocket_url: Option<String>,
     pub websocket_token: Option<String>,
     pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub firmware: FirmwareSettings,
+}
+
+/// Firmware repository: binaries at `<dir>/<board type>/<version>.bin`.
+#[derive(Debug, Deserialize)]
+pub struct FirmwareSettings {
+    #[serde(default = "default_firmware_dir")]
+    pub dir: String,
+    // Public base URL for downloads; defaults to the OTA request's Host
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+fn default_firmware_dir() -> String {
+    "firmware".to_string()
+}
+
+impl Default for FirmwareSettings {
+    fn default() -> Self {
+        Self {
+            dir: default_firmware_dir(),
+            base_url: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MqttConfig {
     pub enable: bool,
+    // Broker address handed to devices in the OTA response
     pub endpoint: String,
+    // Where the server itself connects to the broker; defaults to `endpoint`
+    #[serde(default)]
+    pub broker: Option<String>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub udp: UdpSettings,
+}
+
+fn default_mqtt_client_id() -> String {
+    "xiaozhi-server".to_string()
+}
+
+/// UDP socket that carries audio for MQTT sessions.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UdpSettings {
+    #[serde(default = "default_udp_bind")]
+    pub bind: String,
+    // Host devices send audio to; defaults to the broker's host
+    #[serde(default)]
+    pub public_host: Option<String>,
+}
+
+fn default_udp_bind() -> String {
+    "0.0.0.0:8884".to_string()
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        Self {
+            bind: default_udp_bind(),
+            public_host: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,6 +138,9 @@ pub struct LlmSettings {
     #[serde(default = "default_history_limit")]
     pub history_limit: usize,
     pub system_instruction: Option<String>,
+    // Reply in the language detected by STT instead of the one pinned by the prompt
+    #[serde(default)]
+    pub auto_language: bool,
     #[serde(default)]
     pub gemini: Option<GeminiConfig>,
     #[serde(default)]
@@ -111,6 +204,61 @@ pub struct TtsSettings {
     pub gemini: Option<GeminiTtsConfig>,
     #[serde(default)]
     pub edge: Option<EdgeTtsConfig>,
+    #[serde(default)]
+    pub piper: Option<PiperTtsConfig>,
+    #[serde(default)]
+    pub openai: Option<OpenAiTtsConfig>,
+    #[serde(default)]
+    pub cache: TtsCacheSettings,
+    // `[audio.opus]` encoder profile for synthesized speech: "speech" or "music"
+    #[serde(default = "default_tts_opus_profile")]
+    pub opus_profile: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TtsCacheSettings {
+    // Keep synthesized audio on disk and reuse it for identical sentences
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "default_tts_cache_dir")]
+    pub dir: String,
+    // Least recently used entries are removed above this size
+    #[serde(default = "default_tts_cache_max_size")]
+    pub max_size_mb: u64,
+    // Longer sentences (usually one-off LLM replies) are not cached
+    #[serde(default = "default_tts_cache_max_text")]
+    pub max_text_chars: usize,
+    // Extra phrases synthesized at startup, besides the standby prompt and wake greeting
+    #[serde(default)]
+    pub prewarm: Vec<String>,
+}
+
+fn default_tts_opus_profile() -> String {
+    "speech".to_string()
+}
+
+fn default_tts_cache_dir() -> String {
+    "tts_cache".to_string()
+}
+
+fn default_tts_cache_max_size() -> u64 {
+    100
+}
+
+fn default_tts_cache_max_text() -> usize {
+    200
+}
+
+impl Default for TtsCacheSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            dir: default_tts_cache_dir(),
+            max_size_mb: default_tts_cache_max_size(),
+            max_text_chars: default_tts_cache_max_text(),
+            prewarm: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -120,6 +268,9 @@ pub struct GeminiTtsConfig {
     pub model: String,
     #[serde(default = "default_tts_voice")]
     pub voice_name: String,
+    // Language code (e.g. "en") -> voice name, falls back to `voice_name`
+    #[serde(default)]
+    pub voices: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -132,6 +283,91 @@ pub struct EdgeTtsConfig {
     pub pitch: String,
     #[serde(default = "default_edge_volume")]
     pub volume: String,
+    // Language code (e.g. "en") -> voice name, falls back to `voice`
+    #[serde(default)]
+    pub voices: HashMap<String, String>,
+
+    // Emotion ("happy", "sad", "angry") -> speaking style (e.g. "cheerful")
+    #[serde(default)]
+    pub styles: HashMap<String, String>,
+    // Style intensity, 0.01 to 2
+    #[serde(default)]
+    pub style_degree: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PiperTtsConfig {
+    // Path to the voice's .onnx file
+    pub model: String,
+    // Voice config, defaults to `<model>.json`
+    #[serde(default)]
+    pub config: Option<String>,
+    // Multi-speaker voices only
+    #[serde(default)]
+    pub speaker_id: Option<i64>,
+    // Overrides for the voice's inference settings; larger length_scale speaks slower
+    #[serde(default)]
+    pub length_scale: Option<f32>,
+    #[serde(default)]
+    pub noise_scale: Option<f32>,
+    #[serde(default)]
+    pub noise_w: Option<f32>,
+    #[serde(default = "default_espeak_path")]
+    pub espeak_path: String,
+    #[serde(default = "default_piper_threads")]
+    pub threads: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OpenAiTtsConfig {
+    #[serde(default = "default_openai_tts_base_url")]
+    pub base_url: String,
+    // Optional for self-hosted servers
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_openai_tts_model")]
+    pub model: String,
+    #[serde(default = "default_openai_tts_voice")]
+    pub voice: String,
+    #[serde(default)]
+    pub speed: Option<f32>,
+    // "wav", "mp3", "opus" or "pcm"
+    #[serde(default = "default_openai_tts_format")]
+    pub response_format: String,
+    // Sample rate of "pcm" responses (OpenAI sends 24kHz)
+    #[serde(default = "default_openai_tts_pcm_rate")]
+    pub pcm_sample_rate: u32,
+    // Language code (e.g. "en") -> voice name, falls back to `voice`
+    #[serde(default)]
+    pub voices: HashMap<String, String>,
+}
+
+fn default_openai_tts_base_url() -> String {
+    "https://api.openai.com/v1".to_string()
+}
+
+fn default_openai_tts_model() -> String {
+    "tts-1".to_string()
+}
+
+fn default_openai_tts_voice() -> String {
+    "alloy".to_string()
+}
+
+fn default_openai_tts_format() -> String {
+    "wav".to_string()
+}
+
+fn default_openai_tts_pcm_rate() -> u32 {
+    24000
+}
+
+fn default_espeak_path() -> String {
+    "espeak-ng".to_string()
+}
+
+fn default_piper_threads() -> usize {
+    2
 }
 
 fn default_tts_model() -> String {
@@ -179,22 +415,94 @@ impl Default for DbSettings {
     }
 }
 
-#[derive(Debug, Deserialize, Default, Clone)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct VadSettings {
+    // "sensevoice" uses the model's built-in VAD, "silero" runs our own VAD in front of STT
+    #[serde(default = "default_vad_provider")]
+    pub provider: String,
     #[serde(default = "default_silence_duration")]
     pub silence_duration_ms: u32,
+    // Speech probability above which a frame counts as voiced
+    #[serde(default = "default_vad_threshold")]
+    pub threshold: f32,
+    // Voiced audio needed before speech start is reported
+    #[serde(default = "default_min_speech_duration")]
+    pub min_speech_duration_ms: u32,
+    // Audio kept before speech start and after speech end
+    #[serde(default = "default_speech_pad")]
+    pub speech_pad_ms: u32,
+    // Utterances are cut off at this length even if the speaker keeps talking
+    #[serde(default = "default_max_utterance")]
+    pub max_utterance_ms: u32,
+    // Audio heard just before `listen start` that is still sent to STT
+    #[serde(default = "default_pre_roll")]
+    pub pre_roll_ms: u32,
+    // Audio heard while a reply is processed that is sent to STT once listening resumes
+    #[serde(default = "default_handover")]
+    pub handover_ms: u32,
+}
+
+fn default_vad_provider() -> String {
+    "sensevoice".to_string()
 }
 
 fn default_silence_duration() -> u32 {
     2500
 }
 
-#[derive(Debug, Deserialize, Default, Clone)]
+fn default_vad_threshold() -> f32 {
+    0.5
+}
+
+fn default_min_speech_duration() -> u32 {
+    250
+}
+
+fn default_speech_pad() -> u32 {
+    300
+}
+
+fn default_max_utterance() -> u32 {
+    15000
+}
+
+fn default_pre_roll() -> u32 {
+    500
+}
+
+fn default_handover() -> u32 {
+    1500
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        Self {
+            provider: default_vad_provider(),
+            silence_duration_ms: default_silence_duration(),
+            threshold: default_vad_threshold(),
+            min_speech_duration_ms: default_min_speech_duration(),
+            speech_pad_ms: default_speech_pad(),
+            max_utterance_ms: default_max_utterance(),
+            pre_roll_ms: default_pre_roll(),
+            handover_ms: default_handover(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct ChatSettings {
     #[serde(default = "default_max_idle_duration")]
     pub max_idle_duration: u64,
     #[serde(default = "default_standby_prompt")]
     pub standby_prompt: String,
+    // Reply to a wake word: "none", "fixed" (say `wake_greeting`) or "llm" (let the LLM greet)
+    #[serde(default = "default_wake_reply")]
+    pub wake_reply: String,
+    #[serde(default = "default_wake_greeting")]
+    pub wake_greeting: String,
+    // How long to wait for the user to keep talking after the wake word before greeting
+    #[serde(default = "default_wake_grace")]
+    pub wake_grace_ms: u64,
 }
 
 fn default_max_idle_duration() -> u64 {
@@ -205,12 +513,299 @@ fn default_standby_prompt() -> String {
     "我先去休息了，有需要再叫我。".to_string()
 }
 
+fn default_wake_reply() -> String {
+    "none".to_string()
+}
+
+fn default_wake_greeting() -> String {
+    "我在，有什麼事嗎？".to_string()
+}
+
+fn default_wake_grace() -> u64 {
+    1000
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            max_idle_duration: default_max_idle_duration(),
+            standby_prompt: default_standby_prompt(),
+            wake_reply: default_wake_reply(),
+            wake_greeting: default_wake_greeting(),
+            wake_grace_ms: default_wake_grace(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EndpointSettings {
+    // Decide end of turn from the transcript instead of only waiting for silence
+    #[serde(default)]
+    pub enable: bool,
+    // "rules" or "llm" (LLM is asked only when the rules cannot tell)
+    #[serde(default = "default_endpoint_mode")]
+    pub mode: String,
+    // Wait after a complete-looking sentence before replying
+    #[serde(default = "default_complete_delay")]
+    pub complete_delay_ms: u64,
+    // Wait after an incomplete-looking sentence before replying anyway
+    #[serde(default = "default_incomplete_timeout")]
+    pub incomplete_timeout_ms: u64,
+}
+
+fn default_endpoint_mode() -> String {
+    "rules".to_string()
+}
+
+fn default_complete_delay() -> u64 {
+    300
+}
+
+fn default_incomplete_timeout() -> u64 {
+    4000
+}
+
+impl Default for EndpointSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            mode: default_endpoint_mode(),
+            complete_delay_ms: default_complete_delay(),
+            incomplete_timeout_ms: default_incomplete_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AudioSettings {
+    #[serde(default)]
+    pub enhance: EnhanceSettings,
+    // Downlink format sent to devices; unset follows what the device's hello asks for
+    #[serde(default)]
+    pub output_sample_rate: Option<u32>,
+    #[serde(default)]
+    pub output_frame_duration: Option<u32>,
+    #[serde(default)]
+    pub opus: OpusSettings,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OpusSettings {
+    // Encoder profile for spoken replies
+    #[serde(default)]
+    pub speech: OpusEncoderSettings,
+    // Encoder profile for music and other non-speech audio
+    #[serde(default = "default_opus_music")]
+    pub music: OpusEncoderSettings,
+    // Recover a lost uplink packet from the redundancy in the next one
+    #[serde(default = "default_opus_loss_recovery")]
+    pub fec: bool,
+    // Fill lost uplink packets FEC cannot recover with concealment audio
+    #[serde(default = "default_opus_loss_recovery")]
+    pub plc: bool,
+}
+
+fn default_opus_music() -> OpusEncoderSettings {
+    OpusEncoderSettings {
+        signal: "music".to_string(),
+        ..Default::default()
+    }
+}
+
+fn default_opus_loss_recovery() -> bool {
+    true
+}
+
+impl Default for OpusSettings {
+    fn default() -> Self {
+        Self {
+            speech: OpusEncoderSettings::default(),
+            music: default_opus_music(),
+            fec: default_opus_loss_recovery(),
+            plc: default_opus_loss_recovery(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct OpusEncoderSettings {
+    // Bits per second; unset lets libopus choose from the sample rate and frame size
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    #[serde(default = "default_opus_vbr")]
+    pub vbr: bool,
+    // 0 (fastest) to 10 (best quality)
+    #[serde(default = "default_opus_complexity")]
+    pub complexity: u8,
+    // "voice", "music" or "auto"
+    #[serde(default = "default_opus_signal")]
+    pub signal: String,
+    // Send tiny packets during silence
+    #[serde(default)]
+    pub dtx: bool,
+    // Embed a low-bitrate copy of each packet in the next one
+    #[serde(default)]
+    pub inband_fec: bool,
+    // Expected packet loss, which sizes the FEC data
+    #[serde(default)]
+    pub packet_loss_perc: u8,
+}
+
+fn default_opus_vbr() -> bool {
+    true
+}
+
+fn default_opus_complexity() -> u8 {
+    10
+}
+
+fn default_opus_signal() -> String {
+    "voice".to_string()
+}
+
+impl Default for OpusEncoderSettings {
+    fn default() -> Self {
+        Self {
+            bitrate: None,
+            vbr: default_opus_vbr(),
+            complexity: default_opus_complexity(),
+            signal: default_opus_signal(),
+            dtx: false,
+            inband_fec: false,
+            packet_loss_perc: 0,
+        }
+    }
+}
+
+impl OpusSettings {
+    /// Encoder settings for a profile name: "speech" or "music".
+    pub fn profile(&self, name: &str) -> &OpusEncoderSettings {
+        match name {
+            "music" => &self.music,
+            _ => &self.speech,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EnhanceSettings {
+    // Remove rumble and DC offset below `high_pass_hz`
+    #[serde(default)]
+    pub high_pass: bool,
+    #[serde(default = "default_high_pass_hz")]
+    pub high_pass_hz: f32,
+    // RNNoise noise suppression
+    #[serde(default)]
+    pub denoise: bool,
+    // Automatic gain control for quiet or distant speakers
+    #[serde(default)]
+    pub agc: bool,
+    #[serde(default = "default_agc_target_dbfs")]
+    pub agc_target_dbfs: f32,
+    #[serde(default = "default_agc_max_gain_db")]
+    pub agc_max_gain_db: f32,
+}
+
+fn default_high_pass_hz() -> f32 {
+    100.0
+}
+
+fn default_agc_target_dbfs() -> f32 {
+    -20.0
+}
+
+fn default_agc_max_gain_db() -> f32 {
+    30.0
+}
+
+impl Default for EnhanceSettings {
+    fn default() -> Self {
+        Self {
+            high_pass: false,
+            high_pass_hz: default_high_pass_hz(),
+            denoise: false,
+            agc: false,
+            agc_target_dbfs: default_agc_target_dbfs(),
+            agc_max_gain_db: default_agc_max_gain_db(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EchoSettings {
+    // Drop transcripts of our own TTS picked up by devices without AEC
+    #[serde(default)]
+    pub enable: bool,
+    // How long after playback ends transcripts are still checked
+    #[serde(default = "default_echo_window")]
+    pub window_ms: u64,
+    // Share of the transcript's character pairs found in recent speech to count as echo
+    #[serde(default = "default_echo_text_similarity")]
+    pub text_similarity: f32,
+    // Also mute microphone audio that follows the envelope of our playback
+    #[serde(default)]
+    pub audio: bool,
+    #[serde(default = "default_echo_audio_correlation")]
+    pub audio_correlation: f32,
+    // Longest playback-to-microphone delay searched
+    #[serde(default = "default_echo_max_delay")]
+    pub max_delay_ms: u64,
+}
+
+fn default_echo_window() -> u64 {
+    2000
+}
+
+fn default_echo_text_similarity() -> f32 {
+    0.6
+}
+
+fn default_echo_audio_correlation() -> f32 {
+    0.7
+}
+
+fn default_echo_max_delay() -> u64 {
+    500
+}
+
+impl Default for EchoSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            window_ms: default_echo_window(),
+            text_similarity: default_echo_text_similarity(),
+            audio: false,
+            audio_correlation: default_echo_audio_correlation(),
+            max_delay_ms: default_echo_max_delay(),
+        }
+    }
+}
+
 impl ServerConfig {
     pub fn new() -> Result<Self, config::ConfigError> {
         let builder = config::Config::builder()
             .add_source(config::File::with_name("settings.toml").required(false))
             .add_source(config::Environment::with_prefix("XIAOZHI").separator("__"));
 
-        builder.build()?.try_deserialize()
+        let config: Self = builder.build()?.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Catches settings that would otherwise only fail once services start
+    fn validate(&self) -> Result<(), config::ConfigError> {
+        if !matches!(self.vad.provider.as_str(), "sensevoice" | "silero") {
+            return Err(config::ConfigError::Message(format!(
+                "Unknown vad.provider '{}' (expected \"sensevoice\" or \"silero\")",
+                self.vad.provider
+            )));
+        }
+        // Device tokens only go to activated devices, which needs activation on
+        if self.auth.token_mode == "device" && !self.auth.enable {
+            return Err(config::ConfigError::Message(
+                "auth.token_mode \"device\" requires auth.enable = true".to_string(),
+            ));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,162 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::handlers::ota::bind_with_code;
+use crate::handlers::ota_types::BindRequest;
+use crate::services::auth::{bearer_token, secret_matches};
+use crate::state::AppState;
+use crate::traits::DeviceFilter;
+
+// Admin routes take `Authorization: Bearer <auth.admin_token>`. The rejection
+// is boxed to keep the `Result` small; callers return `*rejection`.
+pub(crate) fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), Box<Response>> {
+    let Some(expected) = state.config.auth.admin_token.as_deref() else {
+        return Err(Box::new(
+            (StatusCode::NOT_FOUND, "Admin API disabled").into_response(),
+        ));
+    };
+    if !secret_matches(bearer_token(headers), expected) {
+        return Err(Box::new(
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Invalid admin token",
+            )
+                .into_response(),
+        ));
+    }
+    Ok(())
+}
+
+/// `GET /admin/devices?board=..&firmware_version=..`: the device inventory
+/// built from OTA reports, most recently seen first.
+pub async fn handle_list_devices(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(filter): Query<DeviceFilter>,
+) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    match state.db.list_devices(&filter).await {
+        Ok(devices) => Json(devices).into_response(),
+        Err(e) => {
+            error!("DB Error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// `POST /admin/devices/{device_id}/revoke`: refuse the device's WebSocket
+/// connections and stop issuing it tokens.
+pub async fn handle_revoke(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    match state.db.revoke_device(&device_id).await {
+        Ok(()) => {
+            info!("Revoked device {}", device_id);
+            Json(json!({ "device_id": device_id, "revoked": true })).into_response()
+        }
+        Err(e) => {
+            error!("DB Error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// `DELETE /admin/devices/{device_id}/revoke`: undo a revocation. The device
+/// gets a new token on its next OTA check.
+pub async fn handle_restore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    match state.db.restore_device(&device_id).await {
+        Ok(()) => {
+            info!("Restored device {}", device_id);
+            Json(json!({ "device_id": device_id, "revoked": false })).into_response()
+        }
+        Err(e) => {
+            error!("DB Error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// `POST /admin/devices/bind`: activate the device showing `code` and record
+/// `owner` as its owner, without the rate limit of the public `/api/devices/bind`.
+pub async fn handle_bind(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<BindRequest>,
+) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    bind_with_code(&state, &req).await
+}
+
+/// `GET /admin/firmware`: builds in the firmware repository.
+pub async fn handle_list_firmware(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    Json(state.firmware.builds()).into_response()
+}
+
+/// `PUT /admin/firmware/{board}/{version}`: upload a binary for one board type.
+pub async fn handle_upload_firmware(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((board, version)): Path<(String, String)>,
+    body: Bytes,
+) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Empty firmware image").into_response();
+    }
+    match state.firmware.store(&board, &version, &body).await {
+        Ok(()) => {
+            info!("Stored firmware {} for {}", version, board);
+            Json(state.firmware.builds()).into_response()
+        }
+        Err(e) => {
+            error!("Failed to store firmware: {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+/// `POST /admin/firmware/reload`: rescan after binaries were copied in by hand.
+pub async fn handle_reload_firmware(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    match state.firmware.reload().await {
+        Ok(_) => Json(state.firmware.builds()).into_response(),
+        Err(e) => {
+            error!("Failed to reload firmware: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to reload firmware",
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::handlers::admin::check_admin;
+use crate::services::language::detect_language;
+use crate::session::generate_reply;
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct ChatRequest {
+    pub text: String,
+    // Chat history is kept per id, apart from real devices' histories
+    #[serde(default = "default_api_device_id")]
+    pub device_id: String,
+}
+
+fn default_api_device_id() -> String {
+    "api".to_string()
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChatResponse {
+    pub text: String,
+    pub emotion: Option<String>,
+    pub sleep: bool,
+}
+
+/// Sends typed text through the conversation pipeline and returns the reply as text.
+///
+/// Meant for scripting and debugging; device MCP tools are not available here.
+/// Takes the admin token, since replies cost LLM calls.
+pub async fn handle_chat(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ChatRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    if req.text.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Empty text").into_response();
+    }
+
+    // Namespaced so a caller cannot read or extend a device's conversation
+    let history_id = format!("api:{}", req.device_id);
+    let language = detect_language(&req.text);
+    match generate_reply(&state, &req.text, language, &history_id, None, None).await {
+        Some(reply) => Json(ChatResponse {
+            text: reply.display_text,
+            emotion: reply.emotion,
+            sleep: reply.should_sleep,
+        })
+        .into_response(),
+        None => (
+            StatusCode::BAD_GATEWAY,
+            "Failed to get a reply from the LLM",
+        )
+            .into_response(),
+    }
+}
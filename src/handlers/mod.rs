@@ -1,3 +1,7 @@
+pub mod admin;
+pub mod chat;
+pub mod mqtt;
 pub mod ota;
 pub mod ota_types;
+pub mod transcription;
 pub mod websocket;
@@ -0,0 +1,735 @@
+use rand::Rng;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, warn};
+
+use crate::services::udp_audio::UdpAudioCipher;
+use crate::session::events::{Inbound, Outbound};
+use crate::session::messages::{ClientMessage, ServerMessage, UdpParams};
+use crate::session::Session;
+use crate::state::AppState;
+
+// Largest datagram we expect: nonce plus one Opus packet
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Topic a device publishes its messages on.
+pub fn publish_topic(device_id: &str) -> String {
+    format!("device/{}/pub", device_id)
+}
+
+/// Topic a device listens on for our messages.
+pub fn subscribe_topic(device_id: &str) -> String {
+    format!("device/{}/sub", device_id)
+}
+
+fn device_of(topic: &str) -> Option<&str> {
+    topic.strip_prefix("device/")?.strip_suffix("/pub")
+}
+
+// Host, port and whether to use TLS, from `ssl://host:port` style addresses
+fn parse_broker(address: &str) -> (String, u16, bool) {
+    let (rest, tls) = match address.split_once("://") {
+        Some(("ssl" | "mqtts" | "tls", rest)) => (rest, true),
+        Some((_, rest)) => (rest, false),
+        None => (address, false),
+    };
+    let default_port = if tls { 8883 } else { 1883 };
+    match rest.rsplit_once(':') {
+        Some((host, port)) => (host.to_string(), port.parse().unwrap_or(default_port), tls),
+        None => (rest.to_string(), default_port, tls),
+    }
+}
+
+struct MqttSession {
+    session_id: String,
+    inbound: Sender<Inbound>,
+}
+
+// Audio side of an MQTT session, found by the ssrc in each datagram's nonce
+struct UdpChannel {
+    device_id: String,
+    cipher: UdpAudioCipher,
+    inbound: Sender<Inbound>,
+    // Learned from the device's datagrams; it may move behind NAT
+    peer: Mutex<Option<SocketAddr>>,
+    sequence: AtomicU32,
+}
+
+#[derive(Clone)]
+struct Gateway {
+    state: AppState,
+    client: AsyncClient,
+    socket: Arc<UdpSocket>,
+    udp_host: String,
+    udp_port: u16,
+    sessions: Arc<Mutex<HashMap<String, MqttSession>>>,
+    channels: Arc<Mutex<HashMap<u32, Arc<UdpChannel>>>>,
+}
+
+/// Serves devices that connect through an MQTT broker instead of a WebSocket.
+///
+/// Control messages travel over the broker on the topics handed out by OTA;
+/// audio travels over an encrypted UDP channel set up in the hello reply.
+/// Each device session runs the same loop as a WebSocket connection.
+pub async fn run_gateway(state: AppState) {
+    let config = &state.config.ota.mqtt;
+    let broker = config.broker.as_deref().unwrap_or(&config.endpoint);
+    let (host, port, tls) = parse_broker(broker);
+
+    let mut options = MqttOptions::new(config.client_id.clone(), host.clone(), port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        );
+    }
+    if tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 256);
+
+    let socket = match UdpSocket::bind(&config.udp.bind).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            error!("Failed to bind UDP socket {}: {}", config.udp.bind, e);
+            return;
+        }
+    };
+    let udp_port = socket.local_addr().map(|a| a.port()).unwrap_or_default();
+    let udp_host = config.udp.public_host.clone().unwrap_or(host);
+    info!(
+        "UDP audio listening on {} (advertised as {}:{})",
+        config.udp.bind, udp_host, udp_port
+    );
+
+    let gateway = Gateway {
+        state: state.clone(),
+        client: client.clone(),
+        socket,
+        udp_host,
+        udp_port,
+        sessions: Arc::new(Mutex::new(HashMap::new())),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+    };
+    tokio::spawn(gateway.clone().receive_udp());
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                // Clean sessions lose their subscriptions on reconnect
+                if let Err(e) = client.subscribe(publish_topic("+"), QoS::AtLeastOnce).await {
+                    error!("Failed to subscribe to device topics: {}", e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                gateway.handle_publish(&publish.topic, &publish.payload);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+impl Gateway {
+    fn handle_publish(&self, topic: &str, payload: &[u8]) {
+        let Some(device_id) = device_of(topic) else {
+            return;
+        };
+        let message: Value = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Ignoring malformed MQTT message from {}: {}", device_id, e);
+                return;
+            }
+        };
+        info!("Received MQTT message from {}: {}", device_id, message);
+
+        // Goodbye only exists on this transport; WebSocket devices just disconnect
+        if message["type"] == "goodbye" {
+            info!("Device {} said goodbye.", device_id);
+            let session = self.sessions.lock().unwrap().remove(device_id);
+            if let Some(session) = session {
+                let _ = session.inbound.try_send(Inbound::Close);
+            }
+            return;
+        }
+
+        let message = match serde_json::from_value::<ClientMessage>(message) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error deserializing message: {}", e);
+                return;
+            }
+        };
+        if matches!(message, ClientMessage::Hello { .. }) {
+            self.start_session(device_id, message);
+            return;
+        }
+
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(device_id) {
+            Some(session) => {
+                if session.inbound.try_send(Inbound::Message(message)).is_err() {
+                    warn!(
+                        "Session for {} is not keeping up; dropping message",
+                        device_id
+                    );
+                }
+            }
+            None => warn!(
+                "Message from {} without a session; send hello first",
+                device_id
+            ),
+        }
+    }
+
+    fn start_session(&self, device_id: &str, hello: ClientMessage) {
+        let device_id = device_id.to_string();
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel::<Inbound>(256);
+        let (tx, rx) = tokio::sync::mpsc::channel::<Outbound>(256);
+
+        let ssrc = {
+            let mut channels = self.channels.lock().unwrap();
+            let ssrc = loop {
+                let ssrc: u32 = rand::rng().random();
+                if !channels.contains_key(&ssrc) {
+                    break ssrc;
+                }
+            };
+            channels.insert(
+                ssrc,
+                Arc::new(UdpChannel {
+                    device_id: device_id.clone(),
+                    cipher: UdpAudioCipher::new(ssrc),
+                    inbound: inbound_tx.clone(),
+                    peer: Mutex::new(None),
+                    sequence: AtomicU32::new(0),
+                }),
+            );
+            ssrc
+        };
+
+        // A new hello replaces whatever session the device had
+        let previous = self.sessions.lock().unwrap().insert(
+            device_id.clone(),
+            MqttSession {
+                session_id: session_id.clone(),
+                inbound: inbound_tx.clone(),
+            },
+        );
+        if let Some(previous) = previous {
+            let _ = previous.inbound.try_send(Inbound::Close);
+        }
+        info!("MQTT session {} started for {}", session_id, device_id);
+
+        let _ = inbound_tx.try_send(Inbound::Message(hello));
+
+        let writer_handle = tokio::spawn(self.clone().write_outbound(
+            rx,
+            device_id.clone(),
+            session_id.clone(),
+            ssrc,
+        ));
+        let gateway = self.clone();
+        tokio::spawn(async move {
+            Session::new(gateway.state.clone(), device_id.clone(), "udp", tx)
+                .run(Box::pin(ReceiverStream::new(inbound_rx)))
+                .await;
+            writer_handle.abort();
+            gateway.end_session(&device_id, &session_id, ssrc).await;
+        });
+    }
+
+    async fn end_session(&self, device_id: &str, session_id: &str, ssrc: u32) {
+        self.channels.lock().unwrap().remove(&ssrc);
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            // The device may already have started a newer one
+            if sessions
+                .get(device_id)
+                .is_some_and(|s| s.session_id == session_id)
+            {
+                sessions.remove(device_id);
+            }
+        }
+
+        let goodbye = json!({ "type": "goodbye", "session_id": session_id });
+        if let Err(e) = self
+            .client
+            .publish(
+                subscribe_topic(device_id),
+                QoS::AtLeastOnce,
+                false,
+                goodbye.to_string(),
+            )
+            .await
+        {
+            error!("Failed to send goodbye to {}: {}", device_id, e);
+        }
+        info!("MQTT session {} for {} closed.", session_id, device_id);
+    }
+
+    // Sends the session's output: JSON over the broker, audio over UDP
+    async fn write_outbound(
+        self,
+        mut rx: Receiver<Outbound>,
+        device_id: String,
+        session_id: String,
+        ssrc: u32,
+    ) {
+        let topic = subscribe_topic(&device_id);
+        let Some(channel) = self.channels.lock().unwrap().get(&ssrc).cloned() else {
+            return;
+        };
+
+        while let Some(event) = rx.recv().await {
+            match event {
+                Outbound::Message(mut message) => {
+                    if let ServerMessage::Hello {
+                        session_id: hello_session_id,
+                        udp,
+                        ..
+                    } = &mut message
+                    {
+                        *hello_session_id = Some(session_id.clone());
+                        *udp = Some(UdpParams {
+                            server: self.udp_host.clone(),
+                            port: self.udp_port,
+                            key: channel.cipher.key_hex(),
+                            nonce: channel.cipher.nonce_hex(),
+                        });
+                    }
+                    let payload = serde_json::to_string(&message).expect("Serialize failed");
+                    if let Err(e) = self
+                        .client
+                        .publish(topic.as_str(), QoS::AtMostOnce, false, payload)
+                        .await
+                    {
+                        error!("Failed to publish to {}: {}", topic, e);
+                        break;
+                    }
+                }
+                Outbound::Audio(packet) => {
+                    let Some(peer) = *channel.peer.lock().unwrap() else {
+                        debug!("No UDP address for {} yet; dropping audio", device_id);
+                        continue;
+                    };
+                    let sequence = channel.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+                    let datagram = channel.cipher.seal(&packet, sequence);
+                    if let Err(e) = self.socket.send_to(&datagram, peer).await {
+                        warn!("Failed to send UDP audio to {}: {}", peer, e);
+                    }
+                }
+                // The session ending is reported by `end_session`
+                Outbound::Close => {}
+            }
+        }
+    }
+
+    async fn receive_udp(self) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("UDP receive error: {}", e);
+                    continue;
+                }
+            };
+            let datagram = &buf[..len];
+            let Some(ssrc) = UdpAudioCipher::ssrc_of(datagram) else {
+                continue;
+            };
+            let Some(channel) = self.channels.lock().unwrap().get(&ssrc).cloned() else {
+                debug!("UDP packet from {} for unknown session {:08x}", from, ssrc);
+                continue;
+            };
+
+            match channel.cipher.open(datagram) {
+                Ok(packet) => {
+                    *channel.peer.lock().unwrap() = Some(from);
+                    if channel.inbound.try_send(Inbound::Audio(packet)).is_err() {
+                        debug!(
+                            "Session for {} is not keeping up; dropping audio",
+                            channel.device_id
+                        );
+                    }
+                }
+                Err(e) => warn!("Malformed UDP packet from {}: {}", from, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Runs the gateway against a minimal in-process broker, with the test
+    // playing a device on both the broker and the UDP channel.
+
+    use super::*;
+    use aes::Aes128;
+    use bytes::BytesMut;
+    use ctr::cipher::{KeyIvInit, StreamCipher};
+    use rumqttc::{
+        ConnAck, ConnectReturnCode, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    use crate::config::OpusEncoderSettings;
+    use crate::services::audio::opus_codec::{OpusService, StreamFormat};
+    use crate::session::tests::{scripted_services, ScriptedServices};
+    use crate::traits::SttEvent;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type Subscriptions = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<BytesMut>)>>>;
+
+    // Just enough of MQTT 3.1.1 for one gateway and one device: every publish
+    // goes out at QoS 0 to each matching subscription.
+    struct Broker {
+        port: u16,
+        subscriptions: Subscriptions,
+    }
+
+    impl Broker {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let subscriptions = Subscriptions::default();
+            let accepted = subscriptions.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(Self::serve(stream, accepted.clone()));
+                }
+            });
+            Self {
+                port,
+                subscriptions,
+            }
+        }
+
+        fn has_subscription(&self, filter: &str) -> bool {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(f, _)| f == filter)
+        }
+
+        async fn serve(stream: TcpStream, subscriptions: Subscriptions) {
+            let (mut reader, mut writer) = stream.into_split();
+            let (tx, mut rx) = mpsc::unbounded_channel::<BytesMut>();
+            tokio::spawn(async move {
+                while let Some(bytes) = rx.recv().await {
+                    if writer.write_all(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut buf = BytesMut::new();
+            loop {
+                let packet = match rumqttc::read(&mut buf, 1 << 20) {
+                    Ok(packet) => packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        if reader.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("Broker got a malformed packet: {:?}", e),
+                };
+
+                let mut out = BytesMut::new();
+                match packet {
+                    Packet::Connect(_) => {
+                        ConnAck::new(ConnectReturnCode::Success, false)
+                            .write(&mut out)
+                            .unwrap();
+                    }
+                    Packet::Subscribe(subscribe) => {
+                        let mut codes = Vec::new();
+                        for filter in subscribe.filters {
+                            codes.push(SubscribeReasonCode::Success(filter.qos));
+                            subscriptions
+                                .lock()
+                                .unwrap()
+                                .push((filter.path, tx.clone()));
+                        }
+                        SubAck::new(subscribe.pkid, codes).write(&mut out).unwrap();
+                    }
+                    Packet::Publish(publish) => {
+                        if publish.qos == QoS::AtLeastOnce {
+                            PubAck::new(publish.pkid).write(&mut out).unwrap();
+                        }
+                        let forward = Publish::new(
+                            publish.topic.as_str(),
+                            QoS::AtMostOnce,
+                            publish.payload.to_vec(),
+                        );
+                        for (filter, subscriber) in subscriptions.lock().unwrap().iter() {
+                            if rumqttc::matches(&publish.topic, filter) {
+                                let mut bytes = BytesMut::new();
+                                forward.write(&mut bytes).unwrap();
+                                let _ = subscriber.send(bytes);
+                            }
+                        }
+                    }
+                    Packet::PingReq => {
+                        PingResp.write(&mut out).unwrap();
+                    }
+                    Packet::Disconnect => return,
+                    _ => {}
+                }
+                if !out.is_empty() && tx.send(out).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // The device's side: JSON over the broker, encrypted audio over UDP
+    struct Device {
+        client: AsyncClient,
+        messages: mpsc::UnboundedReceiver<Value>,
+        socket: UdpSocket,
+    }
+
+    impl Device {
+        async fn connect(broker: &Broker, device_id: &str) -> Self {
+            let options = MqttOptions::new(device_id, "127.0.0.1", broker.port);
+            let (client, mut eventloop) = AsyncClient::new(options, 16);
+            client
+                .subscribe(subscribe_topic(device_id), QoS::AtMostOnce)
+                .await
+                .unwrap();
+            let (tx, messages) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Ok(event) = eventloop.poll().await {
+                    if let Event::Incoming(Packet::Publish(publish)) = event {
+                        let _ = tx.send(serde_json::from_slice(&publish.payload).unwrap());
+                    }
+                }
+            });
+            Self {
+                client,
+                messages,
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            }
+        }
+
+        async fn publish(&self, device_id: &str, message: Value) {
+            self.client
+                .publish(
+                    publish_topic(device_id),
+                    QoS::AtLeastOnce,
+                    false,
+                    message.to_string(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Skips everything until a message of the given type arrives
+        async fn expect(&mut self, kind: &str) -> Value {
+            tokio::time::timeout(TIMEOUT, async {
+                loop {
+                    let message = self.messages.recv().await.expect("Device disconnected");
+                    if message["type"] == kind {
+                        return message;
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("No {} message", kind))
+        }
+
+        async fn receive_datagram(&self) -> Vec<u8> {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let len = tokio::time::timeout(TIMEOUT, self.socket.recv(&mut buf))
+                .await
+                .expect("No UDP audio")
+                .unwrap();
+            buf.truncate(len);
+            buf
+        }
+    }
+
+    // What the firmware does with the key and nonce from the hello reply: set
+    // the payload length, timestamp and sequence, then AES-128-CTR the payload
+    // with the nonce as counter block. Decryption is the same operation.
+    fn device_crypt(udp: &Value, nonce: &mut [u8], payload: &mut [u8]) {
+        let key = hex::decode(udp["key"].as_str().unwrap()).unwrap();
+        ctr::Ctr128BE::<Aes128>::new(key.as_slice().into(), (&*nonce).into())
+            .apply_keystream(payload);
+    }
+
+    fn device_seal(udp: &Value, payload: &[u8], timestamp: u32, sequence: u32) -> Vec<u8> {
+        let mut nonce = hex::decode(udp["nonce"].as_str().unwrap()).unwrap();
+        nonce[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        nonce[8..12].copy_from_slice(&timestamp.to_be_bytes());
+        nonce[12..16].copy_from_slice(&sequence.to_be_bytes());
+        let mut payload = payload.to_vec();
+        device_crypt(udp, &mut nonce, &mut payload);
+        [nonce, payload].concat()
+    }
+
+    async fn eventually(what: &str, condition: impl Fn() -> bool) {
+        tokio::time::timeout(TIMEOUT, async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timed out waiting for {}", what));
+    }
+
+    fn gateway_settings(broker: &Broker) -> String {
+        format!(
+            r#"
+            [ota.mqtt]
+            enable = true
+            endpoint = "mqtt://127.0.0.1:{}"
+            client_id = "gateway-test"
+
+            [ota.mqtt.udp]
+            bind = "127.0.0.1:0"
+            public_host = "127.0.0.1"
+            "#,
+            broker.port
+        )
+    }
+
+    #[test]
+    fn parses_broker_addresses() {
+        assert_eq!(
+            parse_broker("mqtt://example.com"),
+            ("example.com".to_string(), 1883, false)
+        );
+        assert_eq!(
+            parse_broker("ssl://example.com:8884"),
+            ("example.com".to_string(), 8884, true)
+        );
+        assert_eq!(
+            parse_broker("example.com:1884"),
+            ("example.com".to_string(), 1884, false)
+        );
+        assert_eq!(device_of("device/abc/pub"), Some("abc"));
+        assert_eq!(device_of("device/abc/sub"), None);
+    }
+
+    #[tokio::test]
+    async fn serves_a_device_over_the_broker_and_udp() {
+        let broker = Broker::start().await;
+        let ScriptedServices { state, stt, heard } = scripted_services(&gateway_settings(&broker));
+        tokio::spawn(run_gateway(state));
+        eventually("the gateway to subscribe", || {
+            broker.has_subscription("device/+/pub")
+        })
+        .await;
+
+        let mut device = Device::connect(&broker, "dev1").await;
+        device
+            .publish(
+                "dev1",
+                json!({
+                    "type": "hello",
+                    "version": 3,
+                    "transport": "udp",
+                    "audio_params": {
+                        "format": "opus",
+                        "sample_rate": 16000,
+                        "channels": 1,
+                        "frame_duration": 60
+                    }
+                }),
+            )
+            .await;
+        let hello = device.expect("hello").await;
+        let session_id = hello["session_id"].as_str().unwrap().to_string();
+        let udp = hello["udp"].clone();
+        assert_eq!(udp["server"], "127.0.0.1");
+        let server: SocketAddr = format!("127.0.0.1:{}", udp["port"]).parse().unwrap();
+        let ssrc = UdpAudioCipher::ssrc_of(&hex::decode(udp["nonce"].as_str().unwrap()).unwrap())
+            .expect("Audio nonce template");
+
+        device
+            .publish(
+                "dev1",
+                json!({ "type": "listen", "session_id": session_id, "state": "start", "mode": "auto" }),
+            )
+            .await;
+
+        // Uplink: one 60ms packet of a 440Hz tone
+        let tone: Vec<i16> = (0..960)
+            .map(|i| ((i as f32 * 440.0 * std::f32::consts::TAU / 16000.0).sin() * 3000.0) as i16)
+            .collect();
+        let frames = OpusService::encode_frames_with(
+            &tone,
+            &StreamFormat::default(),
+            &OpusEncoderSettings::default(),
+        )
+        .unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            let datagram = device_seal(&udp, frame, i as u32 * 60, i as u32 + 1);
+            device.socket.send_to(&datagram, server).await.unwrap();
+        }
+        eventually("the session to hear the uplink audio", || {
+            heard.load(Ordering::SeqCst) > 0
+        })
+        .await;
+
+        // Downlink: the reply's three packets, numbered from 1
+        stt.send(SttEvent::Text {
+            text: "你好".to_string(),
+            language: Some("zh".to_string()),
+        })
+        .await
+        .unwrap();
+        stt.send(SttEvent::NoSpeech).await.unwrap();
+        device.expect("tts").await;
+        for sequence in 1..=3u32 {
+            let datagram = device.receive_datagram().await;
+            assert_eq!(UdpAudioCipher::ssrc_of(&datagram), Some(ssrc));
+            let (nonce, payload) = datagram.split_at(16);
+            assert_eq!(
+                u32::from_be_bytes(nonce[12..16].try_into().unwrap()),
+                sequence
+            );
+            assert_eq!(
+                u16::from_be_bytes([nonce[2], nonce[3]]) as usize,
+                payload.len()
+            );
+
+            let mut nonce = nonce.to_vec();
+            let mut payload = payload.to_vec();
+            device_crypt(&udp, &mut nonce, &mut payload);
+            assert_eq!(OpusService::decode(&payload).unwrap().len(), 960);
+        }
+
+        device
+            .publish(
+                "dev1",
+                json!({ "type": "goodbye", "session_id": session_id }),
+            )
+            .await;
+        let goodbye = device.expect("goodbye").await;
+        assert_eq!(goodbye["session_id"], session_id.as_str());
+    }
+}
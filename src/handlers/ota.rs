@@ -1,8 +1,11 @@
 use axum::{
-    extract::{Request, State},
+    body::Bytes,
+    extract::{ConnectInfo, State},
     http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Json},
+    response::{IntoResponse, Json, Response},
 };
+use serde_json::json;
+use std::net::SocketAddr;
 use std::time::SystemTime;
 // use uuid::Uuid; // Unused
 use hex;
@@ -10,9 +13,14 @@ use hmac::{Hmac, Mac};
 use rand::{distr::Alphanumeric, Rng};
 use sha2::Sha256;
 
+use crate::handlers::mqtt;
 use crate::handlers::ota_types::*;
+use crate::services::auth::issue_device_token;
 use crate::state::AppState;
 
+// How long a challenge and activation code stay valid
+const ACTIVATION_TTL_SECS: u64 = 300;
+
 /// Handles OTA (Over-The-Air) update requests from the device.
 ///
 /// This endpoint provides the device with:
@@ -24,7 +32,7 @@ use crate::state::AppState;
 pub async fn handle_ota(
     State(state): State<AppState>,
     headers: HeaderMap,
-    _request: Request,
+    body: Bytes,
 ) -> impl IntoResponse {
     let device_id = match headers.get("Device-Id") {
         Some(v) => v.to_str().unwrap_or("").to_string(),
@@ -35,6 +43,25 @@ pub async fn handle_ota(
         None => return (StatusCode::BAD_REQUEST, "Missing Client-Id").into_response(),
     };
 
+    // Device report for the inventory; a bad body must not lock the device out
+    let report: OtaRequest = if body.is_empty() {
+        OtaRequest::default()
+    } else {
+        serde_json::from_slice(&body).unwrap_or_else(|e| {
+            tracing::warn!("Unparseable OTA body from {}: {}", device_id, e);
+            OtaRequest::default()
+        })
+    };
+    let board_type = report.board.as_ref().and_then(|b| b.type_.clone());
+    let app_version = report.app_version().map(str::to_string);
+    if let Err(e) = state
+        .db
+        .upsert_device(&report.into_record(&device_id, &client_id))
+        .await
+    {
+        tracing::error!("Failed to record device {}: {}", device_id, e);
+    }
+
     // Auth Check
     let mut activation_info: Option<ActivationInfo> = None;
     if state.config.auth.enable {
@@ -51,34 +78,94 @@ pub async fn handle_ota(
 
             // Add challenge to DB
             // Ignore errors for now or log them
-            if let Err(e) = state.db.add_challenge(&device_id, &challenge, 300).await {
+            if let Err(e) = state
+                .db
+                .add_challenge(&device_id, &challenge, ACTIVATION_TTL_SECS)
+                .await
+            {
                 tracing::error!("Failed to save challenge: {}", e);
             }
 
+            let code = match activation_code(&state, &device_id).await {
+                Ok(code) => code,
+                Err(e) => {
+                    tracing::error!("Failed to issue activation code: {}", e);
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Failed to issue activation code",
+                    )
+                        .into_response();
+                }
+            };
             activation_info = Some(ActivationInfo {
-                code: "0".to_string(), // Placeholder
-                message: "Device not activated".to_string(),
+                message: format!("Enter code {} to bind this device", code),
+                code,
                 challenge,
-                timeout_ms: 300000,
+                timeout_ms: ACTIVATION_TTL_SECS * 1000,
             });
         }
     }
 
     let ota_config = &state.config.ota;
+    let host = headers
+        .get("Host")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost:8002"); // Fallback
 
     // WebSocket URL construction
     let websocket_url = if let Some(url) = &ota_config.websocket_url {
         url.clone()
     } else {
         // Construct dynamic URL from Host header
-        let host = headers
-            .get("Host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("localhost:8002"); // Fallback
         format!("ws://{}/xiaozhi/v1/", host)
     };
 
-    let websocket_token = ota_config.websocket_token.clone().unwrap_or_default();
+    // Offer the newest build for the device's board, if it beats what it runs
+    let update = match (&board_type, &app_version) {
+        (Some(board), Some(current)) => state.firmware.newer_than(board, current),
+        _ => None,
+    };
+    let firmware_info = match update {
+        Some(build) => {
+            let base_url = match &ota_config.firmware.base_url {
+                Some(url) => url.trim_end_matches('/').to_string(),
+                None => format!("http://{}", host),
+            };
+            tracing::info!(
+                "Offering firmware {} to {} ({:?} -> {})",
+                build.path(),
+                device_id,
+                app_version,
+                build.version
+            );
+            FirmwareInfo {
+                version: build.version.to_string(),
+                url: format!("{}/firmware/{}", base_url, build.path()),
+            }
+        }
+        None => FirmwareInfo {
+            version: ota_config.firmware_version.clone(),
+            url: "".to_string(),
+        },
+    };
+
+    let websocket_token = if state.config.auth.token_mode == "device" {
+        match may_hold_token(&state, &device_id, &client_id).await {
+            Ok(true) => issue_device_token(
+                &state.config.auth.signature_key,
+                &device_id,
+                &client_id,
+                state.config.auth.token_ttl_secs,
+            ),
+            Ok(false) => String::new(),
+            Err(e) => {
+                tracing::error!("DB Error: {}", e);
+                String::new()
+            }
+        }
+    } else {
+        ota_config.websocket_token.clone().unwrap_or_default()
+    };
 
     // MQTT Info Construction
     let mqtt_info = if ota_config.mqtt.enable {
@@ -97,8 +184,8 @@ pub async fn handle_ota(
             client_id: format!("{}_client", device_id),
             username: device_id.clone(),
             password,
-            publish_topic: format!("device/{}/pub", device_id),
-            subscribe_topic: format!("device/{}/sub", device_id),
+            publish_topic: mqtt::publish_topic(&device_id),
+            subscribe_topic: mqtt::subscribe_topic(&device_id),
         })
     } else {
         None
@@ -119,10 +206,7 @@ pub async fn handle_ota(
             time_zone: "Asia/Shanghai".to_string(),
         },
         activation: activation_info,
-        firmware: FirmwareInfo {
-            version: ota_config.firmware_version.clone(),
-            url: "".to_string(),
-        },
+        firmware: firmware_info,
     };
 
     // Log the response payload for debugging
@@ -131,10 +215,105 @@ pub async fn handle_ota(
     Json(response).into_response()
 }
 
+// Anyone can claim a Device-Id here, so tokens only go to devices that were
+// activated (owner bind, then the HMAC challenge), for the Client-Id they were
+// activated with, and not revoked since
+async fn may_hold_token(
+    state: &AppState,
+    device_id: &str,
+    client_id: &str,
+) -> anyhow::Result<bool> {
+    if !state.db.is_activated(device_id).await? || state.db.is_revoked(device_id).await? {
+        return Ok(false);
+    }
+    Ok(state
+        .db
+        .get_device(device_id)
+        .await?
+        .is_some_and(|device| device.client_id == client_id))
+}
+
+// The device's current six-digit code; a new one once the old has expired.
+// Devices poll OTA while waiting, so the code has to stay put in between.
+async fn activation_code(state: &AppState, device_id: &str) -> anyhow::Result<String> {
+    if let Some(code) = state.db.get_activation_code(device_id).await? {
+        return Ok(code);
+    }
+    // Retry on the rare clash with another waiting device; a code that failed
+    // to insert belongs to someone else and must never be shown
+    for _ in 0..20 {
+        let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+        if state
+            .db
+            .add_activation_code(device_id, &code, ACTIVATION_TTL_SECS)
+            .await?
+        {
+            return Ok(code);
+        }
+    }
+    anyhow::bail!("No free activation code")
+}
+
+/// Binds the device waiting with `req.code` to `req.owner`.
+pub(crate) async fn bind_with_code(state: &AppState, req: &BindRequest) -> Response {
+    let code = req.code.trim();
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return (StatusCode::BAD_REQUEST, "Activation code must be 6 digits").into_response();
+    }
+    if req.owner.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing owner").into_response();
+    }
+    match state.db.bind_device(code, &req.owner).await {
+        Ok(Some(device_id)) => {
+            tracing::info!("Device {} bound to {}", device_id, req.owner);
+            Json(json!({ "device_id": device_id, "owner": req.owner })).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Unknown or expired activation code").into_response(),
+        Err(e) => {
+            tracing::error!("DB Error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+/// `POST /api/devices/bind` with `{"code":"123456","owner":"..."}`: lets the
+/// owner bind the device whose screen shows the code. The code is the only
+/// credential, so clients get a few wrong guesses before they are locked out.
+pub async fn handle_bind(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<BindRequest>,
+) -> Response {
+    match state.bind_limiter.is_blocked(addr.ip()) {
+        Ok(false) => {}
+        Ok(true) => {
+            tracing::warn!("Too many wrong activation codes from {}", addr);
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many attempts, try again later",
+            )
+                .into_response();
+        }
+        Err(e) => {
+            tracing::error!("Bind limiter failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response();
+        }
+    }
+    let response = bind_with_code(&state, &req).await;
+    if response.status() == StatusCode::NOT_FOUND {
+        if let Err(e) = state.bind_limiter.record_failure(addr.ip()) {
+            tracing::error!("Bind limiter failed: {}", e);
+        }
+    }
+    response
+}
+
 /// Handles device activation requests.
 ///
 /// The device sends a signature of the challenge received in the `handle_ota` response.
-/// If the signature matches the expected HMAC-SHA256 signature, the device is marked as activated.
+/// Once the signature matches the expected HMAC-SHA256 signature, the device is told it is
+/// activated as soon as its owner has bound it with the activation code; until then it gets
+/// `202 Accepted` and keeps polling.
 pub async fn handle_ota_activate(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -176,6 +355,17 @@ pub async fn handle_ota_activate(
     let expected_signature = hex::encode(expected_signature_bytes);
 
     if signature_to_verify == expected_signature {
+        match state.db.is_activated(&device_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return (StatusCode::ACCEPTED, "Waiting for activation code").into_response()
+            }
+            Err(e) => {
+                tracing::error!("DB Error: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+            }
+        }
+        // Bound by its owner; this also clears the challenge
         if let Err(e) = state.db.activate_device(&device_id).await {
             tracing::error!("Failed to activate device: {}", e);
             return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
@@ -190,3 +380,52 @@ pub async fn handle_ota_activate(
         (StatusCode::ACCEPTED, "Device verification failed").into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::scripted_services;
+    use serde_json::Value;
+
+    async fn ota_token(state: &AppState, device_id: &str, client_id: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert("Device-Id", device_id.parse().unwrap());
+        headers.insert("Client-Id", client_id.parse().unwrap());
+        let response = handle_ota(State(state.clone()), headers, Bytes::new())
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        body["websocket"]["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn device_tokens_only_go_to_activated_devices() {
+        let state = scripted_services(
+            r#"
+            [auth]
+            enable = true
+            signature_key = "test"
+            token_mode = "device"
+            "#,
+        )
+        .state;
+
+        assert_eq!(ota_token(&state, "aa:bb", "device").await, "");
+
+        state.db.activate_device("aa:bb").await.unwrap();
+        let token = ota_token(&state, "aa:bb", "device").await;
+        assert!(
+            crate::services::auth::verify_device_token("test", "aa:bb", "device", &token).is_ok()
+        );
+
+        // Someone else claiming the same MAC
+        assert_eq!(ota_token(&state, "aa:bb", "attacker").await, "");
+
+        state.db.revoke_device("aa:bb").await.unwrap();
+        assert_eq!(ota_token(&state, "aa:bb", "device").await, "");
+    }
+}
@@ -1,5 +1,69 @@
 use serde::{Deserialize, Serialize};
 
+use crate::traits::{DeviceRecord, Partition};
+
+/// What the firmware reports about itself in the OTA request body.
+/// Everything is optional; older firmware sends less.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct OtaRequest {
+    pub flash_size: Option<u64>,
+    pub mac_address: Option<String>,
+    pub chip_model_name: Option<String>,
+    pub application: Option<ApplicationInfo>,
+    pub partition_table: Vec<Partition>,
+    pub board: Option<BoardInfo>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ApplicationInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub compile_time: Option<String>,
+    pub idf_version: Option<String>,
+    pub elf_sha256: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct BoardInfo {
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub name: Option<String>,
+    pub ssid: Option<String>,
+    pub rssi: Option<i32>,
+    pub channel: Option<u32>,
+    pub ip: Option<String>,
+    pub mac: Option<String>,
+}
+
+impl OtaRequest {
+    pub fn app_version(&self) -> Option<&str> {
+        self.application.as_ref()?.version.as_deref()
+    }
+
+    pub fn into_record(self, device_id: &str, client_id: &str) -> DeviceRecord {
+        let app_version = self.app_version().map(str::to_string);
+        let board = self.board.unwrap_or_default();
+        DeviceRecord {
+            device_id: device_id.to_string(),
+            client_id: client_id.to_string(),
+            board_type: board.type_,
+            board_name: board.name,
+            chip_model: self.chip_model_name,
+            // Wi-Fi boards repeat it under `board`
+            mac_address: self.mac_address.or(board.mac),
+            flash_size: self.flash_size,
+            app_version,
+            partition_table: self.partition_table,
+            ssid: board.ssid,
+            rssi: board.rssi,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct OtaResponse {
     #[serde(rename = "websocket")]
@@ -50,8 +114,10 @@ pub struct ServerTimeInfo {
 
 #[derive(Serialize, Debug)]
 pub struct ActivationInfo {
+    // Six digits, shown and read out by the device
     #[serde(rename = "code")]
     pub code: String,
+    // Shown on the device's screen alongside the code
     #[serde(rename = "message")]
     pub message: String,
     #[serde(rename = "challenge")]
@@ -87,3 +153,10 @@ pub struct ActivationPayload {
     #[serde(rename = "device_id")]
     pub device_id: Option<String>,
 }
+
+/// Body of the bind request: the code the device shows and who claims it.
+#[derive(Deserialize, Debug)]
+pub struct BindRequest {
+    pub code: String,
+    pub owner: String,
+}
@@ -0,0 +1,349 @@
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json},
+};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::handlers::admin::check_admin;
+use crate::services::audio::decode::{decode_audio, AudioFormat};
+use crate::state::AppState;
+
+#[derive(Serialize, Debug)]
+pub struct TranscriptionResponse {
+    pub text: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VerboseSegment {
+    pub id: usize,
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    pub language: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VerboseTranscriptionResponse {
+    pub task: String,
+    pub language: Option<String>,
+    pub duration: f64,
+    pub text: String,
+    pub segments: Vec<VerboseSegment>,
+}
+
+/// OpenAI-compatible `POST /v1/audio/transcriptions` backed by the configured STT.
+///
+/// Multipart fields: `file` (WAV, MP3, Ogg/Opus or raw 16-bit PCM), optional
+/// `response_format` (`json`, `text` or `verbose_json`) and `sample_rate` for raw PCM.
+/// `model` and other OpenAI fields are accepted and ignored. Takes the admin
+/// token as the API key, since uploads cost decoding and inference time.
+pub async fn handle_transcription(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_admin(&state, &headers) {
+        return *rejection;
+    }
+    let mut file: Option<(Vec<u8>, Option<String>)> = None;
+    let mut response_format = "json".to_string();
+    let mut sample_rate: u32 = 16000;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "file" => {
+                let hint = field
+                    .file_name()
+                    .or(field.content_type())
+                    .map(|s| s.to_string());
+                match field.bytes().await {
+                    Ok(bytes) => file = Some((bytes.to_vec(), hint)),
+                    Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+                }
+            }
+            "response_format" => {
+                if let Ok(value) = field.text().await {
+                    response_format = value.trim().to_string();
+                }
+            }
+            "sample_rate" => match field.text().await.map(|v| v.trim().parse::<u32>()) {
+                Ok(Ok(rate)) if rate > 0 => sample_rate = rate,
+                _ => return (StatusCode::BAD_REQUEST, "Invalid sample_rate").into_response(),
+            },
+            _ => {}
+        }
+    }
+
+    let Some((data, hint)) = file else {
+        return (StatusCode::BAD_REQUEST, "Missing file").into_response();
+    };
+    let Some(format) = AudioFormat::detect(&data, hint.as_deref()) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported audio format (expected WAV, MP3, Ogg/Opus or .pcm)",
+        )
+            .into_response();
+    };
+
+    // MP3 and Opus decoding of a long upload is CPU-bound
+    let decoded =
+        tokio::task::spawn_blocking(move || decode_audio(&data, format, sample_rate)).await;
+    let pcm = match decoded {
+        Ok(Ok(pcm)) => pcm,
+        Ok(Err(e)) => return (StatusCode::BAD_REQUEST, format!("{:#}", e)).into_response(),
+        Err(e) => {
+            error!("Decode task failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Decode failed").into_response();
+        }
+    };
+    info!(
+        "Transcribing {:?} upload ({:.1}s)",
+        format,
+        pcm.len() as f64 / 16000.0
+    );
+
+    let transcription = match state.stt.transcribe(&pcm).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Transcription failed: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Transcription failed").into_response();
+        }
+    };
+
+    match response_format.as_str() {
+        "text" => transcription.text.into_response(),
+        "verbose_json" => Json(VerboseTranscriptionResponse {
+            task: "transcribe".to_string(),
+            language: transcription.language,
+            duration: transcription.duration,
+            text: transcription.text,
+            segments: transcription
+                .segments
+                .into_iter()
+                .enumerate()
+                .map(|(id, s)| VerboseSegment {
+                    id,
+                    start: s.start,
+                    end: s.end,
+                    text: s.text,
+                    language: s.language,
+                })
+                .collect(),
+        })
+        .into_response(),
+        _ => Json(TranscriptionResponse {
+            text: transcription.text,
+        })
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::scripted_services;
+    use crate::traits::{SttEvent, SttTrait};
+    use async_trait::async_trait;
+    use axum::{routing::post, Router};
+    use futures_util::stream::BoxStream;
+    use serde_json::Value;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    const BOUNDARY: &str = "test-boundary";
+
+    // Says "你好" about whatever it is given
+    struct CannedStt;
+
+    #[async_trait]
+    impl SttTrait for CannedStt {
+        async fn recognize(&self, audio: &[u8]) -> anyhow::Result<String> {
+            assert_eq!(audio.len(), 16000 * 2, "Expected one second at 16kHz");
+            Ok("你好".to_string())
+        }
+
+        async fn stream_speech(
+            &self,
+            _input_stream: BoxStream<'static, Vec<i16>>,
+        ) -> anyhow::Result<BoxStream<'static, anyhow::Result<SttEvent>>> {
+            anyhow::bail!("Not streamed in these tests")
+        }
+    }
+
+    // One second of 440Hz at 48kHz, so the handler has to resample
+    fn canned_wav() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for i in 0..48000 {
+            let t = i as f32 / 48000.0;
+            writer
+                .write_sample((8000.0 * (std::f32::consts::TAU * 440.0 * t).sin()) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    // `file` plus text fields as multipart/form-data
+    fn form(file: Option<(&str, &[u8])>, fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        if let Some((name, data)) = file {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                     Content-Type: application/octet-stream\r\n\r\n",
+                    BOUNDARY, name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    // Serves the route on a local port; returns its URL
+    async fn serve() -> String {
+        let mut state = scripted_services(
+            r#"
+            [auth]
+            enable = false
+            signature_key = "test"
+            admin_token = "admin-secret"
+            "#,
+        )
+        .state;
+        state.stt = Arc::new(CannedStt);
+        let app = Router::new()
+            .route("/v1/audio/transcriptions", post(handle_transcription))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/v1/audio/transcriptions", addr)
+    }
+
+    async fn post_form(url: &str, token: Option<&str>, body: Vec<u8>) -> reqwest::Response {
+        let mut request = reqwest::Client::new().post(url).header(
+            "content-type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        );
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.body(body).send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn transcribes_in_every_response_format() {
+        let url = serve().await;
+        let wav = canned_wav();
+
+        let json: Value = post_form(&url, Some("admin-secret"), form(Some(("a.wav", &wav)), &[]))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(json, serde_json::json!({ "text": "你好" }));
+
+        let response = post_form(
+            &url,
+            Some("admin-secret"),
+            form(
+                Some(("a.wav", &wav)),
+                &[("response_format", "text"), ("model", "whisper-1")],
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "你好");
+
+        let verbose: Value = post_form(
+            &url,
+            Some("admin-secret"),
+            form(
+                Some(("a.wav", &wav)),
+                &[("response_format", "verbose_json")],
+            ),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        assert_eq!(verbose["task"], "transcribe");
+        assert_eq!(verbose["language"], "zh");
+        assert!((verbose["duration"].as_f64().unwrap() - 1.0).abs() < 0.01);
+        assert_eq!(verbose["segments"][0]["id"], 0);
+        assert_eq!(verbose["segments"][0]["text"], "你好");
+        assert_eq!(verbose["segments"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_uploads() {
+        let url = serve().await;
+        let wav = canned_wav();
+        let status = |response: reqwest::Response| response.status();
+
+        let missing_file = post_form(&url, Some("admin-secret"), form(None, &[])).await;
+        assert_eq!(status(missing_file), StatusCode::BAD_REQUEST);
+
+        let bad_rate = post_form(
+            &url,
+            Some("admin-secret"),
+            form(Some(("a.pcm", &[0; 64])), &[("sample_rate", "fast")]),
+        )
+        .await;
+        assert_eq!(status(bad_rate), StatusCode::BAD_REQUEST);
+
+        let truncated = post_form(
+            &url,
+            Some("admin-secret"),
+            form(Some(("a.wav", &wav[..30])), &[]),
+        )
+        .await;
+        assert_eq!(status(truncated), StatusCode::BAD_REQUEST);
+
+        let unknown = post_form(
+            &url,
+            Some("admin-secret"),
+            form(Some(("notes.txt", b"hello there, not audio")), &[]),
+        )
+        .await;
+        assert_eq!(status(unknown), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn requires_the_admin_token() {
+        let url = serve().await;
+        let body = || form(Some(("a.wav", &canned_wav())), &[]);
+
+        let anonymous = post_form(&url, None, body()).await;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        let wrong = post_form(&url, Some("guess"), body()).await;
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+    }
+}
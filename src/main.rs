@@ -1,19 +1,21 @@
 mod config;
 mod handlers;
 mod services;
+mod session;
 mod state;
 mod traits;
 
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
     Router,
 };
 use std::net::SocketAddr;
-use tower_http::trace::TraceLayer;
+use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::config::ServerConfig;
-use crate::handlers::{ota, websocket};
+use crate::handlers::{admin, chat, mqtt, ota, transcription, websocket};
 use crate::state::AppState;
 
 #[tokio::main]
@@ -39,6 +41,11 @@ async fn main() {
     // AppState::new is now async
     let app_state = AppState::new(config).await;
 
+    // Devices configured for MQTT reach us through the broker and UDP
+    if app_state.config.ota.mqtt.enable {
+        tokio::spawn(mqtt::run_gateway(app_state.clone()));
+    }
+
     // Build our application with routes
     // Configure TraceLayer to include headers and body (if printable)
     let trace_layer = TraceLayer::new_for_http()
@@ -66,6 +73,35 @@ async fn main() {
         .route("/xiaozhi/v1/", get(websocket::handle_websocket))
         .route("/xiaozhi/ota/", post(ota::handle_ota))
         .route("/xiaozhi/ota/activate", post(ota::handle_ota_activate))
+        .route("/api/chat", post(chat::handle_chat))
+        .route("/api/devices/bind", post(ota::handle_bind))
+        .route("/admin/devices", get(admin::handle_list_devices))
+        .route("/admin/devices/bind", post(admin::handle_bind))
+        .route("/admin/firmware", get(admin::handle_list_firmware))
+        .route(
+            "/admin/firmware/reload",
+            post(admin::handle_reload_firmware),
+        )
+        .route(
+            "/admin/firmware/{board}/{version}",
+            // Room for 16MB flash images
+            put(admin::handle_upload_firmware).layer(DefaultBodyLimit::max(32 * 1024 * 1024)),
+        )
+        // Downloads handed out by OTA; ServeDir answers Range requests
+        .nest_service(
+            "/firmware",
+            ServeDir::new(&app_state.config.ota.firmware.dir),
+        )
+        .route(
+            "/admin/devices/{device_id}/revoke",
+            post(admin::handle_revoke).delete(admin::handle_restore),
+        )
+        .route(
+            "/v1/audio/transcriptions",
+            // Same upload limit as OpenAI
+            post(transcription::handle_transcription)
+                .layer(DefaultBodyLimit::max(25 * 1024 * 1024)),
+        )
         .layer(trace_layer)
         .with_state(app_state.clone());
 
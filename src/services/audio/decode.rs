@@ -0,0 +1,244 @@
+use crate::services::audio::opus_codec::OpusService;
+use crate::services::audio::resampler::{downmix_to_mono, resample};
+use anyhow::{anyhow, Context, Result};
+use std::io::Cursor;
+
+const TARGET_RATE: u32 = 16000;
+
+/// Container or encoding of an uploaded recording.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    Mp3,
+    OggOpus,
+    // Headerless signed 16-bit little-endian mono
+    Pcm,
+}
+
+impl AudioFormat {
+    /// Identifies the format from magic bytes. Headerless PCM has none, so it
+    /// is only assumed when the file name or MIME type says so.
+    pub fn detect(data: &[u8], hint: Option<&str>) -> Option<Self> {
+        if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+            return Some(Self::Wav);
+        }
+        if data.starts_with(b"OggS") {
+            // Ogg also carries Vorbis, FLAC and Speex; only Opus is decoded
+            return is_ogg_opus(data).then_some(Self::OggOpus);
+        }
+        if data.starts_with(b"ID3") || is_mpeg_audio_frame(data) {
+            return Some(Self::Mp3);
+        }
+
+        let hint = hint?.to_lowercase();
+        if hint.ends_with(".pcm") || hint.ends_with(".raw") || hint.contains("l16") {
+            Some(Self::Pcm)
+        } else {
+            None
+        }
+    }
+}
+
+// MPEG audio frame header: 11 sync bits, then version and layer. AAC ADTS
+// shares the sync word but always has layer 0, which MPEG audio reserves.
+fn is_mpeg_audio_frame(data: &[u8]) -> bool {
+    if data.len() < 2 || data[0] != 0xFF || data[1] & 0xE0 != 0xE0 {
+        return false;
+    }
+    let version = (data[1] >> 3) & 0b11;
+    let layer = (data[1] >> 1) & 0b11;
+    version != 0b01 && layer != 0b00
+}
+
+// The first Ogg page holds the codec's identification header: `OpusHead`
+// right after the 27-byte page header and its segment table
+fn is_ogg_opus(data: &[u8]) -> bool {
+    let Some(&segments) = data.get(26) else {
+        return false;
+    };
+    let start = 27 + segments as usize;
+    data.get(start..start + 8) == Some(b"OpusHead".as_slice())
+}
+
+/// Decodes an uploaded recording to 16kHz mono PCM.
+///
+/// `pcm_sample_rate` is only used for headerless PCM.
+pub fn decode_audio(data: &[u8], format: AudioFormat, pcm_sample_rate: u32) -> Result<Vec<i16>> {
+    let (pcm, sample_rate) = decode_audio_native(data, format, pcm_sample_rate)?;
+    Ok(resample(&pcm, sample_rate, TARGET_RATE))
+}
+
+/// Decodes to mono PCM at the recording's own sample rate, returned alongside.
+pub fn decode_audio_native(
+    data: &[u8],
+    format: AudioFormat,
+    pcm_sample_rate: u32,
+) -> Result<(Vec<i16>, u32)> {
+    Ok(match format {
+        AudioFormat::Wav => decode_wav(data)?,
+        AudioFormat::Mp3 => decode_mp3(data)?,
+        AudioFormat::OggOpus => (decode_ogg_opus(data)?, TARGET_RATE),
+        AudioFormat::Pcm => {
+            if !data.len().is_multiple_of(2) {
+                return Err(anyhow!("Invalid PCM byte length"));
+            }
+            let pcm = data
+                .chunks_exact(2)
+                .map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]))
+                .collect();
+            (pcm, pcm_sample_rate)
+        }
+    })
+}
+
+fn decode_wav(data: &[u8]) -> Result<(Vec<i16>, u32)> {
+    let mut reader = hound::WavReader::new(Cursor::new(data)).context("Invalid WAV file")?;
+    let spec = reader.spec();
+
+    let samples: Vec<i16> = match spec.sample_format {
+        hound::SampleFormat::Int => {
+            let shift = spec.bits_per_sample as i32 - 16;
+            reader
+                .samples::<i32>()
+                .map(|s| {
+                    s.map(|v| {
+                        if shift >= 0 {
+                            (v >> shift) as i16
+                        } else {
+                            (v << -shift) as i16
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .context("Failed to read WAV samples")?
+        }
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|s| s.map(|v| (v.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+            .collect::<Result<_, _>>()
+            .context("Failed to read WAV samples")?,
+    };
+
+    Ok((
+        downmix_to_mono(&samples, spec.channels as usize),
+        spec.sample_rate,
+    ))
+}
+
+fn decode_mp3(data: &[u8]) -> Result<(Vec<i16>, u32)> {
+    let mut decoder = minimp3::Decoder::new(data);
+    let mut pcm = Vec::new();
+    let mut sample_rate = 0;
+
+    loop {
+        match decoder.next_frame() {
+            Ok(frame) => {
+                if sample_rate == 0 {
+                    sample_rate = frame.sample_rate as u32;
+                }
+                pcm.extend(downmix_to_mono(&frame.data, frame.channels));
+            }
+            Err(minimp3::Error::Eof) => break,
+            Err(e) => return Err(anyhow!("MP3 decode error: {:?}", e)),
+        }
+    }
+
+    if sample_rate == 0 {
+        return Err(anyhow!("No MP3 frames found"));
+    }
+    Ok((pcm, sample_rate))
+}
+
+// Ogg Opus (RFC 7845): OpusHead, OpusTags, then one Opus packet per Ogg packet
+fn decode_ogg_opus(data: &[u8]) -> Result<Vec<i16>> {
+    let mut reader = ogg::PacketReader::new(Cursor::new(data));
+
+    let head = reader
+        .read_packet()
+        .context("Invalid Ogg stream")?
+        .ok_or_else(|| anyhow!("Empty Ogg stream"))?;
+    if !head.data.starts_with(b"OpusHead") || head.data.len() < 19 {
+        return Err(anyhow!("Ogg stream is not Opus"));
+    }
+    // Pre-skip is given in 48kHz samples
+    let pre_skip =
+        u16::from_le_bytes([head.data[10], head.data[11]]) as usize * TARGET_RATE as usize / 48000;
+
+    // Opus decodes stereo streams to mono when asked to
+    let mut decoder = OpusService::new_decoder()?;
+    let mut pcm = Vec::new();
+    let mut output = vec![0i16; 5760];
+    while let Some(packet) = reader.read_packet().context("Invalid Ogg stream")? {
+        if packet.data.starts_with(b"OpusTags") {
+            continue;
+        }
+        let len = decoder
+            .decode(&packet.data, &mut output, false)
+            .context("Opus decode error")?;
+        pcm.extend_from_slice(&output[..len]);
+    }
+
+    pcm.drain(..pre_skip.min(pcm.len()));
+    Ok(pcm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0; 22]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    #[test]
+    fn detects_mpeg_audio_but_not_adts() {
+        // MPEG-1 Layer III
+        assert_eq!(
+            AudioFormat::detect(&[0xFF, 0xFB, 0x90, 0x64], None),
+            Some(AudioFormat::Mp3)
+        );
+        assert_eq!(
+            AudioFormat::detect(b"ID3\x04\x00", None),
+            Some(AudioFormat::Mp3)
+        );
+        // AAC ADTS, MPEG-4 and MPEG-2
+        assert_eq!(AudioFormat::detect(&[0xFF, 0xF1, 0x50, 0x80], None), None);
+        assert_eq!(AudioFormat::detect(&[0xFF, 0xF9, 0x50, 0x80], None), None);
+        assert_eq!(
+            AudioFormat::detect(&[0xFF, 0xF1, 0x50, 0x80], Some("clip.mp3")),
+            None
+        );
+    }
+
+    #[test]
+    fn detects_ogg_by_codec() {
+        assert_eq!(
+            AudioFormat::detect(&ogg_page(b"OpusHead\x01\x01"), None),
+            Some(AudioFormat::OggOpus)
+        );
+        assert_eq!(
+            AudioFormat::detect(&ogg_page(b"\x01vorbis"), Some("clip.ogg")),
+            None
+        );
+        assert_eq!(AudioFormat::detect(b"OggS", None), None);
+    }
+
+    #[test]
+    fn headerless_pcm_needs_a_hint() {
+        let pcm = [0x01, 0x02, 0x03, 0x04];
+        assert_eq!(AudioFormat::detect(&pcm, None), None);
+        assert_eq!(
+            AudioFormat::detect(&pcm, Some("audio/L16")),
+            Some(AudioFormat::Pcm)
+        );
+        assert_eq!(
+            AudioFormat::detect(&pcm, Some("take1.pcm")),
+            Some(AudioFormat::Pcm)
+        );
+    }
+}
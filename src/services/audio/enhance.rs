@@ -0,0 +1,289 @@
+use crate::config::EnhanceSettings;
+use crate::services::audio::resampler::Resampler;
+use nnnoiseless::DenoiseState;
+
+const SAMPLE_RATE: f32 = 16000.0;
+// RNNoise's native rate; it works on 10ms frames of `DenoiseState::FRAME_SIZE`
+const DENOISE_RATE: u32 = 48000;
+// AGC gain is updated every 10ms
+const AGC_BLOCK: usize = 160;
+
+/// Second-order Butterworth high-pass (RBJ biquad) to remove rumble and DC.
+struct HighPassFilter {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl HighPassFilter {
+    fn new(cutoff_hz: f32) -> Self {
+        let w0 = 2.0 * std::f32::consts::PI * cutoff_hz / SAMPLE_RATE;
+        let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+
+        Self {
+            b0: (1.0 + cos_w0) / 2.0 / a0,
+            b1: -(1.0 + cos_w0) / a0,
+            b2: (1.0 + cos_w0) / 2.0 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            let x = *s;
+            let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+                - self.a1 * self.y1
+                - self.a2 * self.y2;
+            self.x2 = self.x1;
+            self.x1 = x;
+            self.y2 = self.y1;
+            self.y1 = y;
+            *s = y;
+        }
+    }
+}
+
+/// RNNoise denoiser (via `nnnoiseless`), run at 48kHz on 16kHz input.
+struct Denoiser {
+    state: Box<DenoiseState<'static>>,
+    upsampler: Resampler,
+    downsampler: Resampler,
+    // 48kHz audio short of a whole frame
+    pending: Vec<f32>,
+}
+
+impl Denoiser {
+    fn new() -> Self {
+        Self {
+            state: DenoiseState::new(),
+            upsampler: Resampler::new(SAMPLE_RATE as u32, DENOISE_RATE),
+            downsampler: Resampler::new(DENOISE_RATE, SAMPLE_RATE as u32),
+            pending: Vec::new(),
+        }
+    }
+
+    // Returns denoised audio for every whole 10ms frame so far; the output
+    // lags the input by the frame remainder and both resamplers' delay
+    fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let input: Vec<i16> = samples
+            .iter()
+            .map(|&s| s.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect();
+        self.pending
+            .extend(self.upsampler.process(&input).into_iter().map(f32::from));
+
+        let frames = self.pending.len() / DenoiseState::FRAME_SIZE;
+        let mut denoised = vec![0.0f32; DenoiseState::FRAME_SIZE];
+        let mut output = Vec::with_capacity(frames * DenoiseState::FRAME_SIZE / 3);
+        for frame in self.pending.chunks_exact(DenoiseState::FRAME_SIZE) {
+            self.state.process_frame(&mut denoised, frame);
+            let frame: Vec<i16> = denoised
+                .iter()
+                .map(|&s| s.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+                .collect();
+            output.extend(self.downsampler.process(&frame).into_iter().map(f32::from));
+        }
+        self.pending.drain(..frames * DenoiseState::FRAME_SIZE);
+        output
+    }
+}
+
+/// Automatic gain control towards a target RMS level.
+struct Agc {
+    target_rms: f32,
+    max_gain: f32,
+    // Blocks quieter than this are treated as silence and do not raise the gain
+    noise_floor: f32,
+    gain: f32,
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+impl Agc {
+    fn new(target_dbfs: f32, max_gain_db: f32) -> Self {
+        Self {
+            target_rms: db_to_linear(target_dbfs) * i16::MAX as f32,
+            max_gain: db_to_linear(max_gain_db),
+            noise_floor: db_to_linear(-50.0) * i16::MAX as f32,
+            gain: 1.0,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for block in samples.chunks_mut(AGC_BLOCK) {
+            let rms = (block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32).sqrt();
+
+            let desired = if rms > self.noise_floor {
+                (self.target_rms / rms).min(self.max_gain)
+            } else {
+                self.gain
+            };
+            // Back off quickly on loud input, raise the gain slowly
+            let rate = if desired < self.gain { 0.5 } else { 0.05 };
+            let start_gain = self.gain;
+            self.gain += (desired - self.gain) * rate;
+
+            // Ramp across the block to avoid zipper noise
+            let len = block.len() as f32;
+            for (i, s) in block.iter_mut().enumerate() {
+                let g = start_gain + (self.gain - start_gain) * (i + 1) as f32 / len;
+                *s *= g;
+            }
+        }
+    }
+}
+
+/// Optional clean-up of uplink audio before STT: high-pass, denoise and AGC,
+/// applied in that order to 16kHz mono PCM.
+pub struct SpeechEnhancer {
+    high_pass: Option<HighPassFilter>,
+    denoiser: Option<Denoiser>,
+    agc: Option<Agc>,
+}
+
+impl SpeechEnhancer {
+    /// Returns `None` when every stage is disabled.
+    pub fn new(settings: &EnhanceSettings) -> Option<Self> {
+        if !settings.high_pass && !settings.denoise && !settings.agc {
+            return None;
+        }
+
+        Some(Self {
+            high_pass: settings
+                .high_pass
+                .then(|| HighPassFilter::new(settings.high_pass_hz)),
+            denoiser: settings.denoise.then(Denoiser::new),
+            agc: settings
+                .agc
+                .then(|| Agc::new(settings.agc_target_dbfs, settings.agc_max_gain_db)),
+        })
+    }
+
+    /// Processes a chunk of PCM. With the denoiser on, output is released in
+    /// whole 10ms frames and a few milliseconds late, so it can be shorter
+    /// than the input.
+    pub fn process(&mut self, pcm: &[i16]) -> Vec<i16> {
+        let mut samples: Vec<f32> = pcm.iter().map(|&s| s as f32).collect();
+
+        if let Some(high_pass) = self.high_pass.as_mut() {
+            high_pass.process(&mut samples);
+        }
+        if let Some(denoiser) = self.denoiser.as_mut() {
+            samples = denoiser.process(&samples);
+        }
+        if let Some(agc) = self.agc.as_mut() {
+            agc.process(&mut samples);
+        }
+
+        samples
+            .into_iter()
+            .map(|s| s.clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f32, dbfs: f32, samples: usize) -> Vec<f32> {
+        let amplitude = db_to_linear(dbfs) * i16::MAX as f32 * std::f32::consts::SQRT_2;
+        (0..samples)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / SAMPLE_RATE).sin())
+            .collect()
+    }
+
+    fn rms_dbfs(samples: &[f32]) -> f32 {
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        20.0 * (rms / i16::MAX as f32).log10()
+    }
+
+    // Level of a tone after the high-pass, once the filter has settled
+    fn high_pass_gain_db(freq: f32) -> f32 {
+        let mut filter = HighPassFilter::new(100.0);
+        let mut samples = tone(freq, -20.0, 16000);
+        filter.process(&mut samples);
+        rms_dbfs(&samples[8000..]) + 20.0
+    }
+
+    #[test]
+    fn high_pass_keeps_speech_and_removes_rumble() {
+        assert!(high_pass_gain_db(1000.0).abs() < 0.5);
+        assert!(high_pass_gain_db(300.0).abs() < 1.0);
+        // Butterworth: -3dB at the cutoff, 12dB per octave below it
+        assert!((high_pass_gain_db(100.0) + 3.0).abs() < 0.5);
+        assert!(high_pass_gain_db(25.0) < -20.0);
+
+        let mut filter = HighPassFilter::new(100.0);
+        let mut dc = vec![5000.0f32; 16000];
+        filter.process(&mut dc);
+        assert!(dc[8000..].iter().all(|s| s.abs() < 1.0));
+    }
+
+    #[test]
+    fn agc_brings_quiet_and_loud_speech_to_the_target() {
+        let mut agc = Agc::new(-20.0, 30.0);
+        let mut quiet = tone(440.0, -40.0, 32000);
+        agc.process(&mut quiet);
+        assert!((rms_dbfs(&quiet[16000..]) + 20.0).abs() < 1.0);
+
+        let mut agc = Agc::new(-20.0, 30.0);
+        let mut loud = tone(440.0, -6.0, 16000);
+        agc.process(&mut loud);
+        assert!((rms_dbfs(&loud[8000..]) + 20.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn agc_caps_the_gain_and_leaves_silence_alone() {
+        let mut agc = Agc::new(-20.0, 10.0);
+        let mut faint = tone(440.0, -45.0, 32000);
+        agc.process(&mut faint);
+        assert!((rms_dbfs(&faint[16000..]) + 35.0).abs() < 1.0);
+
+        // Below the noise floor the gain stays at unity
+        let mut agc = Agc::new(-20.0, 30.0);
+        let mut hiss = tone(440.0, -60.0, 16000);
+        agc.process(&mut hiss);
+        assert!((rms_dbfs(&hiss[8000..]) + 60.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn denoiser_output_does_not_depend_on_chunking() {
+        let input = tone(440.0, -20.0, 16000);
+
+        let whole = Denoiser::new().process(&input);
+
+        let mut denoiser = Denoiser::new();
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(97) {
+            chunked.extend(denoiser.process(chunk));
+        }
+
+        assert_eq!(whole, chunked);
+        // Everything but the last partial frame and the resampler delay comes out
+        assert!(whole.len() <= input.len());
+        assert!(whole.len() + 200 >= input.len());
+    }
+
+    #[test]
+    fn denoiser_keeps_silence_silent() {
+        let output = Denoiser::new().process(&vec![0.0; 1600]);
+        assert!(!output.is_empty());
+        assert!(output.iter().all(|&s| s == 0.0));
+    }
+}
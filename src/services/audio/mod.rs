@@ -1,2 +1,7 @@
+pub mod decode;
+pub mod enhance;
 pub mod opus_codec;
+pub mod params;
 pub mod resampler;
+pub mod ring_buffer;
+pub mod vad;
@@ -1,5 +1,152 @@
-use anyhow::{Context, Result};
-use opus::{Application, Channels, Decoder, Encoder};
+use crate::config::OpusEncoderSettings;
+use anyhow::{anyhow, Context, Result};
+use audiopus_sys as ffi;
+use opus::{Channels, Decoder};
+use std::ptr::NonNull;
+use std::time::Duration;
+
+// From opus_defines.h
+const OPUS_AUTO: i32 = -1000;
+const OPUS_APPLICATION_VOIP: i32 = 2048;
+const OPUS_SIGNAL_VOICE: i32 = 3001;
+const OPUS_SIGNAL_MUSIC: i32 = 3002;
+const OPUS_SET_BITRATE_REQUEST: i32 = 4002;
+const OPUS_SET_VBR_REQUEST: i32 = 4006;
+const OPUS_SET_COMPLEXITY_REQUEST: i32 = 4010;
+const OPUS_SET_INBAND_FEC_REQUEST: i32 = 4012;
+const OPUS_SET_PACKET_LOSS_PERC_REQUEST: i32 = 4014;
+const OPUS_SET_DTX_REQUEST: i32 = 4016;
+const OPUS_SET_SIGNAL_REQUEST: i32 = 4024;
+// Recommended output buffer size for one packet
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// Sample rate, channel count and packet duration of an Opus stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub frame_duration: u32,
+}
+
+impl Default for StreamFormat {
+    // 16kHz mono in 60ms packets: what devices assume when nothing else is negotiated
+    fn default() -> Self {
+        Self {
+            sample_rate: 16000,
+            channels: 1,
+            frame_duration: 60,
+        }
+    }
+}
+
+impl StreamFormat {
+    /// Samples per channel in one packet.
+    pub fn frame_samples(&self) -> usize {
+        (self.sample_rate * self.frame_duration / 1000) as usize
+    }
+
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_millis(self.frame_duration as u64)
+    }
+}
+
+/// Opus encoder configured from an `[audio.opus]` profile.
+///
+/// Drives libopus directly since the `opus` crate has no setters for
+/// complexity, signal type or DTX.
+pub struct OpusEncoder {
+    encoder: NonNull<ffi::OpusEncoder>,
+    channels: usize,
+}
+
+// The encoder state is only reached through `&mut self`
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(format: &StreamFormat, settings: &OpusEncoderSettings) -> Result<Self> {
+        let channels = if format.channels == 2 { 2 } else { 1 };
+        let mut error = 0;
+        // SAFETY: plain constructor call; the result is checked for null
+        let encoder = unsafe {
+            ffi::opus_encoder_create(
+                format.sample_rate as i32,
+                channels,
+                OPUS_APPLICATION_VOIP,
+                &mut error,
+            )
+        };
+        let encoder = NonNull::new(encoder)
+            .ok_or_else(|| anyhow!("Failed to create Opus encoder (error {})", error))?;
+        let mut encoder = Self {
+            encoder,
+            channels: channels as usize,
+        };
+
+        let signal = match settings.signal.as_str() {
+            "voice" => OPUS_SIGNAL_VOICE,
+            "music" => OPUS_SIGNAL_MUSIC,
+            _ => OPUS_AUTO,
+        };
+        encoder.ctl(
+            OPUS_SET_BITRATE_REQUEST,
+            settings.bitrate.map_or(OPUS_AUTO, |b| b as i32),
+        )?;
+        encoder.ctl(OPUS_SET_VBR_REQUEST, settings.vbr as i32)?;
+        encoder.ctl(
+            OPUS_SET_COMPLEXITY_REQUEST,
+            settings.complexity.min(10) as i32,
+        )?;
+        encoder.ctl(OPUS_SET_SIGNAL_REQUEST, signal)?;
+        encoder.ctl(OPUS_SET_DTX_REQUEST, settings.dtx as i32)?;
+        encoder.ctl(OPUS_SET_INBAND_FEC_REQUEST, settings.inband_fec as i32)?;
+        encoder.ctl(
+            OPUS_SET_PACKET_LOSS_PERC_REQUEST,
+            settings.packet_loss_perc.min(100) as i32,
+        )?;
+        Ok(encoder)
+    }
+
+    fn ctl(&mut self, request: i32, value: i32) -> Result<()> {
+        // SAFETY: every request used here takes a single opus_int32
+        let ret = unsafe { ffi::opus_encoder_ctl(self.encoder.as_ptr(), request, value) };
+        if ret < 0 {
+            return Err(anyhow!(
+                "Opus encoder rejected setting {} = {} (error {})",
+                request,
+                value,
+                ret
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encodes one packet of interleaved PCM.
+    pub fn encode_vec(&mut self, pcm: &[i16]) -> Result<Vec<u8>> {
+        let mut output = vec![0u8; MAX_PACKET_SIZE];
+        // SAFETY: both buffers outlive the call and their lengths are passed along
+        let len = unsafe {
+            ffi::opus_encode(
+                self.encoder.as_ptr(),
+                pcm.as_ptr(),
+                (pcm.len() / self.channels) as i32,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(anyhow!("Opus encode error {}", len));
+        }
+        output.truncate(len as usize);
+        Ok(output)
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        // SAFETY: created by opus_encoder_create and destroyed only here
+        unsafe { ffi::opus_encoder_destroy(self.encoder.as_ptr()) }
+    }
+}
 
 // OpusService handles encoding and decoding.
 // Since opus Encoders/Decoders are not thread-safe by default, we wrap them if needed,
@@ -37,8 +184,39 @@ impl OpusService {
         Decoder::new(16000, Channels::Mono).context("Failed to create Opus decoder")
     }
 
-    pub fn new_encoder() -> Result<Encoder> {
-        Encoder::new(16000, Channels::Mono, Application::Voip)
-            .context("Failed to create Opus encoder")
+    pub fn new_decoder_for(format: &StreamFormat) -> Result<Decoder> {
+        Decoder::new(format.sample_rate, channels(format)).context("Failed to create Opus decoder")
+    }
+
+    /// Encodes mono PCM at `format.sample_rate` into packets of `format.frame_duration`
+    /// with the given encoder settings, padding the last one with silence.
+    pub fn encode_frames_with(
+        pcm: &[i16],
+        format: &StreamFormat,
+        settings: &OpusEncoderSettings,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut encoder = OpusEncoder::new(format, settings)?;
+        let frame_size = format.frame_samples();
+        let mut frames = Vec::with_capacity(pcm.len().div_ceil(frame_size));
+
+        for chunk in pcm.chunks(frame_size) {
+            let encoded = if chunk.len() < frame_size {
+                let mut padded = chunk.to_vec();
+                padded.resize(frame_size, 0);
+                encoder.encode_vec(&padded)?
+            } else {
+                encoder.encode_vec(chunk)?
+            };
+            frames.push(encoded);
+        }
+        Ok(frames)
+    }
+}
+
+fn channels(format: &StreamFormat) -> Channels {
+    if format.channels == 2 {
+        Channels::Stereo
+    } else {
+        Channels::Mono
     }
 }
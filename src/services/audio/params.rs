@@ -0,0 +1,202 @@
+use crate::config::{AudioSettings, OpusEncoderSettings, OpusSettings};
+use crate::services::audio::opus_codec::{OpusService, StreamFormat};
+use crate::services::audio::resampler::{downmix_to_mono, resample, Resampler};
+use crate::traits::Speech;
+use anyhow::Result;
+use opus::Decoder;
+use tracing::{debug, warn};
+
+// Rates an Opus encoder or decoder can run at
+const OPUS_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+// Packet durations devices can play back
+const DOWNLINK_FRAME_DURATIONS: [u32; 3] = [20, 40, 60];
+// Rate of the PCM handed to VAD and STT
+const STT_RATE: u32 = 16000;
+// Largest Opus packet: 120ms at 48kHz, stereo
+const MAX_DECODED_SAMPLES: usize = 5760 * 2;
+// Longer gaps are skipped rather than filled with concealment audio
+const MAX_CONCEALED_PACKETS: u32 = 5;
+
+/// Uplink and downlink formats agreed on in the `hello` exchange.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionAudio {
+    pub uplink: StreamFormat,
+    pub downlink: StreamFormat,
+}
+
+impl SessionAudio {
+    /// Accepts what the device asks for where Opus supports it, falling back to
+    /// 16kHz mono 60ms. `settings` can pin the downlink rate and packet duration.
+    pub fn negotiate(
+        format: &str,
+        sample_rate: u32,
+        channels: u32,
+        frame_duration: u32,
+        settings: &AudioSettings,
+    ) -> Self {
+        let default = StreamFormat::default();
+        if format != "opus" {
+            warn!("Unsupported audio format '{}', assuming opus", format);
+        }
+
+        let uplink_rate = if OPUS_RATES.contains(&sample_rate) {
+            sample_rate
+        } else {
+            warn!(
+                "Unsupported sample rate {}, using {}",
+                sample_rate, default.sample_rate
+            );
+            default.sample_rate
+        };
+        let uplink = StreamFormat {
+            sample_rate: uplink_rate,
+            channels: if channels == 2 { 2 } else { 1 },
+            frame_duration,
+        };
+
+        let downlink_rate = settings.output_sample_rate.unwrap_or(uplink_rate);
+        let downlink_duration = settings.output_frame_duration.unwrap_or(frame_duration);
+        let downlink = StreamFormat {
+            sample_rate: if OPUS_RATES.contains(&downlink_rate) {
+                downlink_rate
+            } else {
+                warn!("Unsupported output sample rate {}", downlink_rate);
+                default.sample_rate
+            },
+            // Devices play a single speaker
+            channels: 1,
+            frame_duration: if DOWNLINK_FRAME_DURATIONS.contains(&downlink_duration) {
+                downlink_duration
+            } else {
+                default.frame_duration
+            },
+        };
+
+        Self { uplink, downlink }
+    }
+}
+
+/// Decodes uplink Opus packets to 16kHz mono PCM for VAD and STT.
+///
+/// When packets carry sequence numbers, gaps are filled from the in-band FEC
+/// of the next packet and with packet loss concealment.
+pub struct UplinkDecoder {
+    decoder: Decoder,
+    channels: usize,
+    // Only when the device does not send 16kHz
+    resampler: Option<Resampler>,
+    output: Vec<i16>,
+    fec: bool,
+    plc: bool,
+    last_sequence: Option<u32>,
+}
+
+impl UplinkDecoder {
+    pub fn new(format: &StreamFormat, settings: &OpusSettings) -> Result<Self> {
+        Ok(Self {
+            decoder: OpusService::new_decoder_for(format)?,
+            channels: format.channels as usize,
+            resampler: (format.sample_rate != STT_RATE)
+                .then(|| Resampler::new(format.sample_rate, STT_RATE)),
+            output: vec![0; MAX_DECODED_SAMPLES],
+            fec: settings.fec,
+            plc: settings.plc,
+            last_sequence: None,
+        })
+    }
+
+    pub fn decode(&mut self, packet: &[u8], sequence: Option<u32>) -> Result<Vec<i16>> {
+        let mut pcm = Vec::new();
+        if let Some(sequence) = sequence {
+            let gap = self.last_sequence.map(|last| sequence.wrapping_sub(last));
+            // Late or repeated packet: its slot was already played or concealed
+            if gap.is_some_and(|gap| gap == 0 || gap > u32::MAX / 2) {
+                return Ok(pcm);
+            }
+            self.last_sequence = Some(sequence);
+
+            let lost = gap.map_or(0, |gap| gap - 1);
+            if lost > 0 && lost <= MAX_CONCEALED_PACKETS {
+                debug!("Recovering {} lost uplink packets", lost);
+                pcm = self.recover(packet, lost)?;
+            }
+        }
+
+        let len = self.decoder.decode(packet, &mut self.output, false)?;
+        pcm.extend(downmix_to_mono(
+            &self.output[..len * self.channels],
+            self.channels,
+        ));
+        Ok(match self.resampler.as_mut() {
+            Some(resampler) => resampler.process(&pcm),
+            None => pcm,
+        })
+    }
+
+    // Concealment for all but the last lost packet, which FEC in `next` can restore
+    fn recover(&mut self, next: &[u8], lost: u32) -> Result<Vec<i16>> {
+        // Lost packets are assumed to be as long as the one that arrived
+        let frame = self.decoder.get_nb_samples(next)? * self.channels;
+        let mut pcm = Vec::new();
+        for i in 0..lost {
+            let fec = self.fec && i == lost - 1;
+            if !fec && !self.plc {
+                continue;
+            }
+            // An empty packet asks the decoder for concealment audio
+            let input = if fec { next } else { &[] };
+            let len = self.decoder.decode(input, &mut self.output[..frame], fec)?;
+            pcm.extend(downmix_to_mono(
+                &self.output[..len * self.channels],
+                self.channels,
+            ));
+        }
+        Ok(pcm)
+    }
+}
+
+/// Encodes TTS output for the downlink: one resample from the provider's
+/// rate, then Opus packets of the session's duration and encoder profile.
+pub fn encode_downlink(
+    speech: &Speech,
+    format: &StreamFormat,
+    settings: &OpusEncoderSettings,
+) -> Result<Vec<Vec<u8>>> {
+    let pcm = resample(&speech.pcm, speech.sample_rate, format.sample_rate);
+    OpusService::encode_frames_with(&pcm, format, settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downlink_packets_follow_the_negotiated_format() {
+        // One second of speech from a 24kHz provider
+        let speech = Speech {
+            pcm: vec![0; 24000],
+            sample_rate: 24000,
+        };
+        let settings = OpusEncoderSettings::default();
+
+        let format = StreamFormat {
+            sample_rate: 16000,
+            channels: 1,
+            frame_duration: 20,
+        };
+        assert_eq!(
+            encode_downlink(&speech, &format, &settings).unwrap().len(),
+            50
+        );
+
+        let format = StreamFormat {
+            sample_rate: 24000,
+            channels: 1,
+            frame_duration: 60,
+        };
+        assert_eq!(
+            encode_downlink(&speech, &format, &settings).unwrap().len(),
+            17
+        );
+    }
+}
@@ -1,24 +1,261 @@
-pub fn resample_24k_to_16k(input: &[i16]) -> Vec<i16> {
-    let input_len = input.len();
-    // Ratio is 16/24 = 2/3.
-    let output_len = (input_len * 2) / 3;
-    let mut output = Vec::with_capacity(output_len);
-
-    for i in 0..output_len {
-        // Calculate position in input
-        // out_index * 24 / 16 = out_index * 1.5
-        let pos = i as f32 * 1.5;
-        let index = pos.floor() as usize;
-        let frac = pos - index as f32;
-
-        if index + 1 < input_len {
-            let s0 = input[index] as f32;
-            let s1 = input[index + 1] as f32;
-            let val = s0 + (s1 - s0) * frac;
-            output.push(val as i16);
-        } else if index < input_len {
-            output.push(input[index]);
+// Zero crossings of the sinc on each side of the centre, at the filter's cutoff.
+// More means a steeper transition band at the cost of more taps.
+const ZERO_CROSSINGS: usize = 16;
+// Passband edge as a fraction of the lower Nyquist frequency
+const ROLLOFF: f64 = 0.94;
+// Kaiser window shape; 8.6 gives roughly 90dB of stopband attenuation
+const KAISER_BETA: f64 = 8.6;
+// Rate pairs with more phases than this share the nearest precomputed phase
+const MAX_PHASES: usize = 1024;
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Zeroth-order modified Bessel function of the first kind, for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..50 {
+        term *= (half_x / k as f64) * (half_x / k as f64);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Streaming windowed-sinc (polyphase) resampler for mono 16-bit PCM.
+///
+/// The rate ratio is reduced to `up / down`; each output sample is a
+/// Kaiser-windowed sinc interpolation of the input, low-passed at the lower of
+/// the two Nyquist frequencies so downsampling does not alias.
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    phases: usize,
+    // Taps per phase (both sides of the centre)
+    taps: usize,
+    // `phases` rows of `taps` coefficients
+    table: Vec<f32>,
+    // Input not yet fully consumed, including `taps / 2 - 1` samples of history
+    buffer: Vec<f32>,
+    // Time of the next output sample relative to `buffer[0]`, in 1/`up` input samples
+    position: usize,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        let divisor = gcd(from_rate as usize, to_rate as usize).max(1);
+        let up = to_rate as usize / divisor;
+        let down = from_rate as usize / divisor;
+
+        // Cutoff relative to the input Nyquist frequency
+        let cutoff = ROLLOFF * (up as f64 / down as f64).min(1.0);
+        let half = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let taps = half * 2;
+        let phases = up.min(MAX_PHASES);
+
+        let mut table = vec![0.0f32; phases * taps];
+        for phase in 0..phases {
+            let offset = phase as f64 / phases as f64;
+            let row = &mut table[phase * taps..(phase + 1) * taps];
+            let mut sum = 0.0;
+            let mut coefficients = vec![0.0f64; taps];
+            for (j, c) in coefficients.iter_mut().enumerate() {
+                // Distance from the output instant to input sample j, in input samples
+                let t = j as f64 - (half as f64 - 1.0) - offset;
+                let x = cutoff * t;
+                let sinc = if x.abs() < 1e-9 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let ratio = t / half as f64;
+                let window = if ratio.abs() >= 1.0 {
+                    0.0
+                } else {
+                    bessel_i0(KAISER_BETA * (1.0 - ratio * ratio).sqrt()) / bessel_i0(KAISER_BETA)
+                };
+                *c = cutoff * sinc * window;
+                sum += *c;
+            }
+            // Unity gain at DC for every phase
+            for (dst, c) in row.iter_mut().zip(coefficients) {
+                *dst = (c / sum) as f32;
+            }
+        }
+
+        Self {
+            up,
+            down,
+            phases,
+            taps,
+            table,
+            // History before the first sample is silence
+            buffer: vec![0.0; half - 1],
+            position: (half - 1) * up,
+        }
+    }
+
+    /// Resamples the next chunk of input; output may lag the input by the filter delay.
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        self.buffer.extend(input.iter().map(|&s| s as f32));
+
+        let half = self.taps / 2;
+        let mut output = Vec::with_capacity(input.len() * self.up / self.down + 1);
+        loop {
+            let index = self.position / self.up;
+            if index + half >= self.buffer.len() {
+                break;
+            }
+            let phase = (self.position % self.up) * self.phases / self.up;
+            let coefficients = &self.table[phase * self.taps..(phase + 1) * self.taps];
+            let window = &self.buffer[index + 1 - half..index + 1 + half];
+            let value: f32 = window.iter().zip(coefficients).map(|(x, c)| x * c).sum();
+            output.push(value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            self.position += self.down;
+        }
+
+        // Keep only the history the next output still needs
+        let consumed = (self.position / self.up + 1).saturating_sub(half);
+        if consumed > 0 {
+            self.buffer.drain(..consumed.min(self.buffer.len()));
+            self.position -= consumed * self.up;
         }
+        output
     }
+
+    /// Pushes silence through the filter to emit the last samples of the input.
+    pub fn flush(&mut self) -> Vec<i16> {
+        self.process(&vec![0; self.taps / 2])
+    }
+}
+
+/// Resamples a complete buffer of mono PCM.
+pub fn resample(input: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || from_rate == 0 || to_rate == 0 || input.is_empty() {
+        return input.to_vec();
+    }
+    let mut resampler = Resampler::new(from_rate, to_rate);
+    let mut output = resampler.process(input);
+    output.extend(resampler.flush());
+
+    let expected = (input.len() as u64 * to_rate as u64).div_ceil(from_rate as u64) as usize;
+    output.truncate(expected);
     output
 }
+
+/// Averages interleaved channels down to mono.
+pub fn downmix_to_mono(samples: &[i16], channels: usize) -> Vec<i16> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+    samples
+        .chunks(channels)
+        .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / frame.len() as i32) as i16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f64, rate: u32, seconds: f64) -> Vec<i16> {
+        let samples = (rate as f64 * seconds) as usize;
+        (0..samples)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                (16000.0 * (2.0 * std::f64::consts::PI * freq * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    // Amplitude of `freq` in `samples` (single-bin DFT over the middle half,
+    // away from the filter's start-up and flush transients)
+    fn amplitude(samples: &[i16], freq: f64, rate: u32) -> f64 {
+        let steady = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &s) in steady.iter().enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * freq * i as f64 / rate as f64;
+            re += s as f64 * phase.cos();
+            im += s as f64 * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / steady.len() as f64
+    }
+
+    fn gain_db(from: u32, to: u32, freq: f64, measured_at: f64) -> f64 {
+        let output = resample(&tone(freq, from, 1.0), from, to);
+        20.0 * (amplitude(&output, measured_at, to) / 16000.0).log10()
+    }
+
+    #[test]
+    fn passband_tones_keep_their_level() {
+        for (from, to) in [
+            (48000, 16000),
+            (16000, 48000),
+            (16000, 24000),
+            (44100, 16000),
+        ] {
+            // Up to 6kHz; the transition band sits just below the 8kHz Nyquist
+            for freq in [300.0, 1000.0, 3400.0, 6000.0] {
+                let gain = gain_db(from, to, freq, freq);
+                assert!(
+                    gain.abs() < 0.1,
+                    "{}Hz at {} -> {}: {:.3}dB",
+                    freq,
+                    from,
+                    to,
+                    gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn downsampling_rejects_what_would_alias() {
+        // Above the 8kHz output Nyquist, these would fold down to 4kHz and 2kHz
+        for (freq, alias) in [(12000.0, 4000.0), (14000.0, 2000.0)] {
+            let gain = gain_db(48000, 16000, freq, alias);
+            assert!(
+                gain < -70.0,
+                "{}Hz folded to {}Hz at {:.1}dB",
+                freq,
+                alias,
+                gain
+            );
+        }
+    }
+
+    #[test]
+    fn upsampling_rejects_images() {
+        // A 1kHz tone at 16kHz has images at 15kHz and 17kHz
+        for image in [15000.0, 17000.0] {
+            let gain = gain_db(16000, 48000, 1000.0, image);
+            assert!(gain < -70.0, "image at {}Hz: {:.1}dB", image, gain);
+        }
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let input = tone(440.0, 44100, 0.5);
+        let whole = resample(&input, 44100, 16000);
+
+        let mut resampler = Resampler::new(44100, 16000);
+        let mut streamed = Vec::new();
+        for chunk in input.chunks(333) {
+            streamed.extend(resampler.process(chunk));
+        }
+        streamed.extend(resampler.flush());
+        streamed.truncate(whole.len());
+
+        assert_eq!(streamed, whole);
+        assert_eq!(whole.len(), 8000);
+    }
+}
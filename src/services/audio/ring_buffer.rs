@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+/// Fixed-capacity buffer holding the most recent PCM samples.
+pub struct PcmRingBuffer {
+    samples: VecDeque<i16>,
+    capacity: usize,
+}
+
+impl PcmRingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends samples, dropping the oldest ones once full.
+    pub fn push(&mut self, pcm: &[i16]) {
+        if pcm.len() >= self.capacity {
+            self.samples.clear();
+            self.samples.extend(&pcm[pcm.len() - self.capacity..]);
+            return;
+        }
+        let overflow = (self.samples.len() + pcm.len()).saturating_sub(self.capacity);
+        self.samples.drain(..overflow);
+        self.samples.extend(pcm);
+    }
+
+    /// Empties the buffer, returning at most the last `max_samples` samples.
+    pub fn take_last(&mut self, max_samples: usize) -> Vec<i16> {
+        let skip = self.samples.len().saturating_sub(max_samples);
+        self.samples.drain(..).skip(skip).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_samples_across_wraparound() {
+        let mut buffer = PcmRingBuffer::new(5);
+        buffer.push(&[1, 2, 3]);
+        buffer.push(&[4, 5, 6]);
+        buffer.push(&[7]);
+        assert_eq!(buffer.take_last(5), vec![3, 4, 5, 6, 7]);
+
+        // A push larger than the buffer keeps only its tail
+        buffer.push(&[1, 2]);
+        buffer.push(&[10, 11, 12, 13, 14, 15, 16]);
+        assert_eq!(buffer.take_last(5), vec![12, 13, 14, 15, 16]);
+    }
+
+    #[test]
+    fn take_last_is_bounded_by_what_is_buffered_and_empties_the_buffer() {
+        let mut buffer = PcmRingBuffer::new(8);
+        buffer.push(&[1, 2, 3, 4]);
+        assert_eq!(buffer.take_last(100), vec![1, 2, 3, 4]);
+        assert!(buffer.take_last(100).is_empty());
+
+        buffer.push(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(buffer.take_last(3), vec![8, 9, 10]);
+        assert!(buffer.take_last(100).is_empty());
+    }
+}
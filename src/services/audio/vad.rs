@@ -0,0 +1,227 @@
+use crate::config::VadSettings;
+use anyhow::Context;
+use std::collections::VecDeque;
+use voice_activity_detector::VoiceActivityDetector;
+
+const SAMPLE_RATE: usize = 16000;
+// Silero expects 512-sample windows at 16kHz
+const CHUNK_SIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub enum VadEvent {
+    SpeechStart,
+    // The full utterance, including pre-roll and trailing padding
+    SpeechEnd { audio: Vec<i16> },
+}
+
+// Speech probability of one chunk
+type Predictor = Box<dyn FnMut(&[i16]) -> f32 + Send>;
+
+/// Silero VAD (via `voice_activity_detector`) with endpointing on top.
+///
+/// Feed it 16kHz mono PCM of any chunk size; it reports speech start once
+/// `min_speech_duration_ms` of voiced audio is heard, and speech end after
+/// `silence_duration_ms` of silence or when the utterance reaches `max_utterance_ms`.
+pub struct SileroVad {
+    predict: Predictor,
+    settings: VadSettings,
+    pending: Vec<i16>,
+    // Recent audio while idle, so the utterance can start before the trigger point
+    history: VecDeque<i16>,
+    utterance: Vec<i16>,
+    in_speech: bool,
+    voiced_samples: usize,
+    silence_samples: usize,
+}
+
+fn ms_to_samples(ms: u32) -> usize {
+    ms as usize * SAMPLE_RATE / 1000
+}
+
+impl SileroVad {
+    pub fn new(settings: VadSettings) -> anyhow::Result<Self> {
+        let mut detector = VoiceActivityDetector::builder()
+            .sample_rate(SAMPLE_RATE as i64)
+            .chunk_size(CHUNK_SIZE)
+            .build()
+            .context("Failed to create Silero VAD")?;
+
+        Ok(Self::with_predictor(settings, move |chunk| {
+            detector.predict(chunk.iter().copied())
+        }))
+    }
+
+    /// Endpointing over any source of per-chunk speech probabilities.
+    pub(crate) fn with_predictor(
+        settings: VadSettings,
+        predict: impl FnMut(&[i16]) -> f32 + Send + 'static,
+    ) -> Self {
+        Self {
+            predict: Box::new(predict),
+            settings,
+            pending: Vec::new(),
+            history: VecDeque::new(),
+            utterance: Vec::new(),
+            in_speech: false,
+            voiced_samples: 0,
+            silence_samples: 0,
+        }
+    }
+
+    /// Feeds PCM into the detector and returns any events it triggered.
+    pub fn process(&mut self, pcm: &[i16]) -> Vec<VadEvent> {
+        self.pending.extend_from_slice(pcm);
+
+        let mut events = Vec::new();
+        while self.pending.len() >= CHUNK_SIZE {
+            let chunk: Vec<i16> = self.pending.drain(..CHUNK_SIZE).collect();
+            let probability = (self.predict)(&chunk);
+            if let Some(event) = self.process_chunk(&chunk, probability) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn process_chunk(&mut self, chunk: &[i16], probability: f32) -> Option<VadEvent> {
+        let voiced = probability >= self.settings.threshold;
+        let pad_samples = ms_to_samples(self.settings.speech_pad_ms);
+
+        if !self.in_speech {
+            self.history.extend(chunk);
+            self.voiced_samples = if voiced {
+                self.voiced_samples + chunk.len()
+            } else {
+                0
+            };
+
+            let min_speech = ms_to_samples(self.settings.min_speech_duration_ms).max(1);
+            if self.voiced_samples >= min_speech {
+                // Keep the voiced run plus the configured pre-roll
+                let keep = (self.voiced_samples + pad_samples).min(self.history.len());
+                let skip = self.history.len() - keep;
+                self.utterance = self.history.drain(..).skip(skip).collect();
+                self.in_speech = true;
+                self.silence_samples = 0;
+                return Some(VadEvent::SpeechStart);
+            }
+
+            let max_history = min_speech + pad_samples;
+            while self.history.len() > max_history {
+                self.history.pop_front();
+            }
+            return None;
+        }
+
+        self.utterance.extend_from_slice(chunk);
+        if voiced {
+            self.silence_samples = 0;
+        } else {
+            self.silence_samples += chunk.len();
+        }
+
+        let silence_done = self.silence_samples >= ms_to_samples(self.settings.silence_duration_ms);
+        let too_long = self.utterance.len() >= ms_to_samples(self.settings.max_utterance_ms);
+        if silence_done || too_long {
+            // Trim trailing silence down to the configured padding
+            let excess = self.silence_samples.saturating_sub(pad_samples);
+            let len = self.utterance.len().saturating_sub(excess);
+            self.utterance.truncate(len);
+
+            self.in_speech = false;
+            self.voiced_samples = 0;
+            self.silence_samples = 0;
+            return Some(VadEvent::SpeechEnd {
+                audio: std::mem::take(&mut self.utterance),
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays back one probability per chunk; each chunk holds its own index as
+    // samples so the utterance audio shows which chunks it kept
+    fn scripted(settings: VadSettings, probabilities: Vec<f32>) -> (SileroVad, Vec<Vec<i16>>) {
+        let chunks = (0..probabilities.len())
+            .map(|i| vec![i as i16; CHUNK_SIZE])
+            .collect();
+        let mut script = probabilities.into_iter();
+        let vad = SileroVad::with_predictor(settings, move |_| script.next().unwrap_or(0.0));
+        (vad, chunks)
+    }
+
+    fn chunk_indices(audio: &[i16]) -> Vec<i16> {
+        audio.chunks(CHUNK_SIZE).map(|chunk| chunk[0]).collect()
+    }
+
+    // 32ms chunks: speech after 2 voiced chunks, 1 chunk of padding, end after 3 silent chunks
+    fn settings() -> VadSettings {
+        VadSettings {
+            threshold: 0.5,
+            min_speech_duration_ms: 64,
+            speech_pad_ms: 32,
+            silence_duration_ms: 96,
+            max_utterance_ms: 10_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn utterance_keeps_pre_roll_and_trailing_padding() {
+        let (mut vad, chunks) = scripted(
+            settings(),
+            vec![0.1, 0.9, 0.1, 0.9, 0.5, 0.9, 0.0, 0.0, 0.0, 0.0],
+        );
+        let events: Vec<(usize, VadEvent)> = chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(i, chunk)| vad.process(chunk).into_iter().map(move |e| (i, e)))
+            .collect();
+
+        // A single voiced chunk is too short; 0.5 meets the threshold
+        assert!(matches!(events[0], (4, VadEvent::SpeechStart)));
+        let (end, VadEvent::SpeechEnd { audio }) = &events[1] else {
+            panic!("expected speech end, got {:?}", events[1]);
+        };
+        assert_eq!(*end, 8);
+        // Chunk 2 is pre-roll, chunk 6 trailing padding; the rest of the silence is cut
+        assert_eq!(chunk_indices(audio), vec![2, 3, 4, 5, 6]);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn long_speech_is_split_at_max_utterance() {
+        let settings = VadSettings {
+            min_speech_duration_ms: 32,
+            speech_pad_ms: 0,
+            max_utterance_ms: 160,
+            ..settings()
+        };
+        let (mut vad, chunks) = scripted(settings, vec![0.9; 7]);
+        let events: Vec<VadEvent> = chunks.iter().flat_map(|c| vad.process(c)).collect();
+
+        assert!(matches!(events[0], VadEvent::SpeechStart));
+        let VadEvent::SpeechEnd { audio } = &events[1] else {
+            panic!("expected speech end, got {:?}", events[1]);
+        };
+        assert_eq!(chunk_indices(audio), vec![0, 1, 2, 3, 4]);
+        // Still talking: the next chunk starts a new utterance
+        assert!(matches!(events[2], VadEvent::SpeechStart));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn audio_is_buffered_into_whole_chunks() {
+        let (mut vad, _) = scripted(settings(), vec![0.9, 0.9]);
+        assert!(vad.process(&[0; CHUNK_SIZE + 100]).is_empty());
+        assert!(vad.process(&[0; CHUNK_SIZE - 101]).is_empty());
+        assert!(matches!(
+            vad.process(&[0; 1]).as_slice(),
+            [VadEvent::SpeechStart]
+        ));
+    }
+}
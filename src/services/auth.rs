@@ -0,0 +1,172 @@
+use anyhow::{anyhow, bail, Context};
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// The token from an `Authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get("authorization")?.to_str().ok()?.trim();
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|t| !t.is_empty())
+}
+
+/// Compares a presented secret without leaking where it differs.
+pub fn secret_matches(presented: Option<&str>, expected: &str) -> bool {
+    presented.is_some_and(|p| bool::from(p.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+/// What a device token vouches for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceClaims {
+    pub device_id: String,
+    pub client_id: String,
+    // Unix seconds
+    pub exp: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn claims_mac(key: &str, claims: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(claims.as_bytes());
+    mac
+}
+
+/// WebSocket token for one device, good for `ttl_secs`:
+/// `base64url(claims JSON) "." base64url(HMAC-SHA256 of the first part)`.
+pub fn issue_device_token(key: &str, device_id: &str, client_id: &str, ttl_secs: u64) -> String {
+    let claims = DeviceClaims {
+        device_id: device_id.to_string(),
+        client_id: client_id.to_string(),
+        exp: unix_now() + ttl_secs,
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("Serialize failed"));
+    let signature = claims_mac(key, &payload).finalize().into_bytes();
+    format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// Checks signature, expiry and that the token was issued for these ids.
+pub fn verify_device_token(
+    key: &str,
+    device_id: &str,
+    client_id: &str,
+    token: &str,
+) -> anyhow::Result<DeviceClaims> {
+    let (payload, signature) = token.split_once('.').context("Malformed token")?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .context("Malformed token signature")?;
+    claims_mac(key, payload)
+        .verify_slice(&signature)
+        .map_err(|_| anyhow!("Bad token signature"))?;
+
+    let claims: DeviceClaims = serde_json::from_slice(
+        &URL_SAFE_NO_PAD
+            .decode(payload)
+            .context("Malformed token claims")?,
+    )
+    .context("Malformed token claims")?;
+    if claims.device_id != device_id || claims.client_id != client_id {
+        bail!("Token issued for another device");
+    }
+    if claims.exp <= unix_now() {
+        bail!("Token expired");
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "test-key";
+
+    #[test]
+    fn accepts_a_token_for_the_ids_it_was_issued_for() {
+        let token = issue_device_token(KEY, "aa:bb", "client-1", 60);
+        let claims = verify_device_token(KEY, "aa:bb", "client-1", &token).unwrap();
+        assert_eq!(claims.device_id, "aa:bb");
+        assert_eq!(claims.client_id, "client-1");
+        assert!(claims.exp > unix_now());
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let token = issue_device_token(KEY, "aa:bb", "client-1", 0);
+        let err = verify_device_token(KEY, "aa:bb", "client-1", &token).unwrap_err();
+        assert_eq!(err.to_string(), "Token expired");
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let token = issue_device_token(KEY, "aa:bb", "client-1", 60);
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // Claims rewritten for another device under the old signature
+        let forged = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&DeviceClaims {
+                device_id: "cc:dd".to_string(),
+                client_id: "client-1".to_string(),
+                exp: unix_now() + 60,
+            })
+            .unwrap(),
+        );
+        let err = verify_device_token(
+            KEY,
+            "cc:dd",
+            "client-1",
+            &format!("{}.{}", forged, signature),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Bad token signature");
+
+        let mut flipped = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        flipped[0] ^= 1;
+        let tampered = format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(flipped));
+        assert!(verify_device_token(KEY, "aa:bb", "client-1", &tampered).is_err());
+
+        assert!(verify_device_token("other-key", "aa:bb", "client-1", &token).is_err());
+        assert!(verify_device_token(KEY, "aa:bb", "client-1", payload).is_err());
+    }
+
+    #[test]
+    fn rejects_a_token_issued_for_other_ids() {
+        let token = issue_device_token(KEY, "aa:bb", "client-1", 60);
+        for (device_id, client_id) in [("cc:dd", "client-1"), ("aa:bb", "client-2")] {
+            let err = verify_device_token(KEY, device_id, client_id, &token).unwrap_err();
+            assert_eq!(err.to_string(), "Token issued for another device");
+        }
+    }
+
+    #[test]
+    fn reads_bearer_tokens() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert("authorization", "bearer  abc ".parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some("abc"));
+        headers.insert("authorization", "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert("authorization", "Bearer ".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(secret_matches(Some("s3cret"), "s3cret"));
+        assert!(!secret_matches(Some("s3cre"), "s3cret"));
+        assert!(!secret_matches(None, "s3cret"));
+    }
+}
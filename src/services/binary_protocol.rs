@@ -0,0 +1,265 @@
+use anyhow::{anyhow, Result};
+
+// version u16, type u16, reserved u32, timestamp u32, payload_size u32
+const V2_HEADER_LEN: usize = 16;
+// type u8, reserved u8, payload_size u16
+const V3_HEADER_LEN: usize = 4;
+
+/// Framing of binary WebSocket messages, chosen by the `Protocol-Version`
+/// header or the `version` in the client hello.
+///
+/// Version 1 sends bare Opus packets. Versions 2 and 3 add the firmware's
+/// `BinaryProtocol2` / `BinaryProtocol3` header (big-endian); version 2 also
+/// carries a millisecond timestamp for lining microphone audio up with playback.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BinaryProtocol {
+    #[default]
+    V1,
+    V2,
+    V3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadType {
+    Opus,
+    Json,
+}
+
+impl PayloadType {
+    fn from_code(code: u16) -> Result<Self> {
+        match code {
+            0 => Ok(Self::Opus),
+            1 => Ok(Self::Json),
+            other => Err(anyhow!("Unknown binary payload type {}", other)),
+        }
+    }
+
+    fn code(&self) -> u16 {
+        match self {
+            Self::Opus => 0,
+            Self::Json => 1,
+        }
+    }
+}
+
+/// One binary message, with the header fields that matter to us.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryPacket {
+    pub payload_type: PayloadType,
+    // Only version 2 carries one; devices send 0 when nothing was playing
+    pub timestamp: Option<u32>,
+    // Set by transports that number their packets, to spot losses
+    pub sequence: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+impl BinaryPacket {
+    pub fn opus(payload: Vec<u8>, timestamp: Option<u32>) -> Self {
+        Self {
+            payload_type: PayloadType::Opus,
+            timestamp,
+            sequence: None,
+            payload,
+        }
+    }
+}
+
+impl BinaryProtocol {
+    pub fn from_version(version: u32) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            _ => None,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+        }
+    }
+
+    pub fn parse(&self, data: &[u8]) -> Result<BinaryPacket> {
+        match self {
+            Self::V1 => Ok(BinaryPacket::opus(data.to_vec(), None)),
+            Self::V2 => {
+                let header = data
+                    .get(..V2_HEADER_LEN)
+                    .ok_or_else(|| anyhow!("Binary packet shorter than its header"))?;
+                let version = u16::from_be_bytes([header[0], header[1]]);
+                if version != 2 {
+                    return Err(anyhow!("Expected protocol version 2, got {}", version));
+                }
+                let payload_type =
+                    PayloadType::from_code(u16::from_be_bytes([header[2], header[3]]))?;
+                let timestamp = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+                let size = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+                Ok(BinaryPacket {
+                    payload_type,
+                    timestamp: Some(timestamp),
+                    sequence: None,
+                    payload: payload(data, V2_HEADER_LEN, size as usize)?,
+                })
+            }
+            Self::V3 => {
+                let header = data
+                    .get(..V3_HEADER_LEN)
+                    .ok_or_else(|| anyhow!("Binary packet shorter than its header"))?;
+                let size = u16::from_be_bytes([header[2], header[3]]);
+                Ok(BinaryPacket {
+                    payload_type: PayloadType::from_code(header[0] as u16)?,
+                    timestamp: None,
+                    sequence: None,
+                    payload: payload(data, V3_HEADER_LEN, size as usize)?,
+                })
+            }
+        }
+    }
+
+    /// Frames `packet` for sending. Version 1 has no header to tell payload
+    /// types apart, so it refuses anything but Opus.
+    pub fn encode(&self, packet: &BinaryPacket) -> Result<Vec<u8>> {
+        let size = packet.payload.len();
+        let mut data = match self {
+            Self::V1 if packet.payload_type != PayloadType::Opus => {
+                return Err(anyhow!(
+                    "Protocol version 1 only carries Opus, not {:?}",
+                    packet.payload_type
+                ));
+            }
+            Self::V1 => Vec::with_capacity(size),
+            Self::V2 => {
+                let mut data = Vec::with_capacity(V2_HEADER_LEN + size);
+                data.extend_from_slice(&2u16.to_be_bytes());
+                data.extend_from_slice(&packet.payload_type.code().to_be_bytes());
+                data.extend_from_slice(&0u32.to_be_bytes());
+                data.extend_from_slice(&packet.timestamp.unwrap_or(0).to_be_bytes());
+                data.extend_from_slice(&(size as u32).to_be_bytes());
+                data
+            }
+            Self::V3 => {
+                let mut data = Vec::with_capacity(V3_HEADER_LEN + size);
+                data.push(packet.payload_type.code() as u8);
+                data.push(0);
+                data.extend_from_slice(&(size as u16).to_be_bytes());
+                data
+            }
+        };
+        data.extend_from_slice(&packet.payload);
+        Ok(data)
+    }
+}
+
+fn payload(data: &[u8], header_len: usize, size: usize) -> Result<Vec<u8>> {
+    data.get(header_len..header_len + size)
+        .map(|p| p.to_vec())
+        .ok_or_else(|| {
+            anyhow!(
+                "Binary payload size {} exceeds the {} bytes received",
+                size,
+                data.len() - header_len
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload_type: PayloadType, timestamp: Option<u32>) -> BinaryPacket {
+        BinaryPacket {
+            payload_type,
+            timestamp,
+            sequence: None,
+            payload: vec![0xF8, 0x01, 0x02, 0x03, 0x04],
+        }
+    }
+
+    #[test]
+    fn v1_round_trips_bare_opus() {
+        let sent = packet(PayloadType::Opus, None);
+        let data = BinaryProtocol::V1.encode(&sent).unwrap();
+        assert_eq!(data, sent.payload);
+        assert_eq!(BinaryProtocol::V1.parse(&data).unwrap(), sent);
+    }
+
+    #[test]
+    fn v1_refuses_non_audio() {
+        assert!(BinaryProtocol::V1
+            .encode(&packet(PayloadType::Json, None))
+            .is_err());
+    }
+
+    #[test]
+    fn v2_round_trips_with_timestamp() {
+        for sent in [
+            packet(PayloadType::Opus, Some(123_456)),
+            packet(PayloadType::Json, Some(0)),
+        ] {
+            let data = BinaryProtocol::V2.encode(&sent).unwrap();
+            assert_eq!(data.len(), V2_HEADER_LEN + sent.payload.len());
+            assert_eq!(&data[..2], &[0, 2]);
+            assert_eq!(BinaryProtocol::V2.parse(&data).unwrap(), sent);
+        }
+        // Sent without one, read back as 0
+        let data = BinaryProtocol::V2
+            .encode(&packet(PayloadType::Opus, None))
+            .unwrap();
+        assert_eq!(BinaryProtocol::V2.parse(&data).unwrap().timestamp, Some(0));
+    }
+
+    #[test]
+    fn v3_round_trips_without_timestamp() {
+        for sent in [
+            packet(PayloadType::Opus, None),
+            packet(PayloadType::Json, None),
+        ] {
+            let data = BinaryProtocol::V3.encode(&sent).unwrap();
+            assert_eq!(data.len(), V3_HEADER_LEN + sent.payload.len());
+            assert_eq!(BinaryProtocol::V3.parse(&data).unwrap(), sent);
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        for protocol in [BinaryProtocol::V2, BinaryProtocol::V3] {
+            let data = protocol
+                .encode(&packet(PayloadType::Opus, Some(7)))
+                .unwrap();
+            let header_len = data.len() - 5;
+            // Cut inside the header, and inside the payload
+            for len in [0, 1, header_len - 1, header_len + 2] {
+                assert!(
+                    protocol.parse(&data[..len]).is_err(),
+                    "{:?} accepted {} of {} bytes",
+                    protocol,
+                    len,
+                    data.len()
+                );
+            }
+            // An empty payload is still a packet
+            let empty = protocol
+                .encode(&BinaryPacket::opus(vec![], Some(0)))
+                .unwrap();
+            assert!(protocol.parse(&empty).unwrap().payload.is_empty());
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut data = BinaryProtocol::V2
+            .encode(&packet(PayloadType::Opus, None))
+            .unwrap();
+        data[1] = 3;
+        assert!(BinaryProtocol::V2.parse(&data).is_err());
+
+        let mut data = BinaryProtocol::V3
+            .encode(&packet(PayloadType::Opus, None))
+            .unwrap();
+        data[0] = 9;
+        assert!(BinaryProtocol::V3.parse(&data).is_err());
+    }
+}
@@ -1,13 +1,17 @@
-use crate::traits::{DbTrait, Message};
+use crate::traits::{DbTrait, DeviceFilter, DeviceRecord, Message};
 use async_trait::async_trait;
 use std::collections::{HashMap, HashSet};
 use std::sync::RwLock;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub struct InMemoryDb {
     activated_devices: RwLock<HashSet<String>>,
     pending_challenges: RwLock<HashMap<String, (String, SystemTime)>>, // DeviceId -> (Challenge, Expiry)
-    chat_history: RwLock<HashMap<String, Vec<Message>>>,               // DeviceId -> History
+    activation_codes: RwLock<HashMap<String, (String, SystemTime)>>,   // Code -> (DeviceId, Expiry)
+    device_owners: RwLock<HashMap<String, String>>,                    // DeviceId -> Owner
+    revoked_devices: RwLock<HashSet<String>>,
+    devices: RwLock<HashMap<String, DeviceRecord>>,
+    chat_history: RwLock<HashMap<String, Vec<Message>>>, // DeviceId -> History
 }
 
 impl InMemoryDb {
@@ -15,6 +19,10 @@ impl InMemoryDb {
         Self {
             activated_devices: RwLock::new(HashSet::new()),
             pending_challenges: RwLock::new(HashMap::new()),
+            activation_codes: RwLock::new(HashMap::new()),
+            device_owners: RwLock::new(HashMap::new()),
+            revoked_devices: RwLock::new(HashSet::new()),
+            devices: RwLock::new(HashMap::new()),
             chat_history: RwLock::new(HashMap::new()),
         }
     }
@@ -63,6 +71,131 @@ impl DbTrait for InMemoryDb {
         Ok(None)
     }
 
+    async fn get_activation_code(&self, device_id: &str) -> anyhow::Result<Option<String>> {
+        let codes = self.activation_codes.read().unwrap();
+        let now = SystemTime::now();
+        Ok(codes
+            .iter()
+            .find(|(_, (id, expiry))| id == device_id && now < *expiry)
+            .map(|(code, _)| code.clone()))
+    }
+
+    async fn add_activation_code(
+        &self,
+        device_id: &str,
+        code: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let mut codes = self.activation_codes.write().unwrap();
+        let now = SystemTime::now();
+        codes.retain(|_, (id, expiry)| now < *expiry && id != device_id);
+        if codes.contains_key(code) {
+            return Ok(false);
+        }
+        codes.insert(
+            code.to_string(),
+            (device_id.to_string(), now + Duration::from_secs(ttl_secs)),
+        );
+        Ok(true)
+    }
+
+    async fn bind_device(&self, code: &str, owner: &str) -> anyhow::Result<Option<String>> {
+        let device_id = {
+            let mut codes = self.activation_codes.write().unwrap();
+            match codes.remove(code) {
+                Some((device_id, expiry)) if SystemTime::now() < expiry => device_id,
+                _ => return Ok(None),
+            }
+        };
+        // The challenge stays so the device can still prove itself with it
+        self.activated_devices
+            .write()
+            .unwrap()
+            .insert(device_id.clone());
+        self.device_owners
+            .write()
+            .unwrap()
+            .insert(device_id.clone(), owner.to_string());
+        Ok(Some(device_id))
+    }
+
+    async fn is_revoked(&self, device_id: &str) -> anyhow::Result<bool> {
+        let db = self.revoked_devices.read().unwrap();
+        Ok(db.contains(device_id))
+    }
+
+    async fn revoke_device(&self, device_id: &str) -> anyhow::Result<()> {
+        let mut db = self.revoked_devices.write().unwrap();
+        db.insert(device_id.to_string());
+        Ok(())
+    }
+
+    async fn restore_device(&self, device_id: &str) -> anyhow::Result<()> {
+        let mut db = self.revoked_devices.write().unwrap();
+        db.remove(device_id);
+        Ok(())
+    }
+
+    async fn upsert_device(&self, device: &DeviceRecord) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut devices = self.devices.write().unwrap();
+        let mut record = device.clone();
+        record.first_seen = now;
+        record.last_seen = now;
+        if let Some(known) = devices.get(&device.device_id) {
+            record.first_seen = known.first_seen;
+            // Frozen once activated, so an OTA report cannot rebind the device
+            if self
+                .activated_devices
+                .read()
+                .unwrap()
+                .contains(&device.device_id)
+            {
+                record.client_id = known.client_id.clone();
+            }
+            record.board_type = record.board_type.or_else(|| known.board_type.clone());
+            record.board_name = record.board_name.or_else(|| known.board_name.clone());
+            record.chip_model = record.chip_model.or_else(|| known.chip_model.clone());
+            record.mac_address = record.mac_address.or_else(|| known.mac_address.clone());
+            record.flash_size = record.flash_size.or(known.flash_size);
+            record.app_version = record.app_version.or_else(|| known.app_version.clone());
+            if record.partition_table.is_empty() {
+                record.partition_table = known.partition_table.clone();
+            }
+            record.ssid = record.ssid.or_else(|| known.ssid.clone());
+            record.rssi = record.rssi.or(known.rssi);
+        }
+        devices.insert(device.device_id.clone(), record);
+        Ok(())
+    }
+
+    async fn get_device(&self, device_id: &str) -> anyhow::Result<Option<DeviceRecord>> {
+        Ok(self.devices.read().unwrap().get(device_id).cloned())
+    }
+
+    async fn list_devices(&self, filter: &DeviceFilter) -> anyhow::Result<Vec<DeviceRecord>> {
+        let devices = self.devices.read().unwrap();
+        let mut list: Vec<DeviceRecord> = devices
+            .values()
+            .filter(|d| {
+                filter
+                    .board
+                    .as_ref()
+                    .is_none_or(|b| d.board_type.as_ref() == Some(b))
+                    && filter
+                        .firmware_version
+                        .as_ref()
+                        .is_none_or(|v| d.app_version.as_ref() == Some(v))
+            })
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        Ok(list)
+    }
+
     async fn add_chat_history(
         &self,
         device_id: &str,
@@ -98,3 +231,32 @@ impl DbTrait for InMemoryDb {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(client_id: &str, app_version: &str) -> DeviceRecord {
+        DeviceRecord {
+            device_id: "aa:bb:cc:dd:ee:ff".to_string(),
+            client_id: client_id.to_string(),
+            app_version: Some(app_version.to_string()),
+            ..DeviceRecord::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ota_reports_cannot_rebind_an_activated_device() {
+        let db = InMemoryDb::new();
+        db.upsert_device(&report("first", "1.0.0")).await.unwrap();
+        db.upsert_device(&report("device", "1.0.0")).await.unwrap();
+        db.activate_device("aa:bb:cc:dd:ee:ff").await.unwrap();
+
+        db.upsert_device(&report("attacker", "1.1.0"))
+            .await
+            .unwrap();
+        let record = db.get_device("aa:bb:cc:dd:ee:ff").await.unwrap().unwrap();
+        assert_eq!(record.client_id, "device");
+        assert_eq!(record.app_version.as_deref(), Some("1.1.0"));
+    }
+}
@@ -1,6 +1,6 @@
-use crate::traits::{DbTrait, Message};
+use crate::traits::{DbTrait, DeviceFilter, DeviceRecord, Message};
 use async_trait::async_trait;
-use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::{SqlitePool, SqliteRow};
 use sqlx::{Pool, Row, Sqlite};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -28,6 +28,36 @@ impl SqlDb {
                 challenge TEXT NOT NULL,
                 expiry INTEGER NOT NULL
             );
+            CREATE TABLE IF NOT EXISTS activation_codes (
+                code TEXT PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                expiry INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS device_owners (
+                device_id TEXT PRIMARY KEY,
+                owner TEXT NOT NULL,
+                bound_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS revoked_devices (
+                device_id TEXT PRIMARY KEY,
+                revoked_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS devices (
+                device_id TEXT PRIMARY KEY,
+                client_id TEXT NOT NULL,
+                board_type TEXT,
+                board_name TEXT,
+                chip_model TEXT,
+                mac_address TEXT,
+                flash_size INTEGER,
+                app_version TEXT,
+                partition_table TEXT,
+                ssid TEXT,
+                rssi INTEGER,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_devices_board_type ON devices(board_type);
             CREATE TABLE IF NOT EXISTS chat_history (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 device_id TEXT NOT NULL,
@@ -121,6 +151,201 @@ impl DbTrait for SqlDb {
         Ok(None)
     }
 
+    async fn get_activation_code(&self, device_id: &str) -> anyhow::Result<Option<String>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let code: Option<String> = sqlx::query_scalar(
+            "SELECT code FROM activation_codes WHERE device_id = ? AND expiry > ?",
+        )
+        .bind(device_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(code)
+    }
+
+    async fn add_activation_code(
+        &self,
+        device_id: &str,
+        code: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut tx = self.pool.begin().await?;
+
+        // Drop expired codes and any older code for this device
+        sqlx::query("DELETE FROM activation_codes WHERE expiry <= ? OR device_id = ?")
+            .bind(now)
+            .bind(device_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let inserted = sqlx::query(
+            "INSERT OR IGNORE INTO activation_codes (code, device_id, expiry) VALUES (?, ?, ?)",
+        )
+        .bind(code)
+        .bind(device_id)
+        .bind(now + ttl_secs as i64)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+        Ok(inserted > 0)
+    }
+
+    async fn bind_device(&self, code: &str, owner: &str) -> anyhow::Result<Option<String>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut tx = self.pool.begin().await?;
+
+        let device_id: Option<String> = sqlx::query_scalar(
+            "DELETE FROM activation_codes WHERE code = ? AND expiry > ? RETURNING device_id",
+        )
+        .bind(code)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(device_id) = device_id else {
+            return Ok(None);
+        };
+
+        // The challenge stays so the device can still prove itself with it
+        sqlx::query("INSERT OR IGNORE INTO activated_devices (device_id) VALUES (?)")
+            .bind(&device_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO device_owners (device_id, owner, bound_at) VALUES (?, ?, ?)",
+        )
+        .bind(&device_id)
+        .bind(owner)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(device_id))
+    }
+
+    async fn is_revoked(&self, device_id: &str) -> anyhow::Result<bool> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM revoked_devices WHERE device_id = ?")
+                .bind(device_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(count > 0)
+    }
+
+    async fn revoke_device(&self, device_id: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query("INSERT OR IGNORE INTO revoked_devices (device_id, revoked_at) VALUES (?, ?)")
+            .bind(device_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn restore_device(&self, device_id: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM revoked_devices WHERE device_id = ?")
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn upsert_device(&self, device: &DeviceRecord) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        // Stored as JSON; NULL when the device sent none, so the old one is kept
+        let partition_table = if device.partition_table.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&device.partition_table)?)
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO devices (
+                device_id, client_id, board_type, board_name, chip_model, mac_address,
+                flash_size, app_version, partition_table, ssid, rssi, first_seen, last_seen
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(device_id) DO UPDATE SET
+                -- Frozen once activated, so an OTA report cannot rebind the device
+                client_id = CASE
+                    WHEN device_id IN (SELECT device_id FROM activated_devices) THEN client_id
+                    ELSE excluded.client_id
+                END,
+                board_type = COALESCE(excluded.board_type, board_type),
+                board_name = COALESCE(excluded.board_name, board_name),
+                chip_model = COALESCE(excluded.chip_model, chip_model),
+                mac_address = COALESCE(excluded.mac_address, mac_address),
+                flash_size = COALESCE(excluded.flash_size, flash_size),
+                app_version = COALESCE(excluded.app_version, app_version),
+                partition_table = COALESCE(excluded.partition_table, partition_table),
+                ssid = COALESCE(excluded.ssid, ssid),
+                rssi = COALESCE(excluded.rssi, rssi),
+                last_seen = excluded.last_seen
+            "#,
+        )
+        .bind(&device.device_id)
+        .bind(&device.client_id)
+        .bind(&device.board_type)
+        .bind(&device.board_name)
+        .bind(&device.chip_model)
+        .bind(&device.mac_address)
+        .bind(device.flash_size.map(|s| s as i64))
+        .bind(&device.app_version)
+        .bind(partition_table)
+        .bind(&device.ssid)
+        .bind(device.rssi)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_device(&self, device_id: &str) -> anyhow::Result<Option<DeviceRecord>> {
+        let row = sqlx::query("SELECT * FROM devices WHERE device_id = ?")
+            .bind(device_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.as_ref().map(device_from_row))
+    }
+
+    async fn list_devices(&self, filter: &DeviceFilter) -> anyhow::Result<Vec<DeviceRecord>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT * FROM devices
+            WHERE (?1 IS NULL OR board_type = ?1)
+              AND (?2 IS NULL OR app_version = ?2)
+            ORDER BY last_seen DESC
+            "#,
+        )
+        .bind(&filter.board)
+        .bind(&filter.firmware_version)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(device_from_row).collect())
+    }
+
     async fn add_chat_history(
         &self,
         device_id: &str,
@@ -180,3 +405,62 @@ impl DbTrait for SqlDb {
         Ok(messages)
     }
 }
+
+fn device_from_row(row: &SqliteRow) -> DeviceRecord {
+    let partition_table: Option<String> = row.get("partition_table");
+    let flash_size: Option<i64> = row.get("flash_size");
+    let first_seen: i64 = row.get("first_seen");
+    let last_seen: i64 = row.get("last_seen");
+    DeviceRecord {
+        device_id: row.get("device_id"),
+        client_id: row.get("client_id"),
+        board_type: row.get("board_type"),
+        board_name: row.get("board_name"),
+        chip_model: row.get("chip_model"),
+        mac_address: row.get("mac_address"),
+        flash_size: flash_size.map(|s| s as u64),
+        app_version: row.get("app_version"),
+        partition_table: partition_table
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+        ssid: row.get("ssid"),
+        rssi: row.get("rssi"),
+        first_seen: first_seen as u64,
+        last_seen: last_seen as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(client_id: &str, app_version: &str) -> DeviceRecord {
+        DeviceRecord {
+            device_id: "aa:bb:cc:dd:ee:ff".to_string(),
+            client_id: client_id.to_string(),
+            app_version: Some(app_version.to_string()),
+            ..DeviceRecord::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn ota_reports_cannot_rebind_an_activated_device() {
+        // A file, since every pooled connection to `:memory:` gets its own database
+        let path = std::env::temp_dir().join(format!("devices-test-{}.db", uuid::Uuid::new_v4()));
+        let db = SqlDb::new(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db.upsert_device(&report("first", "1.0.0")).await.unwrap();
+        db.upsert_device(&report("device", "1.0.0")).await.unwrap();
+        db.activate_device("aa:bb:cc:dd:ee:ff").await.unwrap();
+
+        db.upsert_device(&report("attacker", "1.1.0"))
+            .await
+            .unwrap();
+        let record = db.get_device("aa:bb:cc:dd:ee:ff").await.unwrap().unwrap();
+        assert_eq!(record.client_id, "device");
+        assert_eq!(record.app_version.as_deref(), Some("1.1.0"));
+        db.pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
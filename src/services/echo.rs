@@ -0,0 +1,347 @@
+use crate::config::EchoSettings;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Envelope resolution: one RMS value per 10ms (160 samples at 16kHz)
+const SLOT_SAMPLES: usize = 160;
+const SLOT_MS: u64 = 10;
+// How much input is compared against the reference at each lag
+const CORRELATION_SLOTS: usize = 50;
+// Input can arrive slightly before our playout estimate, since frames are sent ahead
+const MAX_LEAD_SLOTS: i64 = 20;
+// Envelope history kept for both directions
+const ENVELOPE_CAPACITY: usize = 500;
+// Input quieter than this (-50 dBFS) is never treated as echo
+const MIN_INPUT_RMS: f32 = 100.0;
+// Spoken text older than this is forgotten
+const TEXT_RETENTION: Duration = Duration::from_secs(60);
+
+// RMS values on a shared 10ms timeline; missing slots read as silence
+struct Envelope {
+    first_slot: u64,
+    values: VecDeque<f32>,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            first_slot: 0,
+            values: VecDeque::new(),
+        }
+    }
+
+    fn set(&mut self, slot: u64, value: f32) {
+        if self.values.is_empty() {
+            self.first_slot = slot;
+        }
+        if slot < self.first_slot {
+            return;
+        }
+        let index = (slot - self.first_slot) as usize;
+        if index >= self.values.len() {
+            self.values.resize(index + 1, 0.0);
+        }
+        self.values[index] = value;
+
+        while self.values.len() > ENVELOPE_CAPACITY {
+            self.values.pop_front();
+            self.first_slot += 1;
+        }
+    }
+
+    fn get(&self, slot: i64) -> f32 {
+        if slot < self.first_slot as i64 {
+            return 0.0;
+        }
+        self.values
+            .get((slot - self.first_slot as i64) as usize)
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+fn rms(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|&s| (s as f32) * (s as f32)).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= f32::EPSILON || var_b <= f32::EPSILON {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+// Letters and digits only, lowercased, so punctuation and spacing from STT do not matter
+fn normalize(text: &str) -> Vec<char> {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/// Fraction of the character bigrams of `heard` that also appear in `spoken`.
+///
+/// One-character transcripts ("好", "對") have no bigrams and score 0: a
+/// single character shows up in almost any reply, so it is no evidence of
+/// echo, while dropping it loses a real answer.
+fn overlap_ratio(heard: &str, spoken: &str) -> f32 {
+    let heard = normalize(heard);
+    let spoken = normalize(spoken);
+    if heard.len() < 2 || spoken.len() < 2 {
+        return 0.0;
+    }
+
+    let spoken_bigrams: Vec<(char, char)> = spoken.windows(2).map(|w| (w[0], w[1])).collect();
+    let matched = heard
+        .windows(2)
+        .filter(|w| spoken_bigrams.contains(&(w[0], w[1])))
+        .count();
+    matched as f32 / (heard.len() - 1) as f32
+}
+
+struct EchoState {
+    spoken: VecDeque<(Instant, String)>,
+    // Estimated time the device finishes playing what we have sent
+    playing_until: Option<Instant>,
+    reference: Envelope,
+    input: Envelope,
+    input_pending: Vec<i16>,
+}
+
+/// Keeps track of what the assistant just said so the device's microphone
+/// picking up its own playback is not taken as the next user turn.
+///
+/// Shared between the reply task, which records outgoing speech, and the
+/// session loop, which checks incoming transcripts and audio.
+pub struct EchoGuard {
+    settings: EchoSettings,
+    origin: Instant,
+    state: Mutex<EchoState>,
+}
+
+impl EchoGuard {
+    pub fn new(settings: EchoSettings) -> Self {
+        Self {
+            settings,
+            origin: Instant::now(),
+            state: Mutex::new(EchoState {
+                spoken: VecDeque::new(),
+                playing_until: None,
+                reference: Envelope::new(),
+                input: Envelope::new(),
+                input_pending: Vec::new(),
+            }),
+        }
+    }
+
+    /// Whether outgoing audio should be decoded and passed to `record_playback`.
+    pub fn wants_audio(&self) -> bool {
+        self.settings.enable && self.settings.audio
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, EchoState>> {
+        self.state.lock().map_err(|_| anyhow!("Poisoned lock"))
+    }
+
+    fn slot(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.origin).as_millis() as u64) / SLOT_MS
+    }
+
+    /// Records a sentence about to be spoken.
+    pub fn record_text(&self, text: &str) -> Result<()> {
+        if !self.settings.enable {
+            return Ok(());
+        }
+        let mut state = self.lock()?;
+        let now = Instant::now();
+        state.spoken.push_back((now, text.to_string()));
+        while state
+            .spoken
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > TEXT_RETENTION)
+        {
+            state.spoken.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Marks the device as playing until at least `until`.
+    pub fn extend_playback(&self, until: Instant) -> Result<()> {
+        if !self.settings.enable {
+            return Ok(());
+        }
+        let mut state = self.lock()?;
+        if state.playing_until.is_none_or(|current| current < until) {
+            state.playing_until = Some(until);
+        }
+        Ok(())
+    }
+
+    /// Records outgoing PCM that the device will start playing at `played_at`.
+    pub fn record_playback(&self, played_at: Instant, pcm: &[i16]) -> Result<()> {
+        if !self.wants_audio() {
+            return Ok(());
+        }
+        let first = self.slot(played_at);
+        let mut state = self.lock()?;
+        for (i, chunk) in pcm.chunks(SLOT_SAMPLES).enumerate() {
+            state.reference.set(first + i as u64, rms(chunk));
+        }
+        Ok(())
+    }
+
+    /// Milliseconds since the session started, stamped on outgoing audio so
+    /// devices can report which playback their microphone audio overlaps.
+    pub fn timestamp(&self, at: Instant) -> u32 {
+        at.saturating_duration_since(self.origin).as_millis() as u32
+    }
+
+    // Playback is ongoing or ended less than `window_ms` ago
+    fn in_window(state: &EchoState, window: Duration) -> bool {
+        state
+            .playing_until
+            .is_some_and(|until| Instant::now() < until + window)
+    }
+
+    /// True if `text` looks like a transcript of what we recently said.
+    pub fn is_echo_text(&self, text: &str) -> Result<bool> {
+        if !self.settings.enable {
+            return Ok(false);
+        }
+        let state = self.lock()?;
+        if !Self::in_window(&state, Duration::from_millis(self.settings.window_ms)) {
+            return Ok(false);
+        }
+
+        let spoken: String = state.spoken.iter().map(|(_, t)| t.as_str()).collect();
+        let ratio = overlap_ratio(text, &spoken);
+        Ok(ratio >= self.settings.text_similarity)
+    }
+
+    /// True if this chunk of microphone audio follows the energy envelope of
+    /// our own playback. Call it for every decoded chunk so the input timeline
+    /// stays continuous.
+    ///
+    /// `playback_timestamp` is the `timestamp` of the outgoing audio the device
+    /// was playing while recording the chunk, if it reports one.
+    pub fn is_echo_audio(&self, pcm: &[i16], playback_timestamp: Option<u32>) -> Result<bool> {
+        if !self.wants_audio() {
+            return Ok(false);
+        }
+        let now = self.slot(Instant::now());
+        let mut state = self.lock()?;
+
+        // Place the chunk where the device says it was recorded, or so that it ends now
+        state.input_pending.extend_from_slice(pcm);
+        let slots = state.input_pending.len() / SLOT_SAMPLES;
+        if slots == 0 {
+            return Ok(false);
+        }
+        let (start_slot, end_slot) = match playback_timestamp {
+            Some(ms) => {
+                let start = ms as u64 / SLOT_MS;
+                (start, start + slots as u64)
+            }
+            None => (now.saturating_sub(slots as u64), now),
+        };
+        let pending: Vec<i16> = state.input_pending.drain(..slots * SLOT_SAMPLES).collect();
+        for (i, chunk) in pending.chunks(SLOT_SAMPLES).enumerate() {
+            state.input.set(start_slot + i as u64, rms(chunk));
+        }
+
+        if !Self::in_window(&state, Duration::from_millis(self.settings.window_ms)) {
+            return Ok(false);
+        }
+
+        let end = end_slot as i64;
+        let input: Vec<f32> = (end - CORRELATION_SLOTS as i64..end)
+            .map(|s| state.input.get(s))
+            .collect();
+        let recent = &input[input.len() - slots.min(input.len())..];
+        if recent.iter().sum::<f32>() / (recent.len() as f32) < MIN_INPUT_RMS {
+            return Ok(false);
+        }
+
+        let max_lag = (self.settings.max_delay_ms / SLOT_MS) as i64;
+        let best = (-MAX_LEAD_SLOTS..=max_lag)
+            .map(|lag| {
+                let reference: Vec<f32> = (end - CORRELATION_SLOTS as i64..end)
+                    .map(|s| state.reference.get(s - lag))
+                    .collect();
+                pearson(&input, &reference)
+            })
+            .fold(f32::MIN, f32::max);
+
+        Ok(best >= self.settings.audio_correlation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> EchoGuard {
+        EchoGuard::new(EchoSettings {
+            enable: true,
+            ..EchoSettings::default()
+        })
+    }
+
+    // The assistant just said `text` and is still playing it
+    fn speaking(text: &str) -> EchoGuard {
+        let guard = guard();
+        guard.record_text(text).unwrap();
+        guard
+            .extend_playback(Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        guard
+    }
+
+    #[test]
+    fn overlap_counts_shared_bigrams_ignoring_punctuation() {
+        assert_eq!(overlap_ratio("今天天氣", "今天天氣很好。"), 1.0);
+        assert_eq!(overlap_ratio("Hello, World", "hello world!"), 1.0);
+        assert_eq!(overlap_ratio("明天下雨", "今天天氣很好"), 0.0);
+        assert!((overlap_ratio("天氣如何", "今天天氣很好") - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn single_characters_are_no_evidence_of_echo() {
+        assert_eq!(overlap_ratio("好", "好的，我幫你查一下"), 0.0);
+        assert_eq!(overlap_ratio("對！", "對，沒錯"), 0.0);
+
+        let guard = speaking("好的，要我把燈關掉嗎？對嗎？");
+        assert!(!guard.is_echo_text("好").unwrap());
+        assert!(!guard.is_echo_text("對。").unwrap());
+        assert!(guard.is_echo_text("要我把燈關掉嗎").unwrap());
+    }
+
+    #[test]
+    fn transcripts_are_only_checked_around_playback() {
+        let guard = guard();
+        guard.record_text("好的，要我把燈關掉嗎").unwrap();
+        assert!(!guard.is_echo_text("要我把燈關掉嗎").unwrap());
+
+        let disabled = EchoGuard::new(EchoSettings::default());
+        disabled.record_text("好的，要我把燈關掉嗎").unwrap();
+        disabled
+            .extend_playback(Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(!disabled.is_echo_text("要我把燈關掉嗎").unwrap());
+    }
+}
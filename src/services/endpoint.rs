@@ -0,0 +1,159 @@
+use crate::traits::{ChatResponse, LlmTrait, Message};
+use tracing::{debug, warn};
+
+/// Whether the user's utterance so far reads like a finished turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Completeness {
+    Complete,
+    Incomplete,
+    Unknown,
+}
+
+// Words that leave a sentence hanging when they come last. Only multi-character
+// words: single characters such as 的 or 在 also end "好的" and "我現在".
+const TRAILING_CONTINUATIONS: &[&str] = &[
+    "然後", "還有", "而且", "但是", "可是", "所以", "因為", "如果", "或者", "就是", "那個", "這個",
+    "還是", "以及", "並且", "不過", "的話", "想要", "然后", "还有", "因为", "那个", "这个", "还是",
+    "并且", "不过", "的话",
+];
+
+const TRAILING_CONTINUATIONS_EN: &[&str] = &[
+    "and", "or", "but", "so", "because", "if", "the", "a", "an", "to", "of", "with", "for", "um",
+    "uh", "like",
+];
+
+// Sentence-final particles that usually close a Chinese utterance
+const FINAL_PARTICLES: &[char] = &['嗎', '吗', '呢', '吧', '啊', '呀', '了', '喔', '哦', '啦'];
+
+/// Cheap rule-based completeness check on trailing punctuation, particles and conjunctions.
+///
+/// STT often closes every utterance with a full stop, so the words come first:
+/// "然後。" is still unfinished.
+pub fn rule_completeness(text: &str) -> Completeness {
+    let trimmed = text.trim();
+    let Some(punctuation) = trimmed.chars().last() else {
+        return Completeness::Unknown;
+    };
+    let words = trimmed.trim_end_matches(|c: char| c.is_whitespace() || is_punctuation(c));
+
+    if let Some(last) = words.chars().last() {
+        if TRAILING_CONTINUATIONS.iter().any(|w| words.ends_with(w)) {
+            return Completeness::Incomplete;
+        }
+
+        if let Some(word) = words.split_whitespace().last() {
+            let word = word
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase();
+            if TRAILING_CONTINUATIONS_EN.contains(&word.as_str()) {
+                return Completeness::Incomplete;
+            }
+        }
+
+        if FINAL_PARTICLES.contains(&last) {
+            return Completeness::Complete;
+        }
+    }
+
+    match punctuation {
+        '。' | '？' | '！' | '?' | '!' | '.' => Completeness::Complete,
+        '，' | ',' | '、' | '…' | '-' => Completeness::Incomplete,
+        _ => Completeness::Unknown,
+    }
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || matches!(c, '。' | '？' | '！' | '，' | '、' | '…' | '；' | '：')
+}
+
+/// Asks the LLM whether `text` is a finished request.
+pub async fn llm_completeness(llm: &dyn LlmTrait, text: &str) -> Completeness {
+    let prompt = format!(
+        "You are an end-of-turn detector for a voice assistant. The user may still be talking. \
+         Answer with exactly one word, COMPLETE or INCOMPLETE, telling whether this transcript \
+         is a finished request: \"{}\"",
+        text.trim()
+    );
+    let messages = vec![Message {
+        role: "user".to_string(),
+        content: prompt,
+        tool_calls: vec![],
+        tool_call_id: None,
+    }];
+
+    match llm.chat(messages, None).await {
+        Ok(ChatResponse::Text(answer)) => {
+            let answer = answer.to_uppercase();
+            debug!("End-of-turn classification: {}", answer.trim());
+            if answer.contains("INCOMPLETE") {
+                Completeness::Incomplete
+            } else if answer.contains("COMPLETE") {
+                Completeness::Complete
+            } else {
+                Completeness::Unknown
+            }
+        }
+        Ok(ChatResponse::ToolCall(_)) => Completeness::Unknown,
+        Err(e) => {
+            warn!("End-of-turn classification failed: {}", e);
+            Completeness::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn punctuation_closes_a_plain_sentence() {
+        assert_eq!(rule_completeness("今天天氣很好。"), Completeness::Complete);
+        assert_eq!(
+            rule_completeness("What time is it?"),
+            Completeness::Complete
+        );
+        assert_eq!(
+            rule_completeness("今天天氣很好，"),
+            Completeness::Incomplete
+        );
+        assert_eq!(rule_completeness("今天天氣很好"), Completeness::Unknown);
+        assert_eq!(rule_completeness("  "), Completeness::Unknown);
+    }
+
+    #[test]
+    fn trailing_conjunction_outweighs_a_full_stop() {
+        assert_eq!(
+            rule_completeness("我想去公園然後。"),
+            Completeness::Incomplete
+        );
+        assert_eq!(
+            rule_completeness("我想去公园但是 。"),
+            Completeness::Incomplete
+        );
+        assert_eq!(
+            rule_completeness("I want coffee and."),
+            Completeness::Incomplete
+        );
+        assert_eq!(
+            rule_completeness("I want coffee and"),
+            Completeness::Incomplete
+        );
+    }
+
+    #[test]
+    fn final_particle_closes_a_sentence() {
+        assert_eq!(rule_completeness("你吃飯了嗎"), Completeness::Complete);
+        assert_eq!(rule_completeness("好吧！"), Completeness::Complete);
+    }
+
+    #[test]
+    fn short_answers_ending_in_common_characters_are_not_held_open() {
+        for text in ["好的。", "是的。", "真的！", "對的。", "他不在。"] {
+            assert_eq!(rule_completeness(text), Completeness::Complete, "{}", text);
+        }
+        assert_eq!(rule_completeness("是的"), Completeness::Unknown);
+        assert_eq!(rule_completeness("我現在"), Completeness::Unknown);
+        assert_eq!(rule_completeness("我想要。"), Completeness::Incomplete);
+        assert_eq!(rule_completeness("明天下雨的話"), Completeness::Incomplete);
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{bail, Context};
+use semver::Version;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// One firmware binary, stored as `<dir>/<board>/<version>.bin`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareBuild {
+    pub board: String,
+    pub version: Version,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl FirmwareBuild {
+    // Path under the firmware directory, also the download path under `/firmware/`
+    pub fn path(&self) -> String {
+        format!("{}/{}.bin", self.board, self.version)
+    }
+}
+
+/// Firmware binaries on disk, indexed by board type.
+pub struct FirmwareRepository {
+    dir: PathBuf,
+    builds: RwLock<Vec<FirmwareBuild>>,
+}
+
+impl FirmwareRepository {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            builds: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Rescans the directory, hashing every binary. Returns the build count.
+    pub async fn reload(&self) -> anyhow::Result<usize> {
+        let dir = self.dir.clone();
+        let builds = tokio::task::spawn_blocking(move || scan(&dir)).await??;
+        let count = builds.len();
+        *self.builds.write().unwrap() = builds;
+        info!("Firmware repository: {} build(s) in {:?}", count, self.dir);
+        Ok(count)
+    }
+
+    pub fn builds(&self) -> Vec<FirmwareBuild> {
+        self.builds.read().unwrap().clone()
+    }
+
+    /// The newest build for `board`, if it is newer than `current`.
+    pub fn newer_than(&self, board: &str, current: &str) -> Option<FirmwareBuild> {
+        let current = parse_version(current)?;
+        self.builds
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|b| b.board == board && b.version > current)
+            .max_by(|a, b| a.version.cmp(&b.version))
+            .cloned()
+    }
+
+    /// Saves an uploaded binary and rescans.
+    pub async fn store(&self, board: &str, version: &str, data: &[u8]) -> anyhow::Result<()> {
+        if !is_safe_name(board) {
+            bail!("Invalid board type '{}'", board);
+        }
+        let version = parse_version(version).context("Invalid firmware version")?;
+        let board_dir = self.dir.join(board);
+        tokio::fs::create_dir_all(&board_dir).await?;
+
+        // Write next to the target and rename over it, so devices and rescans
+        // never see a half-written binary. The temp name is not a `.bin`.
+        let path = board_dir.join(format!("{}.bin", version));
+        let temp = board_dir.join(format!(".{}.{}.tmp", version, uuid::Uuid::new_v4()));
+        if let Err(e) = write_then_rename(&temp, &path, data).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e).with_context(|| format!("Failed to save firmware {:?}", path));
+        }
+        self.reload().await?;
+        Ok(())
+    }
+}
+
+async fn write_then_rename(temp: &Path, path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(temp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(temp, path).await
+}
+
+/// Semantic version as firmware reports it; tolerates a `v` prefix and a
+/// missing minor or patch number ("1.6" is 1.6.0).
+pub fn parse_version(s: &str) -> Option<Version> {
+    let s = s.trim().trim_start_matches('v');
+    Version::parse(s)
+        .or_else(|_| Version::parse(&format!("{}.0", s)))
+        .or_else(|_| Version::parse(&format!("{}.0.0", s)))
+        .ok()
+}
+
+// Board types become path segments
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn scan(dir: &Path) -> anyhow::Result<Vec<FirmwareBuild>> {
+    let mut builds = Vec::new();
+    if !dir.exists() {
+        return Ok(builds);
+    }
+    // One unreadable file or board shouldn't hide every other build
+    for board_entry in std::fs::read_dir(dir)? {
+        let board_entry = match board_entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping unreadable entry in {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        if !board_entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let board = board_entry.file_name().to_string_lossy().to_string();
+        if !is_safe_name(&board) {
+            continue;
+        }
+        let entries = match std::fs::read_dir(board_entry.path()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(
+                    "Skipping unreadable board directory {:?}: {}",
+                    board_entry.path(),
+                    e
+                );
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("Skipping unreadable entry for board {}: {}", board, e);
+                    continue;
+                }
+            };
+            if path.extension().and_then(|e| e.to_str()) != Some("bin") {
+                continue;
+            }
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            // Served by file name, so only canonical names count
+            let Some(version) = parse_version(stem).filter(|v| v.to_string() == stem) else {
+                warn!("Skipping firmware with unrecognized version: {:?}", path);
+                continue;
+            };
+            let data = match std::fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Skipping unreadable firmware {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            builds.push(FirmwareBuild {
+                board: board.clone(),
+                version,
+                size: data.len() as u64,
+                sha256: hex::encode(Sha256::digest(&data)),
+            });
+        }
+    }
+    Ok(builds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("firmware-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn store_replaces_builds_without_leaving_temp_files() {
+        let dir = TempDir::new();
+        let repository = FirmwareRepository::new(&dir.0);
+
+        repository.store("board-a", "v1.2", b"first").await.unwrap();
+        repository
+            .store("board-a", "1.2.0", b"second build")
+            .await
+            .unwrap();
+
+        let builds = repository.builds();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].path(), "board-a/1.2.0.bin");
+        assert_eq!(builds[0].size, 12);
+        let files: Vec<_> = std::fs::read_dir(dir.0.join("board-a"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["1.2.0.bin"]);
+
+        assert!(repository.store("../etc", "1.0.0", b"x").await.is_err());
+        assert!(repository.store("board-a", "latest", b"x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scan_skips_what_it_cannot_read() {
+        let dir = TempDir::new();
+        let board = dir.0.join("board-a");
+        std::fs::create_dir_all(&board).unwrap();
+        std::fs::write(board.join("1.0.0.bin"), b"good").unwrap();
+        // A directory named like a binary fails to read as a file
+        std::fs::create_dir_all(board.join("2.0.0.bin")).unwrap();
+        std::fs::write(board.join(".3.0.0.1234.tmp"), b"partial").unwrap();
+        std::fs::write(dir.0.join("stray.bin"), b"not a board").unwrap();
+
+        let repository = FirmwareRepository::new(&dir.0);
+        assert_eq!(repository.reload().await.unwrap(), 1);
+        assert_eq!(
+            repository.newer_than("board-a", "0.9").unwrap().path(),
+            "board-a/1.0.0.bin"
+        );
+        assert!(repository.newer_than("board-a", "1.0.0").is_none());
+    }
+}
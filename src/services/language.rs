@@ -0,0 +1,246 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// A run of text spoken in a single language.
+#[derive(Debug, Clone)]
+pub struct LanguageSegment {
+    pub text: String,
+    pub language: Option<String>,
+}
+
+fn language_tag_regex() -> &'static Regex {
+    static LANG_TAG_REGEX: OnceLock<Regex> = OnceLock::new();
+    LANG_TAG_REGEX.get_or_init(|| Regex::new(r"\[lang:([A-Za-z-]+)\]").expect("Invalid Regex"))
+}
+
+/// Guesses the language of `text` from the Unicode scripts it contains.
+///
+/// Kana wins over Han so Japanese sentences with kanji are not treated as Chinese.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let (mut han, mut kana, mut hangul, mut latin) = (0, 0, 0, 0);
+    for c in text.chars() {
+        match c {
+            '\u{3040}'..='\u{30FF}' => kana += 1,
+            '\u{1100}'..='\u{11FF}' | '\u{AC00}'..='\u{D7AF}' => hangul += 1,
+            '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' => han += 1,
+            c if c.is_ascii_alphabetic() => latin += 1,
+            _ => {}
+        }
+    }
+
+    if kana > 0 {
+        Some("ja")
+    } else if hangul > 0 {
+        Some("ko")
+    } else if han > 0 {
+        Some("zh")
+    } else if latin > 0 {
+        Some("en")
+    } else {
+        None
+    }
+}
+
+/// Removes `[lang:xx]` tags emitted by the LLM.
+pub fn strip_language_tags(text: &str) -> String {
+    language_tag_regex()
+        .replace_all(text, "")
+        .trim()
+        .to_string()
+}
+
+// Splits on sentence terminators, keeping the terminator with its sentence.
+fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        current.push(c);
+        let is_end = match c {
+            '。' | '！' | '？' | '!' | '?' | '\n' => true,
+            // Avoid splitting decimals like "3.5"
+            '.' => chars.peek().is_none_or(|n| n.is_whitespace()),
+            _ => false,
+        };
+        if is_end {
+            sentences.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.trim().is_empty() {
+        sentences.push(current);
+    }
+    sentences
+}
+
+/// Splits an LLM reply into runs of sentences sharing one language.
+///
+/// The language of each sentence comes from the last `[lang:xx]` tag before it, otherwise
+/// from its script. Sentences without a recognizable script (numbers, punctuation) stay with
+/// the previous run; `fallback` (usually the language detected by STT) is used when nothing
+/// else is known.
+pub fn split_language_segments(text: &str, fallback: Option<&str>) -> Vec<LanguageSegment> {
+    let re = language_tag_regex();
+    let mut segments: Vec<LanguageSegment> = Vec::new();
+    let mut tagged: Option<String> = None;
+    let mut last_end = 0;
+
+    let push_chunk = |chunk: &str, tag: Option<&str>, segments: &mut Vec<LanguageSegment>| {
+        for sentence in split_sentences(chunk) {
+            if sentence.trim().is_empty() {
+                continue;
+            }
+            let language = tag
+                .map(|t| t.to_string())
+                .or_else(|| detect_language(&sentence).map(|l| l.to_string()));
+
+            match segments.last_mut() {
+                Some(last) if language.is_none() || last.language == language => {
+                    last.text.push_str(&sentence);
+                }
+                _ => segments.push(LanguageSegment {
+                    text: sentence,
+                    language: language.or_else(|| fallback.map(|f| f.to_string())),
+                }),
+            }
+        }
+    };
+
+    for caps in re.captures_iter(text) {
+        let m = caps.get(0).expect("Capture 0 always exists");
+        push_chunk(&text[last_end..m.start()], tagged.as_deref(), &mut segments);
+        tagged = Some(caps[1].to_lowercase());
+        last_end = m.end();
+    }
+    push_chunk(&text[last_end..], tagged.as_deref(), &mut segments);
+
+    for segment in segments.iter_mut() {
+        segment.text = segment.text.trim().to_string();
+    }
+    segments
+}
+
+/// Looks up a per-language voice, trying the full tag ("zh-TW"), then its primary
+/// subtag ("zh"), then the macrolanguage for Chinese languages such as Cantonese ("yue").
+pub fn voice_for_language<'a>(
+    voices: &'a HashMap<String, String>,
+    language: Option<&str>,
+    default_voice: &'a str,
+) -> &'a str {
+    let Some(lang) = language else {
+        return default_voice;
+    };
+    let lang = lang.to_lowercase();
+    let primary = lang.split('-').next().unwrap_or_default();
+    voices
+        .get(&lang)
+        .or_else(|| voices.get(primary))
+        .or_else(|| macrolanguage(primary).and_then(|macro_lang| voices.get(macro_lang)))
+        .map(|v| v.as_str())
+        .unwrap_or(default_voice)
+}
+
+// SenseVoice reports Cantonese as "yue"; without a voice of its own it is read
+// by the Chinese one
+fn macrolanguage(primary: &str) -> Option<&'static str> {
+    matches!(primary, "yue" | "cmn" | "wuu" | "nan" | "hak").then_some("zh")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(text: &str, fallback: Option<&str>) -> Vec<(String, Option<String>)> {
+        split_language_segments(text, fallback)
+            .into_iter()
+            .map(|segment| (segment.text, segment.language))
+            .collect()
+    }
+
+    fn segment(text: &str, language: &str) -> (String, Option<String>) {
+        (text.to_string(), Some(language.to_string()))
+    }
+
+    #[test]
+    fn detects_language_from_script() {
+        assert_eq!(detect_language("今天天氣很好"), Some("zh"));
+        // Kanji alongside kana is Japanese
+        assert_eq!(detect_language("日本語を話します"), Some("ja"));
+        assert_eq!(detect_language("안녕하세요"), Some("ko"));
+        assert_eq!(detect_language("Good morning"), Some("en"));
+        assert_eq!(detect_language("12:30 !"), None);
+    }
+
+    #[test]
+    fn splits_mixed_chinese_english_and_japanese() {
+        assert_eq!(
+            segments(
+                "你好！今天很冷。Hello there. How are you? こんにちは。",
+                None
+            ),
+            vec![
+                segment("你好！今天很冷。", "zh"),
+                segment("Hello there. How are you?", "en"),
+                segment("こんにちは。", "ja"),
+            ]
+        );
+    }
+
+    #[test]
+    fn language_tags_override_the_script() {
+        assert_eq!(
+            segments("[lang:ja]Tokyo is nice. [lang:EN]是的。", None),
+            vec![segment("Tokyo is nice.", "ja"), segment("是的。", "en")]
+        );
+        assert_eq!(
+            strip_language_tags("[lang:ja]Tokyo is nice."),
+            "Tokyo is nice."
+        );
+    }
+
+    #[test]
+    fn punctuation_and_numbers_stay_with_their_sentence() {
+        // Script-less sentences join the run before them; decimals are not split
+        assert_eq!(
+            segments("溫度是 25.5 度。123！Now 3.5 more.", None),
+            vec![
+                segment("溫度是 25.5 度。123！", "zh"),
+                segment("Now 3.5 more.", "en")
+            ]
+        );
+        // With nothing before them, they take the fallback language
+        assert_eq!(
+            segments("42！你好", Some("en")),
+            vec![segment("42！", "en"), segment("你好", "zh")]
+        );
+        assert_eq!(segments("42！", None), vec![("42！".to_string(), None)]);
+    }
+
+    #[test]
+    fn voice_lookup_falls_back_from_region_to_language_to_default() {
+        let voices = HashMap::from([
+            ("zh".to_string(), "zh-CN-XiaoxiaoNeural".to_string()),
+            ("zh-tw".to_string(), "zh-TW-HsiaoChenNeural".to_string()),
+            ("en".to_string(), "en-US-AriaNeural".to_string()),
+        ]);
+        let voice = |language| voice_for_language(&voices, language, "default");
+
+        assert_eq!(voice(Some("zh-TW")), "zh-TW-HsiaoChenNeural");
+        assert_eq!(voice(Some("zh-HK")), "zh-CN-XiaoxiaoNeural");
+        assert_eq!(voice(Some("en-GB")), "en-US-AriaNeural");
+        assert_eq!(voice(Some("yue")), "zh-CN-XiaoxiaoNeural");
+        assert_eq!(voice(Some("fr")), "default");
+        assert_eq!(voice(None), "default");
+
+        // A Cantonese voice of its own wins over the Chinese one
+        let voices = HashMap::from([
+            ("zh".to_string(), "zh-CN-XiaoxiaoNeural".to_string()),
+            ("yue".to_string(), "zh-HK-HiuGaaiNeural".to_string()),
+        ]);
+        assert_eq!(
+            voice_for_language(&voices, Some("yue"), "default"),
+            "zh-HK-HiuGaaiNeural"
+        );
+    }
+}
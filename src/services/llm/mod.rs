@@ -3,3 +3,5 @@ pub mod ollama;
 pub mod openai;
 
 pub const TECH_INSTRUCTION: &str = "If the user indicates they want you to sleep, stop, or shut up, please politely reply that you are taking a break and append the [SLEEP] tag to the end of your response.";
+
+pub const LANGUAGE_INSTRUCTION: &str = "Reply in the language the user speaks. A [lang:xx] tag at the start of the user message tells you which language was detected. When a reply mixes languages, put a tag such as [lang:en] or [lang:zh] before each part written in a different language.";
@@ -1,6 +1,14 @@
 pub mod audio;
+pub mod auth;
+pub mod binary_protocol;
 pub mod db;
+pub mod echo;
+pub mod endpoint;
+pub mod firmware;
+pub mod language;
 pub mod llm;
 pub mod mcp;
+pub mod rate_limit;
 pub mod stt;
 pub mod tts;
+pub mod udp_audio;
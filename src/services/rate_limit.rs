@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Counts failed attempts per client address and blocks an address once it
+/// reaches `max_failures` within `window`.
+pub struct FailureLimiter {
+    max_failures: u32,
+    window: Duration,
+    // Failures so far and when the first one in the current window happened
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl FailureLimiter {
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_blocked(&self, addr: IpAddr) -> anyhow::Result<bool> {
+        let mut failures = self
+            .failures
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+        let now = Instant::now();
+        failures.retain(|_, (_, since)| now.duration_since(*since) < self.window);
+        Ok(failures
+            .get(&addr)
+            .is_some_and(|(count, _)| *count >= self.max_failures))
+    }
+
+    pub fn record_failure(&self, addr: IpAddr) -> anyhow::Result<()> {
+        let mut failures = self
+            .failures
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+        let now = Instant::now();
+        let entry = failures.entry(addr).or_insert((0, now));
+        if now.duration_since(entry.1) >= self.window {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn blocks_after_too_many_failures_until_the_window_passes() {
+        let limiter = FailureLimiter::new(3, Duration::from_secs(60));
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(!limiter.is_blocked(addr).unwrap());
+            limiter.record_failure(addr).unwrap();
+        }
+        assert!(limiter.is_blocked(addr).unwrap());
+        assert!(!limiter.is_blocked(other).unwrap());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!limiter.is_blocked(addr).unwrap());
+    }
+}
@@ -1 +1,2 @@
 pub mod sensevoice;
+pub mod vad_gate;
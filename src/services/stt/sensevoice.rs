@@ -1,5 +1,5 @@
 use crate::config::VadSettings;
-use crate::traits::{SttEvent, SttTrait};
+use crate::traits::{SttEvent, SttTrait, TranscriptSegment, Transcription};
 use async_stream::stream;
 use async_trait::async_trait;
 use futures_util::stream::{BoxStream, StreamExt};
@@ -85,6 +85,67 @@ impl SttTrait for SenseVoiceStt {
         }
     }
 
+    /// Transcribes a complete recording, keeping SenseVoice's VAD segments and languages.
+    async fn transcribe(&self, pcm: &[i16]) -> anyhow::Result<Transcription> {
+        let start = Instant::now();
+        let shared_model = self.shared_model.clone();
+        let pcm_owned = pcm.to_vec();
+        // Inference on a long upload takes seconds; keep it off the async workers
+        let result = tokio::task::spawn_blocking(move || {
+            let mut model_guard = shared_model
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            let sv = model_guard
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("SenseVoice model not initialized"))?;
+            sv.infer_vec(pcm_owned, 16000)
+                .map_err(|e| anyhow::anyhow!("Inference failed: {}", e))
+        })
+        .await??;
+
+        let segments: Vec<TranscriptSegment> = result
+            .into_iter()
+            .filter(|vt| !vt.content.trim().is_empty())
+            .map(|vt| TranscriptSegment {
+                start: vt.start_ms as f64 / 1000.0,
+                end: vt.end_ms as f64 / 1000.0,
+                language: language_code(&vt.language),
+                text: vt.content,
+            })
+            .collect();
+        let text = segments
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        // The language spoken the longest
+        let mut durations: Vec<(String, f64)> = Vec::new();
+        for segment in &segments {
+            if let Some(lang) = &segment.language {
+                match durations.iter_mut().find(|(l, _)| l == lang) {
+                    Some((_, d)) => *d += segment.end - segment.start,
+                    None => durations.push((lang.clone(), segment.end - segment.start)),
+                }
+            }
+        }
+        let language = durations
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(l, _)| l);
+
+        info!(
+            "Transcribed {} segments in {:?}",
+            segments.len(),
+            start.elapsed()
+        );
+        Ok(Transcription {
+            text,
+            language,
+            duration: pcm.len() as f64 / 16000.0,
+            segments,
+        })
+    }
+
     /// Starts a streaming speech recognition session.
     ///
     /// This spawns a new OS thread and a Tokio runtime to handle the `!Send` stream
@@ -123,7 +184,10 @@ impl SttTrait for SenseVoiceStt {
                                     SenseVoiceLanguage::NoSpeech => Ok(SttEvent::NoSpeech),
                                     _ => {
                                         if !vt.content.is_empty() {
-                                            Ok(SttEvent::Text(vt.content))
+                                            Ok(SttEvent::Text {
+                                                language: language_code(&vt.language),
+                                                text: vt.content,
+                                            })
                                         } else {
                                             continue;
                                         }
@@ -152,6 +216,19 @@ impl SttTrait for SenseVoiceStt {
     }
 }
 
+// Maps SenseVoice's language tag to an ISO 639 code
+fn language_code(language: &SenseVoiceLanguage) -> Option<String> {
+    let code = match language {
+        SenseVoiceLanguage::Zh => "zh",
+        SenseVoiceLanguage::En => "en",
+        SenseVoiceLanguage::Yue => "yue",
+        SenseVoiceLanguage::Ja => "ja",
+        SenseVoiceLanguage::Ko => "ko",
+        _ => return None,
+    };
+    Some(code.to_string())
+}
+
 // Helper to chunk the stream into fixed size vectors
 fn chunk_stream<S>(mut input: S, chunk_size: usize) -> impl Stream<Item = Vec<i16>>
 where
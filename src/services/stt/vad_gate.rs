@@ -0,0 +1,193 @@
+use crate::config::VadSettings;
+use crate::services::audio::vad::{SileroVad, VadEvent};
+use crate::services::language::detect_language;
+use crate::traits::{SttEvent, SttTrait, Transcription};
+use async_stream::stream;
+use async_trait::async_trait;
+use futures_util::stream::{BoxStream, StreamExt};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tracing::{debug, info};
+
+/// Runs our own Silero VAD in front of any STT provider.
+///
+/// Audio is segmented into utterances locally and each one is sent to the
+/// inner provider's `recognize`, so providers without a VAD can be streamed.
+pub struct VadGatedStt {
+    inner: Arc<dyn SttTrait + Send + Sync>,
+    vad_settings: VadSettings,
+}
+
+impl VadGatedStt {
+    pub fn new(inner: Arc<dyn SttTrait + Send + Sync>, vad_settings: VadSettings) -> Self {
+        info!(
+            "Using Silero VAD (threshold {}, silence {}ms) in front of STT",
+            vad_settings.threshold, vad_settings.silence_duration_ms
+        );
+        Self {
+            inner,
+            vad_settings,
+        }
+    }
+}
+
+#[async_trait]
+impl SttTrait for VadGatedStt {
+    async fn recognize(&self, audio: &[u8]) -> anyhow::Result<String> {
+        self.inner.recognize(audio).await
+    }
+
+    async fn transcribe(&self, pcm: &[i16]) -> anyhow::Result<Transcription> {
+        self.inner.transcribe(pcm).await
+    }
+
+    async fn stream_speech(
+        &self,
+        input_stream: BoxStream<'static, Vec<i16>>,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<SttEvent>>> {
+        let vad = SileroVad::new(self.vad_settings.clone())?;
+        Ok(segment(vad, self.inner.clone(), input_stream))
+    }
+}
+
+// Recognizes each utterance the VAD cuts from the input
+fn segment(
+    mut vad: SileroVad,
+    inner: Arc<dyn SttTrait + Send + Sync>,
+    mut input_stream: BoxStream<'static, Vec<i16>>,
+) -> BoxStream<'static, anyhow::Result<SttEvent>> {
+    let output = stream! {
+        while let Some(pcm) = input_stream.next().await {
+            for event in vad.process(&pcm) {
+                match event {
+                    VadEvent::SpeechStart => {
+                        debug!("VAD: speech start");
+                        yield Ok(SttEvent::SpeechStart);
+                    }
+                    VadEvent::SpeechEnd { audio } => {
+                        debug!("VAD: speech end ({} samples)", audio.len());
+                        let bytes: Vec<u8> =
+                            audio.iter().flat_map(|s| s.to_le_bytes()).collect();
+                        // Local providers run inference inside `recognize`
+                        let provider = inner.clone();
+                        let recognized = tokio::task::spawn_blocking(move || {
+                            Handle::current().block_on(provider.recognize(&bytes))
+                        })
+                        .await;
+                        match recognized {
+                            Ok(Ok(text)) if !text.trim().is_empty() => {
+                                let language = detect_language(&text).map(|l| l.to_string());
+                                yield Ok(SttEvent::Text { text, language });
+                            }
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => yield Err(e),
+                            Err(e) => yield Err(anyhow::anyhow!("STT task failed: {}", e)),
+                        }
+                        yield Ok(SttEvent::NoSpeech);
+                    }
+                }
+            }
+        }
+    };
+
+    Box::pin(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Answers the first utterance and hears nothing in the rest
+    struct FirstOnlyStt {
+        calls: AtomicUsize,
+        samples: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SttTrait for FirstOnlyStt {
+        async fn recognize(&self, audio: &[u8]) -> anyhow::Result<String> {
+            self.samples.fetch_add(audio.len() / 2, Ordering::SeqCst);
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(if call == 0 {
+                "你好".into()
+            } else {
+                String::new()
+            })
+        }
+
+        async fn stream_speech(
+            &self,
+            _input_stream: BoxStream<'static, Vec<i16>>,
+        ) -> anyhow::Result<BoxStream<'static, anyhow::Result<SttEvent>>> {
+            anyhow::bail!("Only recognize is used behind the VAD")
+        }
+    }
+
+    fn tone(ms: usize) -> Vec<i16> {
+        (0..ms * 16)
+            .map(|i| ((i as f32 * 440.0 * std::f32::consts::TAU / 16000.0).sin() * 8000.0) as i16)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tone_bursts_become_recognized_utterances() {
+        let settings = VadSettings {
+            threshold: 0.5,
+            min_speech_duration_ms: 64,
+            speech_pad_ms: 32,
+            silence_duration_ms: 320,
+            max_utterance_ms: 10_000,
+            ..Default::default()
+        };
+        // Loud chunks count as speech
+        let vad = SileroVad::with_predictor(settings, |chunk| {
+            let peak = chunk.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0);
+            if peak > 1000 {
+                0.9
+            } else {
+                0.0
+            }
+        });
+        let stt = Arc::new(FirstOnlyStt {
+            calls: AtomicUsize::new(0),
+            samples: AtomicUsize::new(0),
+        });
+
+        let mut audio = vec![0i16; 16 * 320];
+        audio.extend(tone(480));
+        audio.extend(vec![0; 16 * 640]);
+        audio.extend(tone(480));
+        audio.extend(vec![0; 16 * 640]);
+        let chunks: Vec<Vec<i16>> = audio.chunks(960).map(<[i16]>::to_vec).collect();
+
+        let events: Vec<SttEvent> = segment(
+            vad,
+            stt.clone(),
+            Box::pin(futures_util::stream::iter(chunks)),
+        )
+        .map(|event| event.unwrap())
+        .collect()
+        .await;
+        let summary: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                SttEvent::SpeechStart => "start".to_string(),
+                SttEvent::Text { text, language } => format!("{}:{:?}", text, language),
+                SttEvent::NoSpeech => "end".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec!["start", "你好:Some(\"zh\")", "end", "start", "end"]
+        );
+
+        // Each utterance is the tone plus about 32ms of padding on either side
+        let per_utterance = stt.samples.load(Ordering::SeqCst) / 2;
+        assert!(
+            (16 * 480..=16 * 560).contains(&per_utterance),
+            "{}",
+            per_utterance
+        );
+    }
+}
@@ -0,0 +1,444 @@
+use crate::config::OpusEncoderSettings;
+use crate::services::audio::opus_codec::{OpusService, StreamFormat};
+use crate::services::audio::resampler::resample;
+use crate::services::language::split_language_segments;
+use crate::traits::{Speech, TtsTrait};
+use anyhow::Context;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+// Rates libopus encodes at; speech is stored at the lowest one that keeps its bandwidth
+const OPUS_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+const FRAME_DURATION_MS: u32 = 60;
+
+struct CacheEntry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+// In-memory view of the cache directory, used for LRU eviction
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total_size: u64,
+}
+
+/// Caches synthesized speech on disk, as Opus packets, in front of any TTS provider.
+///
+/// Entries are keyed by a hash of the provider fingerprint (provider, voices,
+/// rate, pitch, volume), the text, the emotion and the language. When the
+/// cache grows past `max_bytes`, the least recently used entries are removed.
+pub struct CachedTts {
+    inner: Arc<dyn TtsTrait + Send + Sync>,
+    fingerprint: String,
+    dir: PathBuf,
+    max_bytes: u64,
+    max_text_chars: usize,
+    index: Mutex<CacheIndex>,
+}
+
+impl CachedTts {
+    pub async fn new(
+        inner: Arc<dyn TtsTrait + Send + Sync>,
+        fingerprint: String,
+        dir: PathBuf,
+        max_bytes: u64,
+        max_text_chars: usize,
+    ) -> anyhow::Result<Self> {
+        let scan_dir = dir.clone();
+        let (entries, total_size) =
+            tokio::task::spawn_blocking(move || scan_dir_entries(&scan_dir)).await??;
+        info!(
+            "TTS cache at {}: {} entries, {} KB",
+            dir.display(),
+            entries.len(),
+            total_size / 1024
+        );
+
+        Ok(Self {
+            inner,
+            fingerprint,
+            dir,
+            max_bytes,
+            max_text_chars,
+            index: Mutex::new(CacheIndex {
+                entries,
+                total_size,
+            }),
+        })
+    }
+
+    fn key(&self, text: &str, emotion: Option<&str>, language: Option<&str>) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.fingerprint.as_str(),
+            text,
+            emotion.unwrap_or(""),
+            language.unwrap_or(""),
+        ] {
+            hasher.update(part.as_bytes());
+            // Separator so ("ab", "c") and ("a", "bc") differ
+            hasher.update([0u8]);
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.opus", key))
+    }
+
+    async fn load(&self, key: &str) -> Option<Speech> {
+        if !self.index.lock().unwrap().entries.contains_key(key) {
+            return None;
+        }
+        let path = self.path(key);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to read TTS cache entry {}: {}", key, e);
+                self.forget(key);
+                return None;
+            }
+        };
+        let Some(speech) = decode_speech(&data) else {
+            warn!("Corrupt TTS cache entry {}, removing", key);
+            self.forget(key);
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        };
+
+        let now = SystemTime::now();
+        if let Some(entry) = self.index.lock().unwrap().entries.get_mut(key) {
+            entry.last_used = now;
+        }
+        // Persist recency for the next start; tokio::fs has no set_modified
+        tokio::task::spawn_blocking(move || {
+            if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+                let _ = file.set_modified(now);
+            }
+        });
+        Some(speech)
+    }
+
+    async fn store(&self, key: &str, speech: &Speech) {
+        let data = match encode_speech(speech) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to encode TTS cache entry {}: {}", key, e);
+                return;
+            }
+        };
+        if data.len() as u64 > self.max_bytes {
+            return;
+        }
+        // Readers never see a partly written entry; a crash leaves only a temp file
+        let temp = self
+            .dir
+            .join(format!(".{}.{}.tmp", key, uuid::Uuid::new_v4()));
+        if let Err(e) = write_then_rename(&temp, &self.path(key), &data).await {
+            warn!("Failed to write TTS cache entry {}: {}", key, e);
+            let _ = tokio::fs::remove_file(&temp).await;
+            return;
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            if let Some(old) = index.entries.insert(
+                key.to_string(),
+                CacheEntry {
+                    size: data.len() as u64,
+                    last_used: SystemTime::now(),
+                },
+            ) {
+                index.total_size -= old.size;
+            }
+            index.total_size += data.len() as u64;
+
+            let mut evicted = Vec::new();
+            while index.total_size > self.max_bytes {
+                let Some(oldest) = index
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used)
+                    .map(|(k, _)| k.clone())
+                else {
+                    break;
+                };
+                if let Some(entry) = index.entries.remove(&oldest) {
+                    index.total_size -= entry.size;
+                }
+                evicted.push(oldest);
+            }
+            evicted
+        };
+
+        for key in evicted {
+            debug!("Evicting TTS cache entry {}", key);
+            let _ = tokio::fs::remove_file(self.path(&key)).await;
+        }
+    }
+
+    fn forget(&self, key: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.remove(key) {
+            index.total_size -= entry.size;
+        }
+    }
+
+    /// Synthesizes `texts` into the cache, split the same way sessions speak them.
+    pub async fn prewarm(&self, texts: &[String]) {
+        for text in texts {
+            for segment in split_language_segments(text, None) {
+                if let Err(e) = self
+                    .speak(&segment.text, None, segment.language.as_deref())
+                    .await
+                {
+                    warn!("Failed to pre-warm TTS cache for '{}': {}", segment.text, e);
+                }
+            }
+        }
+        info!("TTS cache pre-warmed with {} prompts", texts.len());
+    }
+}
+
+// Index of the entries already on disk, creating the directory if needed.
+// File modification times stand in for last use across restarts.
+fn scan_dir_entries(dir: &Path) -> anyhow::Result<(HashMap<String, CacheEntry>, u64)> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create TTS cache dir {}", dir.display()))?;
+
+    let mut entries = HashMap::new();
+    let mut total_size = 0;
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        // Raw PCM entries from older versions, and writes cut short by a crash
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "pcm" || ext == "tmp")
+        {
+            let _ = std::fs::remove_file(&path);
+            continue;
+        }
+        if path.extension().is_none_or(|ext| ext != "opus") {
+            continue;
+        }
+        let (Some(key), Ok(metadata)) =
+            (path.file_stem().and_then(|s| s.to_str()), entry.metadata())
+        else {
+            continue;
+        };
+        total_size += metadata.len();
+        entries.insert(
+            key.to_string(),
+            CacheEntry {
+                size: metadata.len(),
+                last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            },
+        );
+    }
+    Ok((entries, total_size))
+}
+
+async fn write_then_rename(temp: &Path, path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(temp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(temp, path).await
+}
+
+fn cache_format(sample_rate: u32) -> StreamFormat {
+    StreamFormat {
+        sample_rate,
+        channels: 1,
+        frame_duration: FRAME_DURATION_MS,
+    }
+}
+
+// The Opus sample rate and the sample count as little-endian u32s, then each
+// packet behind its length as a little-endian u16
+fn encode_speech(speech: &Speech) -> anyhow::Result<Vec<u8>> {
+    let sample_rate = OPUS_RATES
+        .into_iter()
+        .find(|&rate| rate >= speech.sample_rate)
+        .unwrap_or(48000);
+    let pcm = resample(&speech.pcm, speech.sample_rate, sample_rate);
+    let packets = OpusService::encode_frames_with(
+        &pcm,
+        &cache_format(sample_rate),
+        &OpusEncoderSettings::default(),
+    )?;
+
+    let mut data = Vec::with_capacity(8 + packets.iter().map(|p| p.len() + 2).sum::<usize>());
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&(pcm.len() as u32).to_le_bytes());
+    for packet in packets {
+        data.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        data.extend_from_slice(&packet);
+    }
+    Ok(data)
+}
+
+fn decode_speech(data: &[u8]) -> Option<Speech> {
+    let sample_rate = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    let samples = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+    if !OPUS_RATES.contains(&sample_rate) {
+        return None;
+    }
+    let format = cache_format(sample_rate);
+    let mut decoder = OpusService::new_decoder_for(&format).ok()?;
+    let mut frame = vec![0i16; format.frame_samples()];
+    let mut pcm = Vec::with_capacity(samples + frame.len());
+
+    let mut rest = &data[8..];
+    while !rest.is_empty() {
+        let len = u16::from_le_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let packet = rest.get(2..2 + len)?;
+        let decoded = decoder.decode(packet, &mut frame, false).ok()?;
+        pcm.extend_from_slice(&frame[..decoded]);
+        rest = &rest[2 + len..];
+    }
+    // The last packet is padded with silence
+    if pcm.len() < samples {
+        return None;
+    }
+    pcm.truncate(samples);
+    Some(Speech { pcm, sample_rate })
+}
+
+#[async_trait]
+impl TtsTrait for CachedTts {
+    async fn speak(
+        &self,
+        text: &str,
+        emotion: Option<&str>,
+        language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        if text.chars().count() > self.max_text_chars {
+            return self.inner.speak(text, emotion, language).await;
+        }
+
+        let key = self.key(text, emotion, language);
+        if let Some(speech) = self.load(&key).await {
+            debug!("TTS cache hit for '{}'", text);
+            return Ok(speech);
+        }
+
+        let speech = self.inner.speak(text, emotion, language).await?;
+        if !speech.pcm.is_empty() {
+            self.store(&key, &speech).await;
+        }
+        Ok(speech)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts syntheses; the samples encode the text length
+    struct CountingTts(AtomicUsize);
+
+    #[async_trait]
+    impl TtsTrait for CountingTts {
+        async fn speak(
+            &self,
+            text: &str,
+            _emotion: Option<&str>,
+            _language: Option<&str>,
+        ) -> anyhow::Result<Speech> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Speech {
+                pcm: vec![text.len() as i16; 100],
+                sample_rate: 16000,
+            })
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("tts-cache-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn entries_survive_a_restart_and_old_pcm_files_are_removed() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("stale.pcm"), b"old format").unwrap();
+        std::fs::write(dir.join(".partial.tmp"), b"interrupted").unwrap();
+        let inner = Arc::new(CountingTts(AtomicUsize::new(0)));
+
+        let cache = CachedTts::new(inner.clone(), "test".into(), dir.clone(), 1 << 20, 100)
+            .await
+            .unwrap();
+        assert!(!dir.join("stale.pcm").exists());
+        assert!(!dir.join(".partial.tmp").exists());
+        let first = cache.speak("你好", None, None).await.unwrap();
+        let key = cache.key("你好", None, None);
+        assert!(dir.join(format!("{}.opus", key)).exists());
+        // Opus is lossy, so compare the shape of the audio rather than the samples
+        let hit = cache.speak("你好", None, None).await.unwrap();
+        assert_eq!(
+            (hit.sample_rate, hit.pcm.len()),
+            (first.sample_rate, first.pcm.len())
+        );
+        assert_eq!(inner.0.load(Ordering::SeqCst), 1);
+
+        let restarted = CachedTts::new(inner.clone(), "test".into(), dir.clone(), 1 << 20, 100)
+            .await
+            .unwrap();
+        let hit = restarted.speak("你好", None, None).await.unwrap();
+        assert_eq!(
+            (hit.sample_rate, hit.pcm.len()),
+            (first.sample_rate, first.pcm.len())
+        );
+        assert_eq!(inner.0.load(Ordering::SeqCst), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_entry_is_evicted_from_disk() {
+        let dir = temp_dir();
+        // Texts of the same length synthesize to identical audio, so entries are the same size
+        let entry_size = encode_speech(&Speech {
+            pcm: vec![2; 100],
+            sample_rate: 16000,
+        })
+        .unwrap()
+        .len() as u64;
+        let inner = Arc::new(CountingTts(AtomicUsize::new(0)));
+        let cache = CachedTts::new(
+            inner.clone(),
+            "test".into(),
+            dir.clone(),
+            entry_size * 2 + entry_size / 2,
+            100,
+        )
+        .await
+        .unwrap();
+        let file = |text: &str| dir.join(format!("{}.opus", cache.key(text, None, None)));
+
+        cache.speak("aa", None, None).await.unwrap();
+        cache.speak("bb", None, None).await.unwrap();
+        // Touch "aa" so "bb" becomes the least recently used
+        cache.speak("aa", None, None).await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 2);
+        cache.speak("cc", None, None).await.unwrap();
+
+        assert!(file("aa").exists());
+        assert!(!file("bb").exists());
+        assert!(file("cc").exists());
+        assert_eq!(cache.index.lock().unwrap().total_size, entry_size * 2);
+
+        cache.speak("bb", None, None).await.unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,43 +1,123 @@
-use crate::services::audio::opus_codec::OpusService;
-use crate::services::audio::resampler::resample_24k_to_16k;
-use crate::traits::TtsTrait;
+use crate::services::audio::resampler::downmix_to_mono;
+use crate::services::language::voice_for_language;
+use crate::traits::{Speech, TtsTrait};
 use anyhow::Context;
 use async_trait::async_trait;
 use msedge_tts::tts::client::connect_async;
 use msedge_tts::tts::SpeechConfig;
-use tracing::info;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Connection drops and service hiccups are common; each sentence gets this many tries
+const SYNTHESIS_ATTEMPTS: u32 = 3;
 
 pub struct EdgeTts {
     voice: String,
     rate: String,
     pitch: String,
     volume: String,
+    voices: HashMap<String, String>,
+    // Emotion (e.g. "happy") -> `mstts:express-as` style (e.g. "cheerful")
+    styles: HashMap<String, String>,
+    style_degree: Option<f32>,
+    // Voices that returned no audio for a style; they are only asked for plain speech afterwards
+    unstyled_voices: Mutex<HashSet<String>>,
 }
 
 impl EdgeTts {
-    pub fn new(voice: String, rate: String, pitch: String, volume: String) -> Self {
+    pub fn new(
+        voice: String,
+        rate: String,
+        pitch: String,
+        volume: String,
+        voices: HashMap<String, String>,
+        styles: HashMap<String, String>,
+        style_degree: Option<f32>,
+    ) -> Self {
         Self {
             voice,
             rate,
             pitch,
             volume,
+            voices,
+            styles,
+            style_degree,
+            unstyled_voices: Mutex::new(HashSet::new()),
         }
     }
-}
 
-#[async_trait]
-impl TtsTrait for EdgeTts {
-    async fn speak(&self, text: &str, _emotion: Option<&str>) -> anyhow::Result<Vec<Vec<u8>>> {
-        info!(
-            "Generating Edge TTS for: '{}' using voice '{}'",
-            text, self.voice
-        );
+    // `input` is SSML markup, so plain text must be escaped first
+    async fn synthesize(&self, input: &str, config: &SpeechConfig) -> anyhow::Result<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            match self.synthesize_once(input, config).await {
+                Ok(audio) => return Ok(audio),
+                Err(e) if attempt < SYNTHESIS_ATTEMPTS => {
+                    warn!("Edge TTS attempt {} failed, retrying: {:#}", attempt, e);
+                    tokio::time::sleep(Duration::from_millis(250 * attempt as u64)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
+    async fn synthesize_once(&self, input: &str, config: &SpeechConfig) -> anyhow::Result<Vec<u8>> {
         // Connect to Edge TTS
         let mut client = connect_async()
             .await
             .context("Failed to connect to Edge TTS service")?;
 
+        let audio_metadata = client
+            .synthesize(input, config)
+            .await
+            .context("Failed to synthesize speech via Edge TTS")?;
+        Ok(audio_metadata.audio_bytes)
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// msedge-tts places its input inside `<voice><prosody>` of an SSML document that
+// declares the `mstts` namespace, so a speaking style can be passed as markup.
+// Prosody (rate, pitch, volume) still comes from the SpeechConfig.
+fn express_as(text: &str, style: &str, degree: Option<f32>) -> String {
+    let degree = degree
+        .map(|d| format!(" styledegree='{}'", d))
+        .unwrap_or_default();
+    format!(
+        "<mstts:express-as style='{}'{}>{}</mstts:express-as>",
+        escape_xml(style),
+        degree,
+        escape_xml(text)
+    )
+}
+
+#[async_trait]
+impl TtsTrait for EdgeTts {
+    async fn speak(
+        &self,
+        text: &str,
+        emotion: Option<&str>,
+        language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        let voice = voice_for_language(&self.voices, language, &self.voice);
+        let style = emotion
+            .and_then(|e| self.styles.get(e))
+            .filter(|_| !self.unstyled_voices.lock().unwrap().contains(voice));
+        info!(
+            "Generating Edge TTS for: '{}' using voice '{}' (language: {:?}, style: {:?})",
+            text, voice, language, style
+        );
+
         let pitch = self
             .pitch
             .trim_matches(|c: char| !c.is_numeric() && c != '-')
@@ -55,20 +135,47 @@ impl TtsTrait for EdgeTts {
             .unwrap_or(0);
 
         let config = SpeechConfig {
-            voice_name: self.voice.clone(),
+            voice_name: voice.to_string(),
             pitch,
             rate,
             volume,
             audio_format: "audio-24khz-48kbitrate-mono-mp3".to_string(),
         };
 
-        // Synthesize
-        let audio_metadata = client
-            .synthesize(text, &config)
-            .await
-            .context("Failed to synthesize speech via Edge TTS")?;
-
-        let audio_data = audio_metadata.audio_bytes;
+        // Synthesize, falling back to plain speech if the styled request fails.
+        // The service answers a style the voice lacks with no audio; only that
+        // rules the style out for later sentences. The styled request gets a
+        // single try, since the plain one that follows retries anyway.
+        let plain = escape_xml(text);
+        let audio_data = match style {
+            Some(style) => {
+                match self
+                    .synthesize_once(&express_as(text, style, self.style_degree), &config)
+                    .await
+                {
+                    Ok(audio) if !audio.is_empty() => audio,
+                    Ok(_) => {
+                        warn!(
+                            "Voice '{}' does not support style '{}', using plain speech",
+                            voice, style
+                        );
+                        self.unstyled_voices
+                            .lock()
+                            .unwrap()
+                            .insert(voice.to_string());
+                        self.synthesize(&plain, &config).await?
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Styled speech failed for voice '{}' ({:#}), trying plain speech",
+                            voice, e
+                        );
+                        self.synthesize(&plain, &config).await?
+                    }
+                }
+            }
+            None => self.synthesize(&plain, &config).await?,
+        };
 
         info!(
             "Received {} bytes of MP3 audio from Edge TTS",
@@ -88,59 +195,50 @@ impl TtsTrait for EdgeTts {
                                frame.sample_rate, frame.channels, frame.layer, frame.bitrate);
                     }
 
-                    if frame.channels == 1 {
-                        pcm_i16.extend_from_slice(&frame.data);
-                    } else {
-                        // Stereo to Mono: simple downsample (take left channel)
-                        for chunk in frame.data.chunks(frame.channels) {
-                            if let Some(&sample) = chunk.first() {
-                                pcm_i16.push(sample);
-                            }
-                        }
-                    }
+                    pcm_i16.extend(downmix_to_mono(&frame.data, frame.channels));
                 }
                 Err(minimp3::Error::Eof) => break,
                 Err(e) => return Err(anyhow::anyhow!("MP3 decode error: {:?}", e)),
             }
         }
 
-        // Resample based on actual sample rate
-        let resampled_pcm = if sample_rate == 16000 {
-            info!("Sample rate is 16kHz, skipping resampling.");
-            pcm_i16
-        } else if sample_rate == 24000 {
-            info!("Sample rate is 24kHz, resampling to 16kHz.");
-            resample_24k_to_16k(&pcm_i16)
-        } else {
-            info!(
-                "Sample rate is {}, attempting 24k->16k resampling (may be incorrect).",
-                sample_rate
-            );
-            resample_24k_to_16k(&pcm_i16)
-        };
+        Ok(Speech {
+            pcm: pcm_i16,
+            sample_rate: sample_rate as u32,
+        })
+    }
+}
 
-        // Encode to Opus
-        let mut encoder = OpusService::new_encoder()?;
-        let mut frames = Vec::new();
-        let frame_size = 960; // 60ms at 16kHz
-
-        for chunk in resampled_pcm.chunks(frame_size) {
-            let encoded = if chunk.len() < frame_size {
-                let mut padded = chunk.to_vec();
-                padded.resize(frame_size, 0);
-                encoder.encode_vec(&padded, frame_size * 2)?
-            } else {
-                encoder.encode_vec(chunk, frame_size * 2)?
-            };
-            frames.push(encoded);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let total_bytes: usize = frames.iter().map(|f| f.len()).sum();
-        info!(
-            "Encoded {} bytes of Opus audio into {} frames.",
-            total_bytes,
-            frames.len()
+    #[test]
+    fn text_and_style_are_escaped_for_ssml() {
+        assert_eq!(
+            escape_xml(r#"Tom & Jerry <said> "it's" ok"#),
+            "Tom &amp; Jerry &lt;said&gt; &quot;it&apos;s&quot; ok"
+        );
+        assert_eq!(
+            express_as("1 < 2 & 'yes'", "cheer'ful\"><x", None),
+            "<mstts:express-as style='cheer&apos;ful&quot;&gt;&lt;x'>\
+             1 &lt; 2 &amp; &apos;yes&apos;</mstts:express-as>"
+        );
+    }
+
+    #[test]
+    fn style_degree_is_written_only_when_set() {
+        assert_eq!(
+            express_as("好的", "cheerful", Some(1.5)),
+            "<mstts:express-as style='cheerful' styledegree='1.5'>好的</mstts:express-as>"
+        );
+        assert_eq!(
+            express_as("好的", "cheerful", Some(2.0)),
+            "<mstts:express-as style='cheerful' styledegree='2'>好的</mstts:express-as>"
+        );
+        assert_eq!(
+            express_as("好的", "sad", None),
+            "<mstts:express-as style='sad'>好的</mstts:express-as>"
         );
-        Ok(frames)
     }
 }
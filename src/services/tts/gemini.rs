@@ -1,9 +1,10 @@
-use crate::services::audio::opus_codec::OpusService;
-use crate::traits::TtsTrait;
+use crate::services::language::voice_for_language;
+use crate::traits::{Speech, TtsTrait};
 use anyhow::Context;
 use async_trait::async_trait;
 use reqwest::Client;
 use serde_json::json;
+use std::collections::HashMap;
 use tracing::{error, info};
 
 pub struct GeminiTts {
@@ -11,51 +12,52 @@ pub struct GeminiTts {
     client: Client,
     model: String,      // e.g. "gemini-2.5-flash-preview-tts"
     voice_name: String, // e.g. "Kore"
+    voices: HashMap<String, String>,
 }
 
 impl GeminiTts {
-    pub fn new(api_key: String, model: String, voice_name: String) -> Self {
+    pub fn new(
+        api_key: String,
+        model: String,
+        voice_name: String,
+        voices: HashMap<String, String>,
+    ) -> Self {
         Self {
             api_key,
             client: Client::new(),
             model,
             voice_name,
+            voices,
         }
     }
+}
 
-    // Simple resampler using linear interpolation (24kHz -> 16kHz)
-    fn resample_24k_to_16k(input: &[i16]) -> Vec<i16> {
-        let input_len = input.len();
-        // Ratio is 16/24 = 2/3.
-        let output_len = (input_len * 2) / 3;
-        let mut output = Vec::with_capacity(output_len);
-
-        for i in 0..output_len {
-            // Calculate position in input
-            // out_index * 24 / 16 = out_index * 1.5
-            let pos = i as f32 * 1.5;
-            let index = pos.floor() as usize;
-            let frac = pos - index as f32;
-
-            if index + 1 < input_len {
-                let s0 = input[index] as f32;
-                let s1 = input[index + 1] as f32;
-                let val = s0 + (s1 - s0) * frac;
-                output.push(val as i16);
-            } else if index < input_len {
-                output.push(input[index]);
-            }
-        }
-        output
+fn style_instruction(emotion: &str) -> String {
+    match emotion {
+        "happy" => "Say cheerfully".to_string(),
+        "sad" => "Say sadly".to_string(),
+        "angry" => "Say angrily".to_string(),
+        other => format!("Say in a {} tone", other),
     }
 }
 
 #[async_trait]
 impl TtsTrait for GeminiTts {
-    async fn speak(&self, text: &str, _emotion: Option<&str>) -> anyhow::Result<Vec<Vec<u8>>> {
+    async fn speak(
+        &self,
+        text: &str,
+        emotion: Option<&str>,
+        language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        let voice_name = voice_for_language(&self.voices, language, &self.voice_name);
+        // Gemini TTS takes delivery instructions in plain language
+        let prompt = match emotion.map(style_instruction) {
+            Some(instruction) => format!("{}: {}", instruction, text),
+            None => text.to_string(),
+        };
         info!(
-            "Generating Gemini TTS for: '{}' using voice '{}'",
-            text, self.voice_name
+            "Generating Gemini TTS for: '{}' using voice '{}' (language: {:?})",
+            text, voice_name, language
         );
 
         let url = format!(
@@ -66,7 +68,7 @@ impl TtsTrait for GeminiTts {
         let body = json!({
             "contents": [{
                 "parts": [{
-                    "text": text
+                    "text": prompt
                 }]
             }],
             "generationConfig": {
@@ -74,7 +76,7 @@ impl TtsTrait for GeminiTts {
                 "speechConfig": {
                     "voiceConfig": {
                         "prebuiltVoiceConfig": {
-                            "voiceName": self.voice_name
+                            "voiceName": voice_name
                         }
                     }
                 }
@@ -123,36 +125,10 @@ impl TtsTrait for GeminiTts {
             }
         }
 
-        // Resample 24k -> 16k
-        let resampled_pcm = Self::resample_24k_to_16k(&pcm_i16);
-
-        // Encode to Opus
-        // Opus Encoder expects frames. Frame size for 16kHz:
-        // 2.5ms, 5ms, 10ms, 20ms, 40ms, 60ms.
-        // 60ms at 16k = 960 samples.
-        // We will chunk the PCM data into 960-sample chunks and encode each.
-
-        let mut encoder = OpusService::new_encoder()?;
-        let mut frames = Vec::new();
-        let frame_size = 960; // 60ms
-
-        for chunk in resampled_pcm.chunks(frame_size) {
-            let encoded = if chunk.len() < frame_size {
-                let mut padded = chunk.to_vec();
-                padded.resize(frame_size, 0);
-                encoder.encode_vec(&padded, frame_size * 2)?
-            } else {
-                encoder.encode_vec(chunk, frame_size * 2)?
-            };
-            frames.push(encoded);
-        }
-
-        let total_bytes: usize = frames.iter().map(|f| f.len()).sum();
-        info!(
-            "Encoded {} bytes of Opus audio into {} frames.",
-            total_bytes,
-            frames.len()
-        );
-        Ok(frames)
+        // Gemini sends 24kHz
+        Ok(Speech {
+            pcm: pcm_i16,
+            sample_rate: 24000,
+        })
     }
 }
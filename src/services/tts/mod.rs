@@ -1,3 +1,6 @@
+pub mod cache;
 pub mod edge;
 pub mod gemini;
+pub mod openai;
 pub mod opus;
+pub mod piper;
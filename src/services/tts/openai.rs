@@ -0,0 +1,213 @@
+use crate::config::OpenAiTtsConfig;
+use crate::services::audio::decode::{decode_audio_native, AudioFormat};
+use crate::services::language::voice_for_language;
+use crate::traits::{Speech, TtsTrait};
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+use tracing::info;
+
+/// TTS through any server implementing OpenAI's `/v1/audio/speech`
+/// (OpenAI itself, Kokoro-FastAPI, openedai-speech, CosyVoice or GPT-SoVITS wrappers, ...).
+pub struct OpenAiTts {
+    client: Client,
+    base_url: String,
+    config: OpenAiTtsConfig,
+}
+
+impl OpenAiTts {
+    pub fn new(config: OpenAiTtsConfig) -> Self {
+        // Ensure base_url doesn't end with slash for cleaner appending
+        let base_url = config.base_url.trim_end_matches('/').to_string();
+        Self {
+            client: Client::new(),
+            base_url,
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl TtsTrait for OpenAiTts {
+    async fn speak(
+        &self,
+        text: &str,
+        _emotion: Option<&str>,
+        language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        let voice = voice_for_language(&self.config.voices, language, &self.config.voice);
+        info!(
+            "Generating OpenAI-compatible TTS for: '{}' using voice '{}' at {}",
+            text, voice, self.base_url
+        );
+
+        let mut body = json!({
+            "model": self.config.model,
+            "input": text,
+            "voice": voice,
+            "response_format": self.config.response_format,
+        });
+        if let Some(speed) = self.config.speed {
+            body["speed"] = json!(speed);
+        }
+
+        let mut request = self
+            .client
+            .post(format!("{}/audio/speech", self.base_url))
+            .json(&body)
+            .timeout(std::time::Duration::from_secs(30));
+        if let Some(api_key) = &self.config.api_key {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        let resp = request
+            .send()
+            .await
+            .context("Failed to send request to TTS server")?;
+
+        if !resp.status().is_success() {
+            let error_text = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("TTS server error: {}", error_text));
+        }
+        let audio_data = resp.bytes().await.context("Failed to read TTS response")?;
+        info!(
+            "Received {} bytes of audio from TTS server",
+            audio_data.len()
+        );
+
+        let format = match self.config.response_format.as_str() {
+            "wav" => Some(AudioFormat::Wav),
+            "mp3" => Some(AudioFormat::Mp3),
+            "opus" => Some(AudioFormat::OggOpus),
+            "pcm" => Some(AudioFormat::Pcm),
+            _ => None,
+        }
+        // Some servers ignore response_format, so trust the bytes first
+        .map(|requested| match AudioFormat::detect(&audio_data, None) {
+            Some(detected) if requested != AudioFormat::Pcm => detected,
+            _ => requested,
+        })
+        .or_else(|| AudioFormat::detect(&audio_data, None))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unsupported response_format '{}'",
+                self.config.response_format
+            )
+        })?;
+        let (pcm, sample_rate) =
+            decode_audio_native(&audio_data, format, self.config.pcm_sample_rate)?;
+        Ok(Speech { pcm, sample_rate })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::HeaderMap, routing::post, Json, Router};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Seen {
+        body: Arc<Mutex<Option<Value>>>,
+        authorization: Arc<Mutex<Option<String>>>,
+    }
+
+    // One second of 440Hz at 24kHz, like OpenAI's own WAV output
+    fn canned_wav() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 24000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for i in 0..24000 {
+            let t = i as f32 / 24000.0;
+            let sample = 8000.0 * (2.0 * std::f32::consts::PI * 440.0 * t).sin();
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        cursor.into_inner()
+    }
+
+    async fn speech(
+        State(seen): State<Seen>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Vec<u8> {
+        *seen.authorization.lock().unwrap() = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        *seen.body.lock().unwrap() = Some(body);
+        canned_wav()
+    }
+
+    // A stand-in `/v1/audio/speech` server; returns its base URL
+    async fn serve(seen: Seen) -> String {
+        let app = Router::new()
+            .route("/v1/audio/speech", post(speech))
+            .with_state(seen);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/v1/", addr)
+    }
+
+    fn config(base_url: String) -> OpenAiTtsConfig {
+        OpenAiTtsConfig {
+            base_url,
+            api_key: Some("sk-test".to_string()),
+            model: "tts-1".to_string(),
+            voice: "alloy".to_string(),
+            speed: Some(1.25),
+            response_format: "wav".to_string(),
+            pcm_sample_rate: 24000,
+            voices: HashMap::from([("en".to_string(), "nova".to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn speaks_through_an_openai_compatible_server() {
+        let seen = Seen::default();
+        let tts = OpenAiTts::new(config(serve(seen.clone()).await));
+
+        let speech = tts.speak("Hello there", None, Some("en")).await.unwrap();
+
+        // Passed on at the server's rate; sessions resample once when encoding
+        assert_eq!(speech.sample_rate, 24000);
+        assert_eq!(speech.pcm.len(), 24000);
+        assert!(speech.pcm.iter().any(|&s| s.abs() > 7000));
+        let body = seen.body.lock().unwrap().clone().unwrap();
+        assert_eq!(body["model"], "tts-1");
+        assert_eq!(body["input"], "Hello there");
+        assert_eq!(body["voice"], "nova");
+        assert_eq!(body["response_format"], "wav");
+        assert_eq!(body["speed"], 1.25);
+        assert_eq!(
+            seen.authorization.lock().unwrap().as_deref(),
+            Some("Bearer sk-test")
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_reported() {
+        let tts = OpenAiTts::new(config("http://127.0.0.1:1/v1".to_string()));
+        assert!(tts.speak("Hello", None, None).await.is_err());
+
+        let app = Router::new().route(
+            "/v1/audio/speech",
+            post(|| async { (axum::http::StatusCode::BAD_REQUEST, "unknown voice") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let tts = OpenAiTts::new(config(format!("http://{}/v1", addr)));
+        let error = tts.speak("Hello", None, None).await.unwrap_err();
+        assert!(error.to_string().contains("unknown voice"));
+    }
+}
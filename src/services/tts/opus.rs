@@ -1,5 +1,4 @@
-use crate::services::audio::opus_codec::OpusService;
-use crate::traits::TtsTrait;
+use crate::traits::{Speech, TtsTrait};
 use async_trait::async_trait;
 use tracing::info;
 
@@ -13,8 +12,13 @@ impl OpusTts {
 
 #[async_trait]
 impl TtsTrait for OpusTts {
-    async fn speak(&self, text: &str, _emotion: Option<&str>) -> anyhow::Result<Vec<Vec<u8>>> {
-        info!("Generating TTS for: '{}' (Mocking PCM -> Opus)", text);
+    async fn speak(
+        &self,
+        text: &str,
+        _emotion: Option<&str>,
+        _language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        info!("Generating TTS for: '{}' (Mocking PCM)", text);
 
         // 1. Generate Dummy PCM (Sine wave beep)
         // 16kHz, 1 second beep
@@ -31,28 +35,9 @@ impl TtsTrait for OpusTts {
             pcm.push(sample_i16);
         }
 
-        // 2. Encode to Opus
-        let mut encoder = OpusService::new_encoder()?;
-        let mut frames = Vec::new();
-
-        // Opus usually expects frames of 2.5, 5, 10, 20, 40, or 60 ms.
-        // At 16k, 60ms = 960 samples.
-        let frame_size = 960;
-
-        for chunk in pcm.chunks(frame_size) {
-            if chunk.len() < frame_size {
-                let mut padded = chunk.to_vec();
-                padded.resize(frame_size, 0);
-                let encoded = encoder.encode_vec(&padded, frame_size * 2)?;
-                frames.push(encoded);
-            } else {
-                let encoded = encoder.encode_vec(chunk, frame_size * 2)?;
-                frames.push(encoded);
-            }
-        }
-
-        let total_bytes: usize = frames.iter().map(|f| f.len()).sum();
-        info!("Generated {} bytes of Opus audio.", total_bytes);
-        Ok(frames)
+        Ok(Speech {
+            pcm,
+            sample_rate: sample_rate as u32,
+        })
     }
 }
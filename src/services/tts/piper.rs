@@ -0,0 +1,307 @@
+use crate::config::PiperTtsConfig;
+use crate::traits::{Speech, TtsTrait};
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use ort::session::builder::GraphOptimizationLevel;
+use ort::session::Session;
+use ort::value::Tensor;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+// Special symbols of the Piper phoneme alphabet
+const PAD: &str = "_";
+const BOS: &str = "^";
+const EOS: &str = "$";
+
+// The `<model>.onnx.json` file shipped with every Piper voice
+#[derive(Debug, Deserialize)]
+struct PiperVoiceConfig {
+    audio: PiperAudioConfig,
+    #[serde(default)]
+    espeak: Option<PiperEspeakConfig>,
+    #[serde(default)]
+    inference: PiperInferenceConfig,
+    // "espeak" (IPA from espeak-ng) or "text" (raw characters)
+    #[serde(default = "default_phoneme_type")]
+    phoneme_type: String,
+    phoneme_id_map: HashMap<String, Vec<i64>>,
+    #[serde(default = "default_num_speakers")]
+    num_speakers: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct PiperAudioConfig {
+    sample_rate: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct PiperEspeakConfig {
+    voice: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PiperInferenceConfig {
+    #[serde(default = "default_noise_scale")]
+    noise_scale: f32,
+    #[serde(default = "default_length_scale")]
+    length_scale: f32,
+    #[serde(default = "default_noise_w")]
+    noise_w: f32,
+}
+
+impl Default for PiperInferenceConfig {
+    fn default() -> Self {
+        Self {
+            noise_scale: default_noise_scale(),
+            length_scale: default_length_scale(),
+            noise_w: default_noise_w(),
+        }
+    }
+}
+
+fn default_phoneme_type() -> String {
+    "espeak".to_string()
+}
+
+fn default_num_speakers() -> u32 {
+    1
+}
+
+fn default_noise_scale() -> f32 {
+    0.667
+}
+
+fn default_length_scale() -> f32 {
+    1.0
+}
+
+fn default_noise_w() -> f32 {
+    0.8
+}
+
+/// Offline neural TTS running a Piper (VITS) ONNX voice on the CPU.
+///
+/// Text is turned into phonemes with the `espeak-ng` command line tool (not
+/// needed for "text" phoneme voices), mapped through the voice's phoneme table
+/// and synthesized with onnxruntime, the same runtime SenseVoice uses.
+pub struct PiperTts {
+    session: Arc<Mutex<Session>>,
+    voice: PiperVoiceConfig,
+    espeak_path: String,
+    speaker_id: i64,
+    scales: [f32; 3],
+}
+
+impl PiperTts {
+    pub fn new(config: &PiperTtsConfig) -> anyhow::Result<Self> {
+        let config_path = config
+            .config
+            .clone()
+            .unwrap_or_else(|| format!("{}.json", config.model));
+        let voice: PiperVoiceConfig = serde_json::from_str(
+            &std::fs::read_to_string(&config_path)
+                .with_context(|| format!("Failed to read Piper voice config {}", config_path))?,
+        )
+        .with_context(|| format!("Invalid Piper voice config {}", config_path))?;
+
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(config.threads)?
+            .commit_from_file(&config.model)
+            .with_context(|| format!("Failed to load Piper model {}", config.model))?;
+
+        let scales = [
+            config.noise_scale.unwrap_or(voice.inference.noise_scale),
+            config.length_scale.unwrap_or(voice.inference.length_scale),
+            config.noise_w.unwrap_or(voice.inference.noise_w),
+        ];
+        info!(
+            "Loaded Piper voice {} ({} Hz, {} speakers, scales {:?})",
+            config.model, voice.audio.sample_rate, voice.num_speakers, scales
+        );
+
+        Ok(Self {
+            session: Arc::new(Mutex::new(session)),
+            voice,
+            espeak_path: config.espeak_path.clone(),
+            speaker_id: config.speaker_id.unwrap_or(0),
+            scales,
+        })
+    }
+
+    async fn phonemize(&self, text: &str) -> anyhow::Result<String> {
+        if self.voice.phoneme_type == "text" {
+            return Ok(text.to_string());
+        }
+
+        let espeak_voice = self
+            .voice
+            .espeak
+            .as_ref()
+            .map(|e| e.voice.as_str())
+            .unwrap_or("en-us");
+        let output = espeak_ipa(&self.espeak_path, espeak_voice, text).await?;
+
+        // One line per clause; espeak drops punctuation, so restore the sentence ending
+        let mut phonemes = output
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        match text.trim_end().chars().last() {
+            Some('?' | '？') => phonemes.push('?'),
+            Some('!' | '！') => phonemes.push('!'),
+            _ => phonemes.push('.'),
+        }
+        Ok(phonemes)
+    }
+
+    // BOS, then every phoneme followed by padding, then EOS
+    fn phoneme_ids(&self, phonemes: &str) -> Vec<i64> {
+        let map = &self.voice.phoneme_id_map;
+        let id = |symbol: &str| map.get(symbol).cloned().unwrap_or_default();
+        let pad = id(PAD);
+
+        let mut ids = id(BOS);
+        ids.extend(&pad);
+        let mut buf = [0u8; 4];
+        for c in phonemes.chars() {
+            let Some(phoneme) = map.get(&*c.encode_utf8(&mut buf)) else {
+                continue;
+            };
+            ids.extend(phoneme);
+            ids.extend(&pad);
+        }
+        ids.extend(id(EOS));
+        ids
+    }
+}
+
+// IPA for `text` from espeak-ng, one line per clause. The text goes in on
+// stdin so a sentence starting with '-' is never parsed as an option.
+async fn espeak_ipa(espeak_path: &str, voice: &str, text: &str) -> anyhow::Result<String> {
+    let mut child = Command::new(espeak_path)
+        .args(["-q", "--ipa", "--stdin", "-v", voice])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", espeak_path))?;
+    // A sentence is far smaller than the pipe buffer, so writing it all
+    // before reading the output cannot block
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "espeak-ng failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn synthesize(
+    session: &mut Session,
+    ids: Vec<i64>,
+    scales: [f32; 3],
+    speaker_id: Option<i64>,
+) -> anyhow::Result<Vec<f32>> {
+    let len = ids.len() as i64;
+    let input = Tensor::from_array(([1usize, ids.len()], ids))?;
+    let input_lengths = Tensor::from_array(([1usize], vec![len]))?;
+    let scales = Tensor::from_array(([3usize], scales.to_vec()))?;
+
+    let outputs = match speaker_id {
+        Some(sid) => {
+            let sid = Tensor::from_array(([1usize], vec![sid]))?;
+            session.run(ort::inputs![
+                "input" => input,
+                "input_lengths" => input_lengths,
+                "scales" => scales,
+                "sid" => sid,
+            ])?
+        }
+        None => session.run(ort::inputs![
+            "input" => input,
+            "input_lengths" => input_lengths,
+            "scales" => scales,
+        ])?,
+    };
+    let (_, audio) = outputs["output"].try_extract_tensor::<f32>()?;
+    Ok(audio.to_vec())
+}
+
+#[async_trait]
+impl TtsTrait for PiperTts {
+    async fn speak(
+        &self,
+        text: &str,
+        _emotion: Option<&str>,
+        _language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        let start = Instant::now();
+        let phonemes = self.phonemize(text).await?;
+        let ids = self.phoneme_ids(&phonemes);
+        if ids.len() <= 3 {
+            warn!("No known phonemes in '{}'", text);
+            return Ok(Speech::default());
+        }
+
+        let session = self.session.clone();
+        let scales = self.scales;
+        let speaker_id = (self.voice.num_speakers > 1).then_some(self.speaker_id);
+        let audio = tokio::task::spawn_blocking(move || {
+            let mut session = session.lock().map_err(|_| anyhow!("Poisoned lock"))?;
+            synthesize(&mut session, ids, scales, speaker_id)
+        })
+        .await??;
+
+        let pcm: Vec<i16> = audio
+            .iter()
+            .map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect();
+        let sample_rate = self.voice.audio.sample_rate;
+        info!(
+            "Piper synthesized {:.1}s of audio for '{}' in {:?}",
+            pcm.len() as f32 / sample_rate as f32,
+            text,
+            start.elapsed()
+        );
+        Ok(Speech { pcm, sample_rate })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn espeak_gets_the_text_on_stdin_not_as_arguments() {
+        // Stands in for espeak-ng: prints its arguments, then its input
+        let dir = std::env::temp_dir().join(format!("piper-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("espeak-ng");
+        std::fs::write(&script, "#!/bin/sh\necho \"$@\"\ncat\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let text = "--voice=de -w /tmp/out.wav hello";
+        let output = espeak_ipa(script.to_str().unwrap(), "en-us", text)
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (args, input) = output.split_once('\n').unwrap();
+        assert_eq!(args, "-q --ipa --stdin -v en-us");
+        assert_eq!(input, text);
+    }
+}
@@ -0,0 +1,202 @@
+use aes::Aes128;
+use anyhow::{anyhow, Result};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use rand::Rng;
+
+use crate::services::binary_protocol::{BinaryPacket, PayloadType};
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+// type u8, flags u8, payload_len u16, ssrc u32, timestamp u32, sequence u32
+const NONCE_LEN: usize = 16;
+const PACKET_TYPE_AUDIO: u8 = 0x01;
+
+/// Key and nonce of one MQTT session's UDP audio channel.
+///
+/// Every datagram is a 16-byte nonce followed by one Opus packet encrypted
+/// with AES-128-CTR, the nonce doubling as the counter block. The nonce is the
+/// session's template with the payload length, timestamp and sequence number
+/// filled in (big-endian), as the firmware's `MqttProtocol` expects.
+pub struct UdpAudioCipher {
+    key: [u8; 16],
+    nonce: [u8; NONCE_LEN],
+}
+
+impl UdpAudioCipher {
+    /// A fresh random key for the session identified by `ssrc`.
+    pub fn new(ssrc: u32) -> Self {
+        let key: [u8; 16] = rand::rng().random();
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[0] = PACKET_TYPE_AUDIO;
+        nonce[4..8].copy_from_slice(&ssrc.to_be_bytes());
+        Self { key, nonce }
+    }
+
+    pub fn key_hex(&self) -> String {
+        hex::encode(self.key)
+    }
+
+    pub fn nonce_hex(&self) -> String {
+        hex::encode(self.nonce)
+    }
+
+    /// Which session a datagram belongs to, read before decrypting it.
+    pub fn ssrc_of(datagram: &[u8]) -> Option<u32> {
+        let nonce = datagram.get(..NONCE_LEN)?;
+        if nonce[0] != PACKET_TYPE_AUDIO {
+            return None;
+        }
+        Some(u32::from_be_bytes([nonce[4], nonce[5], nonce[6], nonce[7]]))
+    }
+
+    /// Decrypts a datagram from the device.
+    pub fn open(&self, datagram: &[u8]) -> Result<BinaryPacket> {
+        let nonce = datagram
+            .get(..NONCE_LEN)
+            .ok_or_else(|| anyhow!("UDP packet shorter than its nonce"))?;
+        let size = u16::from_be_bytes([nonce[2], nonce[3]]) as usize;
+        let timestamp = u32::from_be_bytes([nonce[8], nonce[9], nonce[10], nonce[11]]);
+        let sequence = u32::from_be_bytes([nonce[12], nonce[13], nonce[14], nonce[15]]);
+
+        let mut payload = datagram[NONCE_LEN..]
+            .get(..size)
+            .ok_or_else(|| {
+                anyhow!(
+                    "UDP payload size {} exceeds the {} bytes received",
+                    size,
+                    datagram.len() - NONCE_LEN
+                )
+            })?
+            .to_vec();
+        self.cipher(nonce).apply_keystream(&mut payload);
+
+        Ok(BinaryPacket {
+            payload_type: PayloadType::Opus,
+            timestamp: Some(timestamp),
+            sequence: Some(sequence),
+            payload,
+        })
+    }
+
+    /// Encrypts an audio packet for the device.
+    pub fn seal(&self, packet: &BinaryPacket, sequence: u32) -> Vec<u8> {
+        let mut nonce = self.nonce;
+        nonce[2..4].copy_from_slice(&(packet.payload.len() as u16).to_be_bytes());
+        nonce[8..12].copy_from_slice(&packet.timestamp.unwrap_or(0).to_be_bytes());
+        nonce[12..16].copy_from_slice(&sequence.to_be_bytes());
+
+        let mut datagram = Vec::with_capacity(NONCE_LEN + packet.payload.len());
+        datagram.extend_from_slice(&nonce);
+        datagram.extend_from_slice(&packet.payload);
+        self.cipher(&nonce)
+            .apply_keystream(&mut datagram[NONCE_LEN..]);
+        datagram
+    }
+
+    fn cipher(&self, nonce: &[u8]) -> Aes128Ctr {
+        Aes128Ctr::new(self.key.as_slice().into(), nonce.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opus(payload: &[u8], timestamp: u32) -> BinaryPacket {
+        BinaryPacket {
+            payload_type: PayloadType::Opus,
+            timestamp: Some(timestamp),
+            sequence: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// What the firmware does with the key and nonce from the hello reply.
+    fn device_seal(
+        key_hex: &str,
+        nonce_hex: &str,
+        payload: &[u8],
+        timestamp: u32,
+        sequence: u32,
+    ) -> Vec<u8> {
+        let key = hex::decode(key_hex).unwrap();
+        let mut nonce = hex::decode(nonce_hex).unwrap();
+        nonce[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        nonce[8..12].copy_from_slice(&timestamp.to_be_bytes());
+        nonce[12..16].copy_from_slice(&sequence.to_be_bytes());
+        let mut encrypted = payload.to_vec();
+        Aes128Ctr::new(key.as_slice().into(), nonce.as_slice().into())
+            .apply_keystream(&mut encrypted);
+        [nonce, encrypted].concat()
+    }
+
+    #[test]
+    fn sealed_packets_carry_the_nonce_fields_in_big_endian() {
+        let cipher = UdpAudioCipher::new(0x0102_0304);
+        let datagram = cipher.seal(&opus(&[9; 40], 0x0a0b_0c0d), 7);
+
+        assert_eq!(datagram.len(), NONCE_LEN + 40);
+        assert_eq!(datagram[0], PACKET_TYPE_AUDIO);
+        assert_eq!(&datagram[2..4], &[0, 40]);
+        assert_eq!(&datagram[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&datagram[8..12], &[0x0a, 0x0b, 0x0c, 0x0d]);
+        assert_eq!(&datagram[12..16], &[0, 0, 0, 7]);
+        assert_ne!(&datagram[NONCE_LEN..], &[9; 40][..]);
+        assert_eq!(UdpAudioCipher::ssrc_of(&datagram), Some(0x0102_0304));
+    }
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let cipher = UdpAudioCipher::new(42);
+        let payload: Vec<u8> = (0..=255).collect();
+        let opened = cipher
+            .open(&cipher.seal(&opus(&payload, 1234), 99))
+            .unwrap();
+
+        assert_eq!(opened.payload, payload);
+        assert_eq!(opened.timestamp, Some(1234));
+        assert_eq!(opened.sequence, Some(99));
+    }
+
+    #[test]
+    fn interoperates_with_the_firmware_side_of_the_channel() {
+        let cipher = UdpAudioCipher::new(5);
+        let (key, nonce) = (cipher.key_hex(), cipher.nonce_hex());
+        assert_eq!(nonce.len(), NONCE_LEN * 2);
+
+        let uplink = device_seal(&key, &nonce, b"uplink opus", 60, 1);
+        assert_eq!(UdpAudioCipher::ssrc_of(&uplink), Some(5));
+        assert_eq!(cipher.open(&uplink).unwrap().payload, b"uplink opus");
+
+        // The keystream depends on the sequence number, so the server's
+        // packets only decrypt with the nonce they were sealed under.
+        let downlink = cipher.seal(&opus(b"downlink opus", 60), 2);
+        assert_eq!(downlink, device_seal(&key, &nonce, b"downlink opus", 60, 2));
+        assert_ne!(
+            downlink[NONCE_LEN..],
+            device_seal(&key, &nonce, b"downlink opus", 60, 3)[NONCE_LEN..]
+        );
+    }
+
+    #[test]
+    fn sessions_get_distinct_keys() {
+        assert_ne!(
+            UdpAudioCipher::new(1).key_hex(),
+            UdpAudioCipher::new(1).key_hex()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_datagrams() {
+        let cipher = UdpAudioCipher::new(3);
+        let datagram = cipher.seal(&opus(&[1; 20], 0), 1);
+
+        assert!(cipher.open(&datagram[..NONCE_LEN - 1]).is_err());
+        assert!(cipher.open(&datagram[..NONCE_LEN + 19]).is_err());
+        assert_eq!(UdpAudioCipher::ssrc_of(&datagram[..8]), None);
+
+        let mut foreign = datagram.clone();
+        foreign[0] = 0x02;
+        assert_eq!(UdpAudioCipher::ssrc_of(&foreign), None);
+    }
+}
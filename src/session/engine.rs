@@ -0,0 +1,999 @@
+use futures_util::{
+    stream::{self, select_all, BoxStream},
+    StreamExt,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
+use tokio::time::{Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, warn};
+
+use crate::config::EndpointSettings;
+use crate::services::audio::enhance::SpeechEnhancer;
+use crate::services::audio::params::{SessionAudio, UplinkDecoder};
+use crate::services::audio::ring_buffer::PcmRingBuffer;
+use crate::services::binary_protocol::BinaryPacket;
+use crate::services::echo::EchoGuard;
+use crate::services::endpoint::{llm_completeness, rule_completeness, Completeness};
+use crate::services::language::detect_language;
+use crate::services::mcp::{
+    create_request, ClientInfo, JsonRpcResponse, McpInitializeParams, McpTool, McpToolListResult,
+};
+use crate::session::events::{Inbound, Outbound};
+use crate::session::messages::{AudioParams, AudioParamsResponse, ClientMessage, ServerMessage};
+use crate::session::reply::{
+    generate_greeting, process_text_logic, send_message_safe, send_nowait, strip_wake_word,
+    trigger_tts_only, LlmRequest, RpcCall,
+};
+use crate::state::AppState;
+use crate::traits::{SttEvent, ToolDefinition};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionState {
+    Listening,
+    Processing,
+}
+
+// How the device decides when a turn ends, sent with `listen start`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenMode {
+    // The server ends the turn on silence (VAD)
+    Auto,
+    // Push-to-talk: the turn ends on `listen stop`
+    Manual,
+    // Keep listening while the reply plays, user speech interrupts it (needs device AEC)
+    Realtime,
+}
+
+impl ListenMode {
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "auto" => Some(Self::Auto),
+            "manual" => Some(Self::Manual),
+            "realtime" => Some(Self::Realtime),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum McpState {
+    Disabled,
+    Initializing,
+    Ready,
+}
+
+// What an outstanding MCP request to the device is waiting to do
+enum McpWaiter {
+    Initialize,
+    ToolsList,
+    // A tool call from the reply task
+    Call(oneshot::Sender<Result<Value, String>>),
+}
+
+struct PendingMcp {
+    waiter: McpWaiter,
+    deadline: Instant,
+}
+
+// Devices that never answer must not stall a reply forever
+const MCP_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum ControlMessage {
+    // The reply to the turn with this id is done
+    LlmFinished(u64),
+    Sleep,
+}
+
+// Result of a background end-of-turn check for the transcript at `generation`
+struct EndpointVerdict {
+    generation: u64,
+    completeness: Completeness,
+}
+
+// Everything the session loop reacts to
+enum Event {
+    Inbound(Inbound),
+    Stt(SttEvent),
+    Control(ControlMessage),
+    Rpc(RpcCall),
+    Endpoint(EndpointVerdict),
+}
+
+/// One conversation with a device, independent of how the device is connected.
+///
+/// The session consumes [`Inbound`] events and answers with [`Outbound`] events
+/// on the sender it was created with; transports only translate between those
+/// and their wire format. STT and reply generation run in background tasks
+/// that report back into the same loop.
+pub struct Session {
+    state: AppState,
+    device_id: String,
+    transport: String,
+    tx: Sender<Outbound>,
+
+    session_state: SessionState,
+    listen_mode: ListenMode,
+    current_session_id: String,
+    // Id of the newest turn; replies to older, interrupted turns are stale
+    turn: u64,
+    accumulated_text: String,
+    accumulated_language: Option<String>,
+    // STT has heard speech it has not reported the end of yet
+    utterance_open: bool,
+    // `listen stop` came before STT finished; the turn goes out with its final transcript
+    stop_pending: bool,
+
+    // Wake word handling
+    pending_wake_word: Option<String>,
+    greeting_deadline: Option<Instant>,
+
+    // Replaced once the device's hello tells us its audio format
+    audio: SessionAudio,
+    // The reply task reads the downlink format at the start of every turn
+    audio_tx: watch::Sender<SessionAudio>,
+    uplink_decoder: Option<UplinkDecoder>,
+    // Optional high-pass / denoise / AGC on uplink audio
+    enhancer: Option<SpeechEnhancer>,
+    // Audio decoded while not listening, replayed to STT when listening resumes
+    pre_roll: PcmRingBuffer,
+    pre_roll_samples: usize,
+    handover_samples: usize,
+    // What we recently spoke, to recognize the device hearing itself
+    echo: Arc<EchoGuard>,
+
+    max_idle_duration: Duration,
+    last_activity: Instant,
+    is_standby: bool,
+
+    // End-of-turn detection
+    endpoint_settings: EndpointSettings,
+    endpoint_verdict: Completeness,
+    endpoint_deadline: Option<Instant>,
+    endpoint_generation: u64,
+
+    mcp_state: McpState,
+    mcp_request_id_counter: i64,
+    pending_mcp_requests: HashMap<i64, PendingMcp>,
+    mcp_tools: Vec<McpTool>,
+
+    stt_audio_tx: Sender<Vec<i16>>,
+    llm_tx: Sender<LlmRequest>,
+    control_tx: Sender<ControlMessage>,
+    endpoint_tx: Sender<EndpointVerdict>,
+    // The newest turn, for the reply task: replies to any other are cut short or skipped
+    turn_tx: watch::Sender<u64>,
+    // Background task output, merged with the transport's events in `run`
+    events: Vec<BoxStream<'static, Event>>,
+}
+
+impl Session {
+    /// Sets up the session and starts its STT and reply tasks. `transport` is
+    /// what the hello reply reports to the device.
+    pub fn new(state: AppState, device_id: String, transport: &str, tx: Sender<Outbound>) -> Self {
+        let audio = SessionAudio::default();
+        let uplink_decoder = match UplinkDecoder::new(&audio.uplink, &state.config.audio.opus) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Failed to create Opus decoder: {}", e);
+                None
+            }
+        };
+        let (audio_tx, audio_rx) = watch::channel(audio);
+
+        let (stt_audio_tx, stt_audio_rx) = tokio::sync::mpsc::channel::<Vec<i16>>(256);
+        let (stt_event_tx, stt_event_rx) = tokio::sync::mpsc::channel::<SttEvent>(32);
+
+        let stt = state.stt.clone();
+        tokio::spawn(async move {
+            let input_stream = ReceiverStream::new(stt_audio_rx);
+            let boxed_input = Box::pin(input_stream);
+            match stt.stream_speech(boxed_input).await {
+                Ok(mut output_stream) => {
+                    while let Some(res) = output_stream.next().await {
+                        match res {
+                            Ok(evt) => {
+                                if stt_event_tx.send(evt).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => error!("STT Stream Error: {}", e),
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to start STT stream: {}", e),
+            }
+        });
+
+        // Channel for the reply task to receive user turns
+        let (llm_tx, mut llm_rx) = tokio::sync::mpsc::channel::<LlmRequest>(16);
+        let (control_tx, control_rx) = tokio::sync::mpsc::channel::<ControlMessage>(16);
+        // Channel for the reply task to request RPC calls from the main loop
+        let (rpc_tx, rpc_rx) = tokio::sync::mpsc::channel::<RpcCall>(16);
+        // Channel for background end-of-turn checks
+        let (endpoint_tx, endpoint_rx) = tokio::sync::mpsc::channel::<EndpointVerdict>(16);
+        let (turn_tx, mut turn_rx) = watch::channel(0u64);
+
+        let echo = Arc::new(EchoGuard::new(state.config.echo.clone()));
+
+        let state_clone = state.clone();
+        let tx_clone = tx.clone();
+        let dev_id = device_id.clone();
+        let control_tx_llm = control_tx.clone();
+        let echo_clone = echo.clone();
+
+        tokio::spawn(async move {
+            while let Some(request) = llm_rx.recv().await {
+                let turn = request.turn;
+                // Superseded while queued behind an earlier reply
+                if *turn_rx.borrow_and_update() != turn {
+                    debug!("Skipping reply to superseded turn {}.", turn);
+                    continue;
+                }
+                let audio = *audio_rx.borrow();
+
+                let should_sleep = tokio::select! {
+                    should_sleep = process_text_logic(
+                        &state_clone,
+                        &tx_clone,
+                        &echo_clone,
+                        &audio,
+                        request,
+                        &dev_id,
+                        &rpc_tx,
+                    ) => should_sleep,
+                    // The guard `wait_for` returns must not live across the awaits below
+                    _ = async { turn_rx.wait_for(|latest| *latest != turn).await.is_ok() } => {
+                        info!("Reply interrupted.");
+                        let tts_stop = ServerMessage::Tts {
+                            state: "stop".to_string(),
+                            text: None,
+                        };
+                        let _ = send_message_safe(&tx_clone, Outbound::Message(tts_stop)).await;
+                        false
+                    }
+                };
+                if should_sleep {
+                    let _ = control_tx_llm.send(ControlMessage::Sleep).await;
+                } else {
+                    let _ = control_tx_llm.send(ControlMessage::LlmFinished(turn)).await;
+                }
+            }
+        });
+
+        let events: Vec<BoxStream<'static, Event>> = vec![
+            Box::pin(ReceiverStream::new(stt_event_rx).map(Event::Stt)),
+            Box::pin(ReceiverStream::new(control_rx).map(Event::Control)),
+            Box::pin(ReceiverStream::new(rpc_rx).map(Event::Rpc)),
+            Box::pin(ReceiverStream::new(endpoint_rx).map(Event::Endpoint)),
+        ];
+
+        let pre_roll_samples = state.config.vad.pre_roll_ms as usize * 16;
+        let handover_samples = state.config.vad.handover_ms as usize * 16;
+
+        Self {
+            device_id,
+            transport: transport.to_string(),
+            tx,
+            session_state: SessionState::Listening,
+            listen_mode: ListenMode::Auto,
+            current_session_id: String::new(),
+            turn: 0,
+            accumulated_text: String::new(),
+            accumulated_language: None,
+            utterance_open: false,
+            stop_pending: false,
+            pending_wake_word: None,
+            greeting_deadline: None,
+            audio,
+            audio_tx,
+            uplink_decoder,
+            enhancer: SpeechEnhancer::new(&state.config.audio.enhance),
+            pre_roll: PcmRingBuffer::new(pre_roll_samples.max(handover_samples)),
+            pre_roll_samples,
+            handover_samples,
+            echo,
+            max_idle_duration: Duration::from_millis(state.config.chat.max_idle_duration),
+            last_activity: Instant::now(),
+            is_standby: false,
+            endpoint_settings: state.config.endpoint.clone(),
+            endpoint_verdict: Completeness::Unknown,
+            endpoint_deadline: None,
+            endpoint_generation: 0,
+            mcp_state: McpState::Disabled,
+            mcp_request_id_counter: 1,
+            pending_mcp_requests: HashMap::new(),
+            mcp_tools: Vec::new(),
+            stt_audio_tx,
+            llm_tx,
+            control_tx,
+            endpoint_tx,
+            turn_tx,
+            events,
+            state,
+        }
+    }
+
+    /// Runs until the device leaves, `inbound` ends or the session goes to sleep.
+    pub async fn run(mut self, inbound: BoxStream<'static, Inbound>) {
+        let mut streams = std::mem::take(&mut self.events);
+        // A transport that just stops is treated as the device leaving
+        streams.push(Box::pin(
+            inbound
+                .chain(stream::once(async { Inbound::Close }))
+                .map(Event::Inbound),
+        ));
+        let mut events = select_all(streams);
+
+        loop {
+            let now = Instant::now();
+            let mcp_deadline = self.pending_mcp_requests.values().map(|p| p.deadline).min();
+            let timeout_at = self.last_activity + self.max_idle_duration;
+            let sleep_duration = if self.session_state == SessionState::Processing {
+                Duration::from_secs(3600 * 24)
+            } else if timeout_at > now {
+                timeout_at - now
+            } else {
+                Duration::from_millis(100)
+            };
+
+            let flow = tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => self.handle_event(event).await,
+                    None => ControlFlow::Break(()),
+                },
+                _ = tokio::time::sleep_until(self.endpoint_deadline.unwrap_or(now)), if self.endpoint_deadline.is_some() => {
+                    self.on_endpoint_deadline().await;
+                    ControlFlow::Continue(())
+                }
+                _ = tokio::time::sleep_until(self.greeting_deadline.unwrap_or(now)), if self.greeting_deadline.is_some() => {
+                    self.on_greeting_deadline();
+                    ControlFlow::Continue(())
+                }
+                _ = tokio::time::sleep_until(mcp_deadline.unwrap_or(now)), if mcp_deadline.is_some() => {
+                    self.on_mcp_timeout();
+                    ControlFlow::Continue(())
+                }
+                _ = tokio::time::sleep(sleep_duration) => {
+                    self.on_idle();
+                    ControlFlow::Continue(())
+                }
+            };
+            if flow.is_break() {
+                break;
+            }
+        }
+
+        info!("Session for {} ended.", self.device_id);
+    }
+
+    async fn handle_event(&mut self, event: Event) -> ControlFlow<()> {
+        match event {
+            Event::Inbound(Inbound::Message(message)) => self.handle_message(message).await,
+            Event::Inbound(Inbound::Audio(packet)) => self.handle_audio(packet).await,
+            Event::Inbound(Inbound::Close) => return ControlFlow::Break(()),
+            Event::Stt(evt) => self.handle_stt(evt).await,
+            Event::Control(ControlMessage::LlmFinished(turn)) if turn != self.turn => {
+                debug!("Reply to superseded turn {} finished.", turn);
+            }
+            Event::Control(ControlMessage::LlmFinished(_)) => {
+                info!("LLM Finished. Switching to Listening.");
+                self.session_state = SessionState::Listening;
+                self.touch();
+                self.flush_pre_roll(self.handover_samples).await;
+            }
+            Event::Control(ControlMessage::Sleep) => {
+                info!("Sleep requested. Closing.");
+                send_nowait(&self.tx, Outbound::Close);
+                return ControlFlow::Break(());
+            }
+            Event::Rpc(call) => self.handle_rpc(call),
+            Event::Endpoint(verdict) => {
+                if verdict.generation == self.endpoint_generation
+                    && self.session_state == SessionState::Listening
+                {
+                    debug!("LLM end-of-turn verdict: {:?}", verdict.completeness);
+                    self.endpoint_verdict = verdict.completeness;
+                    self.endpoint_deadline =
+                        endpoint_deadline_for(self.endpoint_verdict, &self.endpoint_settings);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    async fn handle_message(&mut self, message: ClientMessage) {
+        self.touch();
+        match message {
+            ClientMessage::Hello {
+                audio_params,
+                features,
+                ..
+            } => self.handle_hello(&audio_params, features),
+            ClientMessage::Listen {
+                session_id,
+                state: listen_state,
+                mode,
+                text,
+                text_only,
+            } => {
+                self.current_session_id = session_id;
+                if let Some(mode) = mode.as_deref() {
+                    match ListenMode::parse(mode) {
+                        Some(parsed) => {
+                            debug!("Listen mode: {:?}", parsed);
+                            self.listen_mode = parsed;
+                        }
+                        None => warn!("Unknown listen mode: {}", mode),
+                    }
+                }
+                match listen_state.as_str() {
+                    "start" => {
+                        info!("Client started listening.");
+                        self.start_listening();
+                        self.flush_pre_roll(self.pre_roll_samples).await;
+                    }
+                    "detect" => {
+                        let wake_word = text.unwrap_or_default();
+                        info!("Wake word detected: {}", wake_word);
+                        self.start_listening();
+                        // Give the user a moment to go on talking before greeting
+                        if self.state.config.chat.wake_reply != "none" {
+                            self.greeting_deadline = Some(
+                                Instant::now()
+                                    + Duration::from_millis(self.state.config.chat.wake_grace_ms),
+                            );
+                        }
+                        self.pending_wake_word = Some(wake_word);
+                    }
+                    "text" => {
+                        // Typed input skips STT and goes straight to the LLM
+                        let input = text.unwrap_or_default();
+                        if !input.trim().is_empty() {
+                            info!("Received text input: {}", input);
+                            self.interrupt_reply();
+                            self.session_state = SessionState::Processing;
+                            self.accumulated_text.clear();
+                            self.accumulated_language = None;
+                            self.endpoint_deadline = None;
+                            self.greeting_deadline = None;
+
+                            self.send(ServerMessage::Stt {
+                                text: input.clone(),
+                            });
+                            let turn = self.next_turn();
+                            let _ = self
+                                .llm_tx
+                                .send(LlmRequest {
+                                    turn,
+                                    language: detect_language(&input).map(|l| l.to_string()),
+                                    text: input,
+                                    tools: self.llm_tools(),
+                                    speak: !text_only,
+                                })
+                                .await;
+                        }
+                    }
+                    "stop" => {
+                        info!("Client stopped listening.");
+                        self.endpoint_deadline = None;
+                        // STT flushes after the audio ends, so the final
+                        // transcript usually arrives after this
+                        if self.utterance_open || self.accumulated_text.trim().is_empty() {
+                            debug!("Waiting for the final transcript.");
+                            self.stop_pending = true;
+                            // The device stops sending audio; trailing silence
+                            // lets the VAD close the utterance
+                            let silence_ms =
+                                self.state.config.vad.silence_duration_ms as usize + 100;
+                            let _ = self.stt_audio_tx.send(vec![0; silence_ms * 16]).await;
+                        } else {
+                            self.session_state = SessionState::Processing;
+                            self.submit_turn().await;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            ClientMessage::Abort { reason, .. } => {
+                info!("Client aborted: {}", reason);
+                self.interrupt_reply();
+                self.start_listening();
+                self.flush_pre_roll(self.pre_roll_samples).await;
+            }
+            ClientMessage::Iot { .. } => {
+                info!("Received IoT message");
+            }
+            ClientMessage::Mcp { payload, .. } => self.handle_mcp(payload),
+        }
+    }
+
+    fn handle_hello(&mut self, audio_params: &AudioParams, features: Option<Value>) {
+        self.audio = SessionAudio::negotiate(
+            &audio_params.format,
+            audio_params.sample_rate,
+            audio_params.channels,
+            audio_params.frame_duration,
+            &self.state.config.audio,
+        );
+        info!(
+            "Negotiated audio: uplink {:?}, downlink {:?}",
+            self.audio.uplink, self.audio.downlink
+        );
+        self.uplink_decoder =
+            match UplinkDecoder::new(&self.audio.uplink, &self.state.config.audio.opus) {
+                Ok(d) => Some(d),
+                Err(e) => {
+                    error!("Failed to create Opus decoder: {}", e);
+                    None
+                }
+            };
+        let _ = self.audio_tx.send(self.audio);
+
+        self.send(ServerMessage::Hello {
+            transport: self.transport.clone(),
+            audio_params: Some(AudioParamsResponse {
+                format: "opus".to_string(),
+                sample_rate: self.audio.downlink.sample_rate,
+                channels: self.audio.downlink.channels,
+                frame_duration: self.audio.downlink.frame_duration,
+            }),
+            session_id: None,
+            udp: None,
+        });
+
+        // The handshake goes on as responses arrive, see `handle_mcp`
+        let supports_mcp = features
+            .and_then(|f| f.get("mcp").and_then(|v| v.as_bool()))
+            .unwrap_or(false);
+        if supports_mcp {
+            info!("Client supports MCP. Initializing handshake...");
+            self.mcp_state = McpState::Initializing;
+            let params = McpInitializeParams {
+                capabilities: json!({}),
+                protocol_version: "2024-11-05".to_string(),
+                client_info: ClientInfo {
+                    name: "XiaoZhi Server".to_string(),
+                    version: "1.0.0".to_string(),
+                },
+            };
+            self.send_mcp_request(
+                "initialize",
+                serde_json::to_value(params).unwrap(),
+                McpWaiter::Initialize,
+            );
+        }
+    }
+
+    // MCP responses: tool call results, or the next step of the handshake
+    fn handle_mcp(&mut self, payload: Value) {
+        debug!("Received MCP payload: {:?}", payload);
+        let Ok(response) = serde_json::from_value::<JsonRpcResponse>(payload) else {
+            return;
+        };
+        let Some(id) = response.id.as_ref().and_then(|id| id.as_i64()) else {
+            return;
+        };
+
+        let Some(pending) = self.pending_mcp_requests.remove(&id) else {
+            debug!("MCP response to unknown or expired request {}", id);
+            return;
+        };
+
+        match pending.waiter {
+            McpWaiter::Call(resp_tx) => {
+                if let Some(error) = response.error {
+                    let _ = resp_tx.send(Err(format!("{}: {}", error.code, error.message)));
+                } else {
+                    let _ = resp_tx.send(Ok(response.result.unwrap_or(Value::Null)));
+                }
+            }
+            McpWaiter::Initialize => {
+                if let Some(error) = response.error {
+                    warn!("MCP initialize failed: {}", error.message);
+                    self.mcp_state = McpState::Disabled;
+                    return;
+                }
+                info!("Received MCP Initialize response. Fetching tools list...");
+                self.send_mcp_request("tools/list", json!({ "cursor": "" }), McpWaiter::ToolsList);
+            }
+            McpWaiter::ToolsList => {
+                if let Ok(tool_list_res) = serde_json::from_value::<McpToolListResult>(
+                    response.result.unwrap_or(json!({})),
+                ) {
+                    if !tool_list_res.tools.is_empty() {
+                        info!("Discovered {} MCP tools.", tool_list_res.tools.len());
+                        debug!("[MCP TOOLS] {:?}", tool_list_res.tools);
+                        self.mcp_tools = tool_list_res.tools;
+                    }
+                }
+                self.mcp_state = McpState::Ready;
+            }
+        }
+    }
+
+    // Fails requests the device did not answer in time
+    fn on_mcp_timeout(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<i64> = self
+            .pending_mcp_requests
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let Some(pending) = self.pending_mcp_requests.remove(&id) else {
+                continue;
+            };
+            match pending.waiter {
+                McpWaiter::Call(resp_tx) => {
+                    warn!("MCP request {} timed out", id);
+                    let _ = resp_tx.send(Err("Device did not answer in time".to_string()));
+                }
+                McpWaiter::Initialize | McpWaiter::ToolsList => {
+                    warn!("MCP handshake timed out. Continuing without tools.");
+                    self.mcp_state = McpState::Disabled;
+                }
+            }
+        }
+    }
+
+    fn handle_rpc(&mut self, call: RpcCall) {
+        info!("Executing MCP RPC: {}", call.method);
+        // Fails the RPC right away if the outbound buffer is full
+        self.send_mcp_request(&call.method, call.params, McpWaiter::Call(call.resp_tx));
+    }
+
+    async fn handle_audio(&mut self, packet: BinaryPacket) {
+        let Some(decoder) = self.uplink_decoder.as_mut() else {
+            return;
+        };
+        let pcm = match decoder.decode(&packet.payload, packet.sequence) {
+            Ok(pcm) => pcm,
+            Err(e) => {
+                error!("Opus decode error: {}", e);
+                return;
+            }
+        };
+        let mut pcm_chunk = match self.enhancer.as_mut() {
+            Some(enhancer) => enhancer.process(&pcm),
+            None => pcm,
+        };
+        // Our own playback coming back: pass silence so VAD timing is kept
+        // The timestamp names the playback the device heard; 0 means none
+        let is_echo = self
+            .echo
+            .is_echo_audio(&pcm_chunk, packet.timestamp.filter(|&t| t != 0))
+            .unwrap_or_else(|e| {
+                error!("Echo check failed: {}", e);
+                false
+            });
+        if is_echo {
+            pcm_chunk.fill(0);
+        }
+        // In realtime mode we keep listening while the reply plays
+        if self.session_state == SessionState::Listening || self.listen_mode == ListenMode::Realtime
+        {
+            // Do NOT reset last_activity on audio packets!
+            self.is_standby = false;
+            let _ = self.stt_audio_tx.send(pcm_chunk).await;
+        } else {
+            // Keep it so speech starting before we listen again is not lost
+            self.pre_roll.push(&pcm_chunk);
+        }
+    }
+
+    async fn handle_stt(&mut self, evt: SttEvent) {
+        match evt {
+            SttEvent::Text { text, language } => {
+                let is_echo = self.echo.is_echo_text(&text).unwrap_or_else(|e| {
+                    error!("Echo check failed: {}", e);
+                    false
+                });
+                if is_echo {
+                    info!("Dropping transcript of our own speech: {}", text);
+                    return;
+                }
+                self.touch();
+                // The wake word is often transcribed too; it is not part of the query
+                let text = match self.pending_wake_word.take() {
+                    Some(wake_word) if self.accumulated_text.trim().is_empty() => {
+                        strip_wake_word(&text, &wake_word)
+                    }
+                    _ => text,
+                };
+                if text.trim().is_empty() {
+                    return;
+                }
+                // The user went on talking after the wake word: one turn, no greeting
+                self.greeting_deadline = None;
+                self.barge_in();
+                if language.is_some() {
+                    self.accumulated_language = language;
+                }
+                self.accumulated_text.push_str(&text);
+                self.accumulated_text.push(' ');
+                self.send(ServerMessage::Stt {
+                    text: self.accumulated_text.clone(),
+                });
+                if self.stop_pending {
+                    info!("Final transcript after listen stop. Triggering LLM.");
+                    self.session_state = SessionState::Processing;
+                    self.submit_turn().await;
+                    return;
+                }
+                self.utterance_open = true;
+                self.check_end_of_turn();
+            }
+            SttEvent::SpeechStart => {
+                debug!("VAD detected speech start.");
+                self.utterance_open = true;
+                self.touch();
+                self.greeting_deadline = None;
+                self.barge_in();
+                // The user kept talking, so the earlier sentence was not the end
+                if self.endpoint_verdict == Completeness::Complete {
+                    self.endpoint_deadline = None;
+                }
+            }
+            SttEvent::NoSpeech => {
+                self.utterance_open = false;
+                if self.stop_pending {
+                    self.stop_pending = false;
+                    if self.accumulated_text.trim().is_empty() {
+                        debug!("Nothing was said before listen stop.");
+                    } else {
+                        info!("STT NoSpeech after listen stop. Triggering LLM.");
+                        self.session_state = SessionState::Processing;
+                        self.submit_turn().await;
+                    }
+                    return;
+                }
+                let waiting_for_more = self.endpoint_verdict == Completeness::Incomplete
+                    && self.endpoint_deadline.is_some_and(|d| d > Instant::now());
+                if self.listen_mode == ListenMode::Manual {
+                    // Push-to-talk: only `listen stop` ends the turn
+                    debug!("STT NoSpeech ignored in manual listen mode.");
+                } else if waiting_for_more {
+                    debug!("STT NoSpeech, but the sentence looks unfinished. Waiting.");
+                } else if !self.accumulated_text.trim().is_empty() {
+                    info!("STT NoSpeech. Triggering LLM.");
+                    self.session_state = SessionState::Processing;
+                    self.endpoint_deadline = None;
+                    self.submit_turn().await;
+                }
+            }
+        }
+    }
+
+    // Judges whether the transcript so far ends the turn, asking the LLM if configured
+    fn check_end_of_turn(&mut self) {
+        if !self.endpoint_settings.enable || self.listen_mode == ListenMode::Manual {
+            return;
+        }
+        self.endpoint_generation += 1;
+        self.endpoint_verdict = rule_completeness(&self.accumulated_text);
+        if self.endpoint_verdict == Completeness::Unknown && self.endpoint_settings.mode == "llm" {
+            // Ask the LLM in the background; until then, wait as if unfinished
+            self.endpoint_verdict = Completeness::Incomplete;
+            let llm = self.state.llm.clone();
+            let query = self.accumulated_text.clone();
+            let generation = self.endpoint_generation;
+            let endpoint_tx = self.endpoint_tx.clone();
+            tokio::spawn(async move {
+                let completeness = llm_completeness(llm.as_ref(), &query).await;
+                let _ = endpoint_tx
+                    .send(EndpointVerdict {
+                        generation,
+                        completeness,
+                    })
+                    .await;
+            });
+        }
+        self.endpoint_deadline =
+            endpoint_deadline_for(self.endpoint_verdict, &self.endpoint_settings);
+        debug!("End-of-turn verdict: {:?}", self.endpoint_verdict);
+    }
+
+    async fn on_endpoint_deadline(&mut self) {
+        self.endpoint_deadline = None;
+        if self.session_state == SessionState::Listening && !self.accumulated_text.trim().is_empty()
+        {
+            info!(
+                "End of turn detected ({:?}). Triggering LLM.",
+                self.endpoint_verdict
+            );
+            self.session_state = SessionState::Processing;
+            self.submit_turn().await;
+        }
+    }
+
+    fn on_greeting_deadline(&mut self) {
+        self.greeting_deadline = None;
+        if self.session_state != SessionState::Listening || !self.accumulated_text.trim().is_empty()
+        {
+            return;
+        }
+        self.session_state = SessionState::Processing;
+        let turn = self.next_turn();
+        let wake_word = self.pending_wake_word.take().unwrap_or_default();
+        let state = self.state.clone();
+        let tx = self.tx.clone();
+        let device_id = self.device_id.clone();
+        let control_tx = self.control_tx.clone();
+        let echo = self.echo.clone();
+        let audio = self.audio;
+
+        tokio::spawn(async move {
+            let greeting = if state.config.chat.wake_reply == "llm" {
+                generate_greeting(&state, &device_id, &wake_word).await
+            } else {
+                None
+            };
+            let greeting = greeting.unwrap_or_else(|| state.config.chat.wake_greeting.clone());
+            trigger_tts_only(&state, &tx, &echo, &audio, &greeting).await;
+            let _ = control_tx.send(ControlMessage::LlmFinished(turn)).await;
+        });
+    }
+
+    fn on_idle(&mut self) {
+        if self.is_standby || self.session_state == SessionState::Processing {
+            return;
+        }
+        info!("Idle timeout detected. Sending standby prompt.");
+        self.is_standby = true;
+
+        let state = self.state.clone();
+        let tx = self.tx.clone();
+        let prompt = self.state.config.chat.standby_prompt.clone();
+        let control_tx = self.control_tx.clone();
+        let echo = self.echo.clone();
+        let audio = self.audio;
+
+        tokio::spawn(async move {
+            trigger_tts_only(&state, &tx, &echo, &audio, &prompt).await;
+            // Say we are going to rest, then disconnect
+            let _ = control_tx.send(ControlMessage::Sleep).await;
+        });
+    }
+
+    // Resets the idle timer
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.is_standby = false;
+    }
+
+    fn start_listening(&mut self) {
+        self.session_state = SessionState::Listening;
+        self.accumulated_text.clear();
+        self.accumulated_language = None;
+        self.endpoint_deadline = None;
+        self.stop_pending = false;
+    }
+
+    // Starts a new turn; whatever the previous one still reports is ignored
+    fn next_turn(&mut self) -> u64 {
+        self.turn += 1;
+        self.turn_tx.send_replace(self.turn);
+        self.turn
+    }
+
+    fn interrupt_reply(&mut self) {
+        if self.session_state == SessionState::Processing {
+            self.next_turn();
+        }
+    }
+
+    // User speech while the reply plays interrupts it in realtime mode
+    fn barge_in(&mut self) {
+        if self.listen_mode == ListenMode::Realtime
+            && self.session_state == SessionState::Processing
+        {
+            info!("Barge-in detected. Interrupting reply.");
+            self.next_turn();
+            self.session_state = SessionState::Listening;
+        }
+    }
+
+    // Hands the accumulated user text to the reply task
+    async fn submit_turn(&mut self) {
+        self.stop_pending = false;
+        let turn = self.next_turn();
+        let _ = self
+            .llm_tx
+            .send(LlmRequest {
+                turn,
+                text: std::mem::take(&mut self.accumulated_text),
+                language: self.accumulated_language.take(),
+                tools: self.llm_tools(),
+                speak: true,
+            })
+            .await;
+    }
+
+    // Forwards audio buffered while not listening to STT, then empties the buffer
+    async fn flush_pre_roll(&mut self, max_samples: usize) {
+        let buffered = self.pre_roll.take_last(max_samples);
+        if !buffered.is_empty() {
+            debug!("Forwarding {} buffered samples to STT", buffered.len());
+            let _ = self.stt_audio_tx.send(buffered).await;
+        }
+    }
+
+    fn send(&self, message: ServerMessage) -> bool {
+        send_nowait(&self.tx, Outbound::Message(message))
+    }
+
+    // Sends a request and remembers what its response is for. If it cannot be
+    // queued, the waiter fails at once.
+    fn send_mcp_request(&mut self, method: &str, params: Value, waiter: McpWaiter) {
+        let id = self.mcp_request_id_counter;
+        self.mcp_request_id_counter += 1;
+        let req = create_request(method, Some(params), Some(json!(id)));
+        let sent = self.send(ServerMessage::Mcp {
+            payload: serde_json::to_value(req).unwrap(),
+            session_id: Some(self.current_session_id.clone()),
+        });
+        if sent {
+            self.pending_mcp_requests.insert(
+                id,
+                PendingMcp {
+                    waiter,
+                    deadline: Instant::now() + MCP_REQUEST_TIMEOUT,
+                },
+            );
+            return;
+        }
+        match waiter {
+            McpWaiter::Call(resp_tx) => {
+                let _ = resp_tx.send(Err("Outbound buffer full - dropped tool call".to_string()));
+            }
+            McpWaiter::Initialize | McpWaiter::ToolsList => self.mcp_state = McpState::Disabled,
+        }
+    }
+
+    // Tools for the LLM, once the handshake has finished
+    fn llm_tools(&self) -> Option<Vec<ToolDefinition>> {
+        if self.mcp_state != McpState::Ready {
+            return None;
+        }
+        tool_definitions(&self.mcp_tools)
+    }
+}
+
+// When to end the turn for a given verdict; `None` leaves it to the STT silence timeout
+fn endpoint_deadline_for(verdict: Completeness, settings: &EndpointSettings) -> Option<Instant> {
+    match verdict {
+        Completeness::Complete => {
+            Some(Instant::now() + Duration::from_millis(settings.complete_delay_ms))
+        }
+        Completeness::Incomplete => {
+            Some(Instant::now() + Duration::from_millis(settings.incomplete_timeout_ms))
+        }
+        Completeness::Unknown => None,
+    }
+}
+
+// Converts MCP tools to ToolDefinition for LLM
+fn tool_definitions(mcp_tools: &[McpTool]) -> Option<Vec<ToolDefinition>> {
+    if !mcp_tools.is_empty() {
+        debug!("Passing {} tools to LLM logic.", mcp_tools.len());
+        Some(
+            mcp_tools
+                .iter()
+                .map(|t| ToolDefinition {
+                    name: t.name.clone(),
+                    description: t.description.clone(),
+                    parameters: t.input_schema.clone(),
+                })
+                .collect(),
+        )
+    } else {
+        debug!("No tools to pass to LLM logic.");
+        None
+    }
+}
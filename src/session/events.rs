@@ -0,0 +1,20 @@
+use crate::services::binary_protocol::BinaryPacket;
+use crate::session::messages::{ClientMessage, ServerMessage};
+
+/// What a transport hands the session.
+#[derive(Debug)]
+pub enum Inbound {
+    Message(ClientMessage),
+    Audio(BinaryPacket),
+    // The device left; ending the inbound stream means the same
+    Close,
+}
+
+/// What the session hands its transport. Framing audio for the wire is up
+/// to the transport.
+#[derive(Debug)]
+pub enum Outbound {
+    Message(ServerMessage),
+    Audio(BinaryPacket),
+    Close,
+}
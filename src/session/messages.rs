@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AudioParams {
+    pub format: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub frame_duration: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        transport: String,
+        audio_params: AudioParams,
+        #[serde(default)]
+        features: Option<Value>,
+    },
+    Listen {
+        session_id: String,
+        state: String,
+        #[serde(default)]
+        mode: Option<String>,
+        #[serde(default)]
+        text: Option<String>,
+        // For `state: "text"`: reply with text only, no TTS audio
+        #[serde(default)]
+        text_only: bool,
+    },
+    Abort {
+        session_id: String,
+        reason: String,
+    },
+    Iot {
+        session_id: String,
+        #[serde(default)]
+        descriptors: Option<Value>,
+        #[serde(default)]
+        states: Option<Value>,
+    },
+    Mcp {
+        payload: Value,
+        #[serde(default)]
+        session_id: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AudioParamsResponse {
+    pub format: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub frame_duration: u32,
+}
+
+/// Where and how an MQTT device sends its audio.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UdpParams {
+    pub server: String,
+    pub port: u16,
+    // AES-128 key and nonce template, hex encoded
+    pub key: String,
+    pub nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        transport: String,
+        #[serde(default)]
+        audio_params: Option<AudioParamsResponse>,
+        // Filled in by transports that hand out their own session
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp: Option<UdpParams>,
+    },
+    Stt {
+        text: String,
+    },
+    Tts {
+        state: String, // start, stop, sentence_start
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+    Llm {
+        #[serde(default)]
+        emotion: Option<String>,
+        #[serde(default)]
+        text: Option<String>,
+    },
+    Iot {
+        commands: Vec<Value>,
+    },
+    Mcp {
+        payload: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+}
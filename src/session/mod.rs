@@ -0,0 +1,11 @@
+// The conversation with a device, shared by all transports
+mod engine;
+pub mod events;
+pub mod messages;
+mod reply;
+
+pub use engine::Session;
+pub(crate) use reply::generate_reply;
+
+#[cfg(test)]
+pub(crate) mod tests;
@@ -0,0 +1,547 @@
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::services::audio::opus_codec::StreamFormat;
+use crate::services::audio::params::{encode_downlink, SessionAudio};
+use crate::services::audio::resampler::resample;
+use crate::services::binary_protocol::BinaryPacket;
+use crate::services::echo::EchoGuard;
+use crate::services::language::{split_language_segments, strip_language_tags};
+use crate::session::events::Outbound;
+use crate::session::messages::ServerMessage;
+use crate::state::AppState;
+use crate::traits::{ChatResponse, ToolDefinition};
+
+// A user turn handed to the reply task
+pub(super) struct LlmRequest {
+    // Tags the reply's completion so the session can tell stale ones apart
+    pub turn: u64,
+    pub text: String,
+    pub language: Option<String>,
+    pub tools: Option<Vec<ToolDefinition>>,
+    // false for text-only replies (no TTS audio)
+    pub speak: bool,
+}
+
+// A request for the device, sent by the reply task and answered by the session loop
+pub(crate) struct RpcCall {
+    pub method: String,
+    pub params: Value,
+    pub resp_tx: oneshot::Sender<Result<Value, String>>,
+}
+
+fn clean_text_and_extract_emotion(text: &str) -> (String, Option<String>) {
+    static EMOJI_REGEX: OnceLock<Regex> = OnceLock::new();
+    let re =
+        EMOJI_REGEX.get_or_init(|| Regex::new(r"\p{Emoji_Presentation}").expect("Invalid Regex"));
+    let cleaned = re.replace_all(text, "").to_string();
+
+    let emotion =
+        if text.contains('😂') || text.contains('😊') || text.contains('哈') || text.contains('嘻')
+        {
+            Some("happy".to_string())
+        } else if text.contains('😭') || text.contains('😢') || text.contains('难') {
+            Some("sad".to_string())
+        } else if text.contains('😡') || text.contains('怒') {
+            Some("angry".to_string())
+        } else {
+            None
+        };
+
+    (cleaned, emotion)
+}
+
+// Helper to send message safely with timeout
+pub(super) async fn send_message_safe(tx: &Sender<Outbound>, msg: Outbound) -> bool {
+    match tokio::time::timeout(Duration::from_secs(5), tx.send(msg)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            error!("Failed to send message: {}", e);
+            false
+        }
+        Err(_) => {
+            error!("Failed to send message: Timeout (Writer blocked)");
+            false
+        }
+    }
+}
+
+// Helper to send message without blocking (for use in select! loop)
+pub(super) fn send_nowait(tx: &Sender<Outbound>, msg: Outbound) -> bool {
+    match tx.try_send(msg) {
+        Ok(_) => true,
+        Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+            warn!("Outbound buffer full, dropping message to avoid blocking loop.");
+            false
+        }
+        Err(e) => {
+            error!("Outbound channel closed: {}", e);
+            false
+        }
+    }
+}
+
+// Sends `sentence_start` and streams the sentence's audio paced at playback speed.
+// Returns false if the connection is gone.
+async fn speak_sentence(
+    state: &AppState,
+    tx: &Sender<Outbound>,
+    echo: &EchoGuard,
+    audio: &SessionAudio,
+    text: &str,
+    emotion: Option<&str>,
+    language: Option<&str>,
+) -> bool {
+    let tts_sentence = ServerMessage::Tts {
+        state: "sentence_start".to_string(),
+        text: Some(text.to_string()),
+    };
+    if !send_message_safe(tx, Outbound::Message(tts_sentence)).await {
+        return false;
+    }
+    if let Err(e) = echo.record_text(text) {
+        error!("Failed to record speech for echo detection: {}", e);
+    }
+
+    let mut total_frames = 0;
+    let frame_duration = audio.downlink.frame_interval();
+    let cache_frame_count = 2;
+
+    let speech = match state.tts.speak(text, emotion, language).await {
+        Ok(speech) => speech,
+        Err(e) => {
+            error!("TTS Error: {}", e);
+            return true;
+        }
+    };
+    let profile = state
+        .config
+        .audio
+        .opus
+        .profile(&state.config.tts.opus_profile);
+    match encode_downlink(&speech, &audio.downlink, profile) {
+        Ok(frames) => {
+            let start_time = Instant::now();
+            // What we send, at the rate echo detection compares with the microphone
+            let reference = StreamFormat {
+                sample_rate: 16000,
+                ..audio.downlink
+            };
+            let reference_pcm = if echo.wants_audio() {
+                resample(&speech.pcm, speech.sample_rate, reference.sample_rate)
+            } else {
+                Vec::new()
+            };
+            let mut reference_chunks = reference_pcm.chunks(reference.frame_samples());
+            info!("Sending {} audio frames (paced)", frames.len());
+            for frame in frames {
+                // Flow control: Sliding window
+                if total_frames >= cache_frame_count {
+                    let target_time =
+                        start_time + frame_duration * (total_frames - cache_frame_count) as u32;
+                    let now = Instant::now();
+                    if target_time > now {
+                        tokio::time::sleep(target_time - now).await;
+                    }
+                }
+
+                let played_at = start_time + frame_duration * total_frames as u32;
+                let recorded = match reference_chunks.next() {
+                    Some(pcm) => echo.record_playback(played_at, pcm),
+                    None => Ok(()),
+                }
+                .and_then(|_| echo.extend_playback(played_at + frame_duration));
+                if let Err(e) = recorded {
+                    error!("Failed to record playback for echo detection: {}", e);
+                }
+
+                // Devices echo this back on microphone packets captured during playback
+                let packet = BinaryPacket::opus(frame, Some(echo.timestamp(played_at)));
+                if !send_message_safe(tx, Outbound::Audio(packet)).await {
+                    warn!("Failed to send audio frame");
+                    return false;
+                }
+                total_frames += 1;
+            }
+            info!("Finished sending audio frames");
+
+            let total_duration = frame_duration * total_frames as u32;
+            let elapsed = start_time.elapsed();
+            if total_duration > elapsed {
+                tokio::time::sleep(total_duration - elapsed).await;
+            }
+        }
+        Err(e) => error!("Opus encode error: {}", e),
+    }
+    true
+}
+
+/// A finished LLM reply to one user turn.
+pub(crate) struct Reply {
+    /// Cleaned text, still carrying `[lang:xx]` tags for voice selection
+    pub speech_text: String,
+    pub display_text: String,
+    pub emotion: Option<String>,
+    pub should_sleep: bool,
+}
+
+/// Runs one user turn through the LLM, executing MCP tool calls on the device
+/// through `mcp_rpc_tx` when given, and stores the exchange in chat history.
+pub(crate) async fn generate_reply(
+    state: &AppState,
+    text: &str,
+    language: Option<&str>,
+    device_id: &str,
+    mcp_tools: Option<Vec<ToolDefinition>>,
+    mcp_rpc_tx: Option<&Sender<RpcCall>>,
+) -> Option<Reply> {
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    info!("Processing text: {}", text);
+
+    let mut messages = match state
+        .db
+        .get_chat_history(device_id, state.history_limit)
+        .await
+    {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to fetch chat history: {}", e);
+            Vec::new()
+        }
+    };
+
+    // Tell the LLM which language was heard; the plain text is what goes into history
+    let content = match language {
+        Some(lang) if state.config.llm.auto_language => format!("[lang:{}] {}", lang, text),
+        _ => text.to_string(),
+    };
+    messages.push(crate::traits::Message {
+        role: "user".to_string(),
+        content,
+        tool_calls: vec![],
+        tool_call_id: None,
+    });
+
+    let mut loop_count = 0;
+    const MAX_LOOPS: i32 = 5;
+
+    loop {
+        if loop_count >= MAX_LOOPS {
+            error!("Too many tool loops. Breaking.");
+            return None;
+        }
+        loop_count += 1;
+
+        // Use mcp_tools in chat request
+        match state.llm.chat(messages.clone(), mcp_tools.clone()).await {
+            Ok(chat_response) => {
+                match chat_response {
+                    ChatResponse::Text(response_text) => {
+                        info!("LLM Response: {}", response_text);
+
+                        let should_sleep = response_text.contains("[SLEEP]");
+                        let raw_response = response_text.replace("[SLEEP]", "").trim().to_string();
+
+                        let (clean_text, emotion) = clean_text_and_extract_emotion(&raw_response);
+                        let display_text = strip_language_tags(&clean_text);
+
+                        if !display_text.is_empty() {
+                            let _ = state.db.add_chat_history(device_id, "user", text).await;
+                            let _ = state
+                                .db
+                                .add_chat_history(device_id, "model", &display_text)
+                                .await;
+                        }
+
+                        return Some(Reply {
+                            speech_text: clean_text,
+                            display_text,
+                            emotion,
+                            should_sleep,
+                        });
+                    }
+                    ChatResponse::ToolCall(tool_calls) => {
+                        info!("LLM requested tool calls: {:?}", tool_calls);
+
+                        // Append the assistant's tool call message to history
+                        messages.push(crate::traits::Message {
+                            role: "assistant".to_string(),
+                            content: "".to_string(),
+                            tool_calls: tool_calls.clone(),
+                            tool_call_id: None,
+                        });
+
+                        let Some(mcp_rpc_tx) = mcp_rpc_tx else {
+                            error!("LLM requested tools, but no device is connected to run them.");
+                            return None;
+                        };
+
+                        for call in tool_calls {
+                            let (resp_tx, resp_rx) = oneshot::channel();
+
+                            debug!(
+                                "[MCP CALL] Requesting tool execution: {}",
+                                call.function.name
+                            );
+                            // Send execution request to Main Loop
+                            if let Err(e) = mcp_rpc_tx.send(RpcCall {
+                                method: "tools/call".to_string(),
+                                params: json!({
+                                    "name": call.function.name,
+                                    "arguments": serde_json::from_str::<Value>(&call.function.arguments).unwrap_or(json!({}))
+                                }),
+                                resp_tx
+                            }).await {
+                                error!("Failed to send tool call to main loop: {}", e);
+                                return None;
+                            }
+
+                            // Wait for result
+                            let result_json = match resp_rx.await {
+                                Ok(Ok(val)) => val,
+                                Ok(Err(e)) => {
+                                    error!("Tool execution error: {}", e);
+                                    // Should we feed error back to LLM? Yes.
+                                    json!({ "error": e })
+                                }
+                                Err(_) => {
+                                    error!("RPC channel closed");
+                                    return None;
+                                }
+                            };
+
+                            info!("Tool execution result: {:?}", result_json);
+                            debug!("[MCP RESULT] {:?}", result_json);
+
+                            // Format result content.
+                            // The device returns { content: [{type:text, text: "..."}], isError: false }
+                            // We need to extract the text content to feed back to LLM.
+                            let mut tool_output = String::new();
+                            if let Some(content_array) =
+                                result_json.get("content").and_then(|v| v.as_array())
+                            {
+                                for c in content_array {
+                                    if let Some(t) = c.get("text").and_then(|v| v.as_str()) {
+                                        tool_output.push_str(t);
+                                    }
+                                }
+                            } else if let Some(err) = result_json.get("error") {
+                                tool_output = format!("Error: {:?}", err);
+                            } else {
+                                tool_output = result_json.to_string();
+                            }
+
+                            messages.push(crate::traits::Message {
+                                role: "tool".to_string(),
+                                content: tool_output,
+                                tool_calls: vec![],
+                                tool_call_id: Some(call.id.clone()),
+                            });
+                        }
+                        // Loop continues to feed result back to LLM
+                    }
+                }
+            }
+            Err(e) => {
+                error!("LLM Error: {}", e);
+                return None;
+            }
+        }
+    }
+}
+
+// Returns true if sleep is requested
+pub(super) async fn process_text_logic(
+    state: &AppState,
+    tx: &Sender<Outbound>,
+    echo: &EchoGuard,
+    audio: &SessionAudio,
+    request: LlmRequest,
+    device_id: &str,
+    mcp_rpc_tx: &Sender<RpcCall>,
+) -> bool {
+    let language = request.language.as_deref();
+    let Some(reply) = generate_reply(
+        state,
+        &request.text,
+        language,
+        device_id,
+        request.tools,
+        Some(mcp_rpc_tx),
+    )
+    .await
+    else {
+        return false;
+    };
+
+    // Only send text if it's not empty, to avoid empty bubbles
+    if !reply.display_text.is_empty() {
+        let llm_msg = ServerMessage::Llm {
+            emotion: reply.emotion.clone().or(Some("happy".to_string())),
+            text: Some(reply.display_text.clone()),
+        };
+        if !send_message_safe(tx, Outbound::Message(llm_msg)).await {
+            return false;
+        }
+
+        if request.speak {
+            let tts_start = ServerMessage::Tts {
+                state: "start".to_string(),
+                text: None,
+            };
+            if !send_message_safe(tx, Outbound::Message(tts_start)).await {
+                return false;
+            }
+
+            // Speak each language run with its own voice
+            for segment in split_language_segments(&reply.speech_text, language) {
+                if !speak_sentence(
+                    state,
+                    tx,
+                    echo,
+                    audio,
+                    &segment.text,
+                    reply.emotion.as_deref(),
+                    segment.language.as_deref(),
+                )
+                .await
+                {
+                    return false; // Abort
+                }
+            }
+            // Ensure client buffer plays out
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            let tts_stop = ServerMessage::Tts {
+                state: "stop".to_string(),
+                text: None,
+            };
+            if !send_message_safe(tx, Outbound::Message(tts_stop)).await {
+                return false;
+            }
+            info!("Sent TTS Stop command");
+        }
+    }
+
+    if reply.should_sleep {
+        info!("LLM requested sleep. Closing connection.");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        return true;
+    }
+
+    false
+}
+
+// Drops the wake word (and the punctuation around it) from the start of a transcript
+pub(super) fn strip_wake_word(text: &str, wake_word: &str) -> String {
+    let wanted: Vec<char> = wake_word
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    if wanted.is_empty() {
+        return text.to_string();
+    }
+
+    let mut matched = 0;
+    for (i, c) in text.char_indices() {
+        if !c.is_alphanumeric() {
+            continue;
+        }
+        if c.to_lowercase().next() != Some(wanted[matched]) {
+            return text.to_string();
+        }
+        matched += 1;
+        if matched == wanted.len() {
+            return text[i + c.len_utf8()..]
+                .trim_start_matches(|c: char| !c.is_alphanumeric())
+                .to_string();
+        }
+    }
+    // The transcript is only (part of) the wake word
+    String::new()
+}
+
+// Asks the LLM for a short greeting after a wake word. Nothing is written to history.
+pub(super) async fn generate_greeting(
+    state: &AppState,
+    device_id: &str,
+    wake_word: &str,
+) -> Option<String> {
+    let mut messages = state
+        .db
+        .get_chat_history(device_id, state.history_limit)
+        .await
+        .unwrap_or_default();
+    messages.push(crate::traits::Message {
+        role: "user".to_string(),
+        content: format!(
+            "(The user just woke you up by saying \"{}\". Greet them in one short sentence and ask how you can help.)",
+            wake_word
+        ),
+        tool_calls: vec![],
+        tool_call_id: None,
+    });
+
+    match state.llm.chat(messages, None).await {
+        Ok(ChatResponse::Text(response_text)) => {
+            let (clean_text, _) =
+                clean_text_and_extract_emotion(&response_text.replace("[SLEEP]", ""));
+            let greeting = strip_language_tags(&clean_text);
+            (!greeting.is_empty()).then_some(greeting)
+        }
+        Ok(ChatResponse::ToolCall(_)) => None,
+        Err(e) => {
+            error!("Failed to generate greeting: {}", e);
+            None
+        }
+    }
+}
+
+pub(super) async fn trigger_tts_only(
+    state: &AppState,
+    tx: &Sender<Outbound>,
+    echo: &EchoGuard,
+    audio: &SessionAudio,
+    text: &str,
+) {
+    info!("Triggering TTS only: {}", text);
+    let tts_start = ServerMessage::Tts {
+        state: "start".to_string(),
+        text: None,
+    };
+    if !send_message_safe(tx, Outbound::Message(tts_start)).await {
+        return;
+    }
+
+    for segment in split_language_segments(text, None) {
+        if !speak_sentence(
+            state,
+            tx,
+            echo,
+            audio,
+            &segment.text,
+            None,
+            segment.language.as_deref(),
+        )
+        .await
+        {
+            return;
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let tts_stop = ServerMessage::Tts {
+        state: "stop".to_string(),
+        text: None,
+    };
+    let _ = send_message_safe(tx, Outbound::Message(tts_stop)).await;
+}
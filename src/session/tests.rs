@@ -0,0 +1,745 @@
+// Drives `Session` through scripted transport, STT and LLM events on a paused
+// clock, checking what the device would see.
+
+use async_trait::async_trait;
+use futures_util::stream::{BoxStream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::config::{OpusEncoderSettings, ServerConfig};
+use crate::services::audio::opus_codec::{OpusService, StreamFormat};
+use crate::services::binary_protocol::BinaryPacket;
+use crate::services::db::memory::InMemoryDb;
+use crate::services::firmware::FirmwareRepository;
+use crate::services::rate_limit::FailureLimiter;
+use crate::session::events::{Inbound, Outbound};
+use crate::session::messages::{AudioParams, ClientMessage, ServerMessage};
+use crate::session::Session;
+use crate::state::AppState;
+use crate::traits::{
+    ChatResponse, LlmTrait, Message, Speech, SttEvent, SttTrait, ToolCall, ToolDefinition,
+    ToolFunction, TtsTrait,
+};
+
+const BASE_SETTINGS: &str = r#"
+[server]
+port = 8002
+host = "127.0.0.1"
+
+[auth]
+enable = false
+signature_key = "test"
+
+[ota]
+firmware_version = "1.0.0"
+
+[ota.mqtt]
+enable = false
+endpoint = "mqtt://localhost"
+
+[llm]
+provider = "scripted"
+
+[stt]
+provider = "scripted"
+
+[tts]
+provider = "scripted"
+
+[chat]
+max_idle_duration = 3600000
+"#;
+
+pub(crate) fn test_config(overrides: &str) -> ServerConfig {
+    config::Config::builder()
+        .add_source(config::File::from_str(
+            BASE_SETTINGS,
+            config::FileFormat::Toml,
+        ))
+        .add_source(config::File::from_str(overrides, config::FileFormat::Toml))
+        .build()
+        .and_then(|c| c.try_deserialize())
+        .expect("Invalid test settings")
+}
+
+// One LLM call as the session made it
+#[derive(Debug, Clone)]
+struct LlmCall {
+    // Content of the last message: the user turn or a tool result
+    last: String,
+    has_tools: bool,
+}
+
+// Answers from a script, taking `latency` per call
+struct ScriptedLlm {
+    replies: Mutex<VecDeque<ChatResponse>>,
+    latency: Duration,
+    calls: Mutex<Vec<LlmCall>>,
+}
+
+#[async_trait]
+impl LlmTrait for ScriptedLlm {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        tools: Option<Vec<ToolDefinition>>,
+    ) -> anyhow::Result<ChatResponse> {
+        self.calls.lock().unwrap().push(LlmCall {
+            last: messages
+                .last()
+                .map(|m| m.content.clone())
+                .unwrap_or_default(),
+            has_tools: tools.is_some_and(|t| !t.is_empty()),
+        });
+        tokio::time::sleep(self.latency).await;
+        let reply = self.replies.lock().unwrap().pop_front();
+        Ok(reply.unwrap_or_else(|| ChatResponse::Text("好的".to_string())))
+    }
+}
+
+// Hands out test-controlled events and counts the samples it was fed
+struct ScriptedStt {
+    events: Mutex<Option<mpsc::Receiver<SttEvent>>>,
+    heard: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl SttTrait for ScriptedStt {
+    async fn recognize(&self, _audio: &[u8]) -> anyhow::Result<String> {
+        Ok(String::new())
+    }
+
+    async fn stream_speech(
+        &self,
+        mut input_stream: BoxStream<'static, Vec<i16>>,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<SttEvent>>> {
+        let heard = self.heard.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = input_stream.next().await {
+                heard.fetch_add(chunk.len(), Ordering::SeqCst);
+            }
+        });
+        let events = self
+            .events
+            .lock()
+            .unwrap()
+            .take()
+            .expect("One stream per test");
+        Ok(Box::pin(ReceiverStream::new(events).map(Ok)))
+    }
+}
+
+// Three frames per sentence; the bytes are never decoded
+struct SilentTts;
+
+#[async_trait]
+impl TtsTrait for SilentTts {
+    async fn speak(
+        &self,
+        _text: &str,
+        _emotion: Option<&str>,
+        _language: Option<&str>,
+    ) -> anyhow::Result<Speech> {
+        // Three 60ms packets at the default downlink format
+        Ok(Speech {
+            pcm: vec![0; 2880],
+            sample_rate: 16000,
+        })
+    }
+}
+
+/// App state for transport tests: the LLM answers "好的", TTS speaks 180ms of
+/// silence, and STT emits what is sent on `stt` and counts what it hears.
+pub(crate) struct ScriptedServices {
+    pub state: AppState,
+    pub stt: mpsc::Sender<SttEvent>,
+    pub heard: Arc<AtomicUsize>,
+}
+
+pub(crate) fn scripted_services(overrides: &str) -> ScriptedServices {
+    scripted_state(overrides, Vec::new(), Duration::ZERO).0
+}
+
+fn scripted_state(
+    overrides: &str,
+    replies: Vec<ChatResponse>,
+    latency: Duration,
+) -> (ScriptedServices, Arc<ScriptedLlm>) {
+    let (stt_tx, stt_rx) = mpsc::channel(32);
+    let heard = Arc::new(AtomicUsize::new(0));
+    let llm = Arc::new(ScriptedLlm {
+        replies: Mutex::new(replies.into()),
+        latency,
+        calls: Mutex::new(Vec::new()),
+    });
+    let config = test_config(overrides);
+    let state = AppState {
+        firmware: Arc::new(FirmwareRepository::new(&config.ota.firmware.dir)),
+        bind_limiter: Arc::new(FailureLimiter::new(5, Duration::from_secs(600))),
+        history_limit: config.llm.history_limit,
+        config: Arc::new(config),
+        db: Arc::new(InMemoryDb::new()),
+        llm: llm.clone(),
+        stt: Arc::new(ScriptedStt {
+            events: Mutex::new(Some(stt_rx)),
+            heard: heard.clone(),
+        }),
+        tts: Arc::new(SilentTts),
+    };
+    let services = ScriptedServices {
+        state,
+        stt: stt_tx,
+        heard,
+    };
+    (services, llm)
+}
+
+struct Harness {
+    inbound: mpsc::Sender<Inbound>,
+    outbound: mpsc::Receiver<Outbound>,
+    stt: mpsc::Sender<SttEvent>,
+    llm: Arc<ScriptedLlm>,
+    heard: Arc<AtomicUsize>,
+    session_id: String,
+}
+
+impl Harness {
+    fn start(overrides: &str, replies: Vec<ChatResponse>, latency: Duration) -> Self {
+        let (services, llm) = scripted_state(overrides, replies, latency);
+
+        let (inbound_tx, inbound_rx) = mpsc::channel(32);
+        let (outbound_tx, outbound_rx) = mpsc::channel(256);
+        let session = Session::new(
+            services.state,
+            "test-device".to_string(),
+            "websocket",
+            outbound_tx,
+        );
+        tokio::spawn(session.run(Box::pin(ReceiverStream::new(inbound_rx))));
+
+        Self {
+            inbound: inbound_tx,
+            outbound: outbound_rx,
+            stt: services.stt,
+            llm,
+            heard: services.heard,
+            session_id: "test-session".to_string(),
+        }
+    }
+
+    async fn send(&self, message: ClientMessage) {
+        self.inbound.send(Inbound::Message(message)).await.unwrap();
+        settle().await;
+    }
+
+    async fn hello(&self, mcp: bool) {
+        self.send(ClientMessage::Hello {
+            version: 1,
+            transport: "websocket".to_string(),
+            audio_params: AudioParams {
+                format: "opus".to_string(),
+                sample_rate: 16000,
+                channels: 1,
+                frame_duration: 60,
+            },
+            features: Some(json!({ "mcp": mcp })),
+        })
+        .await;
+    }
+
+    async fn listen(&self, state: &str, mode: Option<&str>, text: Option<&str>) {
+        self.send(ClientMessage::Listen {
+            session_id: self.session_id.clone(),
+            state: state.to_string(),
+            mode: mode.map(str::to_string),
+            text: text.map(str::to_string),
+            text_only: false,
+        })
+        .await;
+    }
+
+    async fn stt(&self, event: SttEvent) {
+        self.stt.send(event).await.unwrap();
+        settle().await;
+    }
+
+    async fn transcript(&self, text: &str) {
+        self.stt(SttEvent::Text {
+            text: text.to_string(),
+            language: Some("zh".to_string()),
+        })
+        .await;
+    }
+
+    // One 60ms packet of a 440Hz tone from the device's microphone
+    async fn speak_into_mic(&self) {
+        let pcm: Vec<i16> = (0..960)
+            .map(|i| ((i as f32 * 440.0 * std::f32::consts::TAU / 16000.0).sin() * 3000.0) as i16)
+            .collect();
+        let frames = OpusService::encode_frames_with(
+            &pcm,
+            &StreamFormat::default(),
+            &OpusEncoderSettings::default(),
+        )
+        .unwrap();
+        for frame in frames {
+            self.inbound
+                .send(Inbound::Audio(BinaryPacket::opus(frame, None)))
+                .await
+                .unwrap();
+        }
+        settle().await;
+    }
+
+    // Samples the session has passed to STT so far
+    fn heard(&self) -> usize {
+        self.heard.load(Ordering::SeqCst)
+    }
+
+    fn llm_calls(&self) -> Vec<LlmCall> {
+        self.llm.calls.lock().unwrap().clone()
+    }
+
+    // The next message for the device, skipping audio; lets the clock run
+    async fn next_message(&mut self) -> ServerMessage {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(300), self.outbound.recv())
+                .await
+                .expect("No message from the session")
+                .expect("Session ended");
+            match event {
+                Outbound::Message(message) => return message,
+                Outbound::Audio(_) => {}
+                Outbound::Close => panic!("Session closed"),
+            }
+        }
+    }
+
+    // Reads messages until `tts stop`, returning the sentences spoken
+    async fn until_tts_stop(&mut self) -> Vec<String> {
+        let mut sentences = Vec::new();
+        loop {
+            match self.next_message().await {
+                ServerMessage::Tts { state, text } if state == "sentence_start" => {
+                    sentences.push(text.unwrap_or_default())
+                }
+                ServerMessage::Tts { state, .. } if state == "stop" => break,
+                _ => {}
+            }
+        }
+        // Let the reply task report back to the session
+        settle().await;
+        sentences
+    }
+
+    // Whatever is queued for the device right now, without moving the clock
+    fn pending_messages(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(event) = self.outbound.try_recv() {
+            if let Outbound::Message(message) = event {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+}
+
+// Runs every ready task without moving the paused clock
+async fn settle() {
+    for _ in 0..100 {
+        tokio::task::yield_now().await;
+    }
+}
+
+fn mcp_payload(message: &ServerMessage) -> Option<&Value> {
+    match message {
+        ServerMessage::Mcp { payload, .. } => Some(payload),
+        _ => None,
+    }
+}
+
+fn tool_call(name: &str) -> ChatResponse {
+    ChatResponse::ToolCall(vec![ToolCall {
+        id: "call-1".to_string(),
+        type_: "function".to_string(),
+        function: ToolFunction {
+            name: name.to_string(),
+            arguments: "{}".to_string(),
+        },
+    }])
+}
+
+#[tokio::test(start_paused = true)]
+async fn turn_moves_from_listening_to_processing_to_speaking_and_back() {
+    let mut h = Harness::start("", vec![], Duration::from_secs(1));
+    h.listen("start", Some("auto"), None).await;
+
+    // Listening: microphone audio goes to STT
+    h.speak_into_mic().await;
+    let heard_listening = h.heard();
+    assert!(heard_listening > 0);
+
+    h.transcript("今天天氣如何").await;
+    h.stt(SttEvent::NoSpeech).await;
+    assert_eq!(h.llm_calls().len(), 1);
+    assert_eq!(h.llm_calls()[0].last.trim(), "今天天氣如何");
+
+    // Processing: audio is held back instead of transcribed
+    h.speak_into_mic().await;
+    assert_eq!(h.heard(), heard_listening);
+
+    // Speaking
+    loop {
+        if let ServerMessage::Tts { state, .. } = h.next_message().await {
+            if state == "start" {
+                break;
+            }
+        }
+    }
+    h.speak_into_mic().await;
+    assert_eq!(h.heard(), heard_listening);
+
+    // Back to listening: the handover audio is replayed, new audio goes to STT
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    let heard_after_handover = h.heard();
+    assert!(heard_after_handover > heard_listening);
+    h.speak_into_mic().await;
+    assert!(h.heard() > heard_after_handover);
+}
+
+#[tokio::test(start_paused = true)]
+async fn audio_held_while_processing_reaches_stt_once_listening_resumes() {
+    // Each mic burst is 960 samples; the handover keeps the last 1920
+    let mut h = Harness::start("[vad]\nhandover_ms = 120\n", vec![], Duration::from_secs(1));
+    h.listen("start", Some("auto"), None).await;
+    h.transcript("今天天氣如何").await;
+    h.stt(SttEvent::NoSpeech).await;
+    let heard = h.heard();
+
+    for _ in 0..3 {
+        h.speak_into_mic().await;
+    }
+    assert_eq!(h.heard(), heard);
+
+    // LlmFinished forwards the newest handover window, not all of it
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    settle().await;
+    assert_eq!(h.heard(), heard + 1920);
+}
+
+#[tokio::test(start_paused = true)]
+async fn listen_start_during_a_reply_forwards_the_pre_roll() {
+    let h = Harness::start("[vad]\npre_roll_ms = 60\n", vec![], Duration::from_secs(1));
+    h.listen("start", Some("auto"), None).await;
+    h.transcript("今天天氣如何").await;
+    h.stt(SttEvent::NoSpeech).await;
+    let heard = h.heard();
+
+    h.speak_into_mic().await;
+    h.speak_into_mic().await;
+    assert_eq!(h.heard(), heard);
+
+    // Pressing the button mid-reply keeps only the last 60ms of what was said
+    h.listen("start", Some("manual"), None).await;
+    settle().await;
+    assert_eq!(h.heard(), heard + 960);
+}
+
+#[tokio::test(start_paused = true)]
+async fn wake_word_greeting_waits_for_the_grace_period() {
+    let settings =
+        "[chat]\nwake_reply = \"fixed\"\nwake_greeting = \"我在\"\nwake_grace_ms = 1500\n";
+    let mut h = Harness::start(settings, vec![], Duration::ZERO);
+    h.listen("detect", None, Some("你好小智")).await;
+
+    tokio::time::advance(Duration::from_millis(1400)).await;
+    settle().await;
+    assert!(h.pending_messages().is_empty());
+
+    tokio::time::advance(Duration::from_millis(200)).await;
+    settle().await;
+    assert_eq!(h.until_tts_stop().await, vec!["我在"]);
+    assert!(h.llm_calls().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn speech_after_the_wake_word_replaces_the_greeting() {
+    let settings =
+        "[chat]\nwake_reply = \"fixed\"\nwake_greeting = \"我在\"\nwake_grace_ms = 1500\n";
+    let mut h = Harness::start(settings, vec![], Duration::ZERO);
+    h.listen("detect", None, Some("你好小智")).await;
+
+    tokio::time::advance(Duration::from_millis(500)).await;
+    h.transcript("你好小智，打開客廳的燈").await;
+    h.stt(SttEvent::NoSpeech).await;
+
+    // One turn without the wake word, and no greeting
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].last.trim(), "打開客廳的燈");
+
+    tokio::time::advance(Duration::from_secs(5)).await;
+    settle().await;
+    assert!(h.pending_messages().is_empty());
+}
+
+// Answers the initialize and tools/list requests with one `light_on` tool
+async fn complete_mcp_handshake(h: &mut Harness) {
+    let initialize = h.next_message().await;
+    let initialize = mcp_payload(&initialize).expect("Expected MCP initialize");
+    assert_eq!(initialize["method"], "initialize");
+    h.send(ClientMessage::Mcp {
+        payload: json!({ "jsonrpc": "2.0", "id": initialize["id"], "result": {} }),
+        session_id: None,
+    })
+    .await;
+
+    let list = h.next_message().await;
+    let list = mcp_payload(&list).expect("Expected MCP tools/list");
+    assert_eq!(list["method"], "tools/list");
+    h.send(ClientMessage::Mcp {
+        payload: json!({
+            "jsonrpc": "2.0",
+            "id": list["id"],
+            "result": { "tools": [{
+                "name": "light_on",
+                "description": "Turn on the light",
+                "inputSchema": { "type": "object", "properties": {} }
+            }] }
+        }),
+        session_id: None,
+    })
+    .await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn mcp_tool_call_round_trip() {
+    let mut h = Harness::start("", vec![tool_call("light_on")], Duration::ZERO);
+    h.hello(true).await;
+    assert!(matches!(
+        h.next_message().await,
+        ServerMessage::Hello { .. }
+    ));
+    complete_mcp_handshake(&mut h).await;
+
+    h.listen("text", None, Some("開燈")).await;
+    let call = loop {
+        let message = h.next_message().await;
+        if let Some(payload) = mcp_payload(&message) {
+            break payload.clone();
+        }
+    };
+    assert_eq!(call["method"], "tools/call");
+    assert_eq!(call["params"]["name"], "light_on");
+    h.send(ClientMessage::Mcp {
+        payload: json!({
+            "jsonrpc": "2.0",
+            "id": call["id"],
+            "result": { "content": [{ "type": "text", "text": "light is on" }], "isError": false }
+        }),
+        session_id: None,
+    })
+    .await;
+
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[0].has_tools);
+    assert_eq!(calls[1].last, "light is on");
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_mcp_tool_call_times_out() {
+    let mut h = Harness::start("", vec![tool_call("light_on")], Duration::ZERO);
+    h.hello(true).await;
+    assert!(matches!(
+        h.next_message().await,
+        ServerMessage::Hello { .. }
+    ));
+    complete_mcp_handshake(&mut h).await;
+
+    h.listen("text", None, Some("開燈")).await;
+    let asked_at = Instant::now();
+    // The device never answers the tools/call; the reply still arrives
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    assert!(asked_at.elapsed() >= Duration::from_secs(30));
+
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[1].last.contains("did not answer"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_mcp_handshake_times_out_without_tools() {
+    let mut h = Harness::start("", vec![], Duration::ZERO);
+    h.hello(true).await;
+    assert!(matches!(
+        h.next_message().await,
+        ServerMessage::Hello { .. }
+    ));
+    let initialize = h.next_message().await;
+    let id = mcp_payload(&initialize).expect("Expected MCP initialize")["id"].clone();
+
+    tokio::time::advance(Duration::from_secs(31)).await;
+    settle().await;
+
+    // A late answer does not restart the handshake
+    h.send(ClientMessage::Mcp {
+        payload: json!({ "jsonrpc": "2.0", "id": id, "result": {} }),
+        session_id: None,
+    })
+    .await;
+    assert!(h.pending_messages().is_empty());
+
+    h.listen("text", None, Some("你好")).await;
+    h.until_tts_stop().await;
+    assert!(!h.llm_calls()[0].has_tools);
+}
+
+#[tokio::test(start_paused = true)]
+async fn auto_mode_ends_the_turn_on_silence() {
+    let mut h = Harness::start("", vec![], Duration::ZERO);
+    h.listen("start", Some("auto"), None).await;
+    h.transcript("現在幾點").await;
+    assert!(h.llm_calls().is_empty());
+
+    h.stt(SttEvent::NoSpeech).await;
+    assert_eq!(h.llm_calls().len(), 1);
+    assert_eq!(h.llm_calls()[0].last.trim(), "現在幾點");
+    h.until_tts_stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn manual_mode_waits_for_listen_stop() {
+    let mut h = Harness::start("", vec![], Duration::ZERO);
+    h.listen("start", Some("manual"), None).await;
+    h.transcript("幫我設一個").await;
+    h.stt(SttEvent::NoSpeech).await;
+    h.transcript("明天早上七點的鬧鐘").await;
+    h.stt(SttEvent::NoSpeech).await;
+    assert!(h.llm_calls().is_empty());
+
+    h.listen("stop", None, None).await;
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].last.trim(), "幫我設一個 明天早上七點的鬧鐘");
+    h.until_tts_stop().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn manual_mode_submits_the_transcript_that_arrives_after_listen_stop() {
+    let mut h = Harness::start("", vec![], Duration::ZERO);
+    h.listen("start", Some("manual"), None).await;
+    h.stt(SttEvent::SpeechStart).await;
+    h.speak_into_mic().await;
+    let heard = h.heard();
+
+    // STT only flushes once the audio has ended; silence helps it get there
+    h.listen("stop", None, None).await;
+    assert!(h.heard() > heard);
+    assert!(h.llm_calls().is_empty());
+
+    h.transcript("明天早上七點叫我").await;
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].last.trim(), "明天早上七點叫我");
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn listen_stop_without_speech_keeps_listening() {
+    let mut h = Harness::start("", vec![], Duration::ZERO);
+    h.listen("start", Some("manual"), None).await;
+    h.listen("stop", None, None).await;
+    h.stt(SttEvent::NoSpeech).await;
+    assert!(h.llm_calls().is_empty());
+    assert!(h.pending_messages().is_empty());
+
+    // Not stuck processing: the next push-to-talk turn goes through
+    h.listen("start", Some("manual"), None).await;
+    h.transcript("現在幾點").await;
+    h.stt(SttEvent::NoSpeech).await;
+    h.listen("stop", None, None).await;
+    assert_eq!(h.llm_calls().len(), 1);
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn realtime_mode_keeps_listening_and_barges_in() {
+    let mut h = Harness::start("", vec![], Duration::from_secs(2));
+    h.listen("start", Some("realtime"), None).await;
+    h.transcript("講個故事").await;
+    h.stt(SttEvent::NoSpeech).await;
+    assert_eq!(h.llm_calls().len(), 1);
+
+    // Still transcribing while the reply is on its way
+    let heard = h.heard();
+    h.speak_into_mic().await;
+    assert!(h.heard() > heard);
+
+    // The user talks over it: the first reply stops, the new turn goes out
+    h.stt(SttEvent::SpeechStart).await;
+    h.transcript("算了，講個笑話").await;
+    h.stt(SttEvent::NoSpeech).await;
+    let calls = h.llm_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].last.trim(), "算了，講個笑話");
+
+    // The interrupted reply only says stop; the second one is spoken
+    assert!(h.until_tts_stop().await.is_empty());
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn replies_superseded_while_queued_are_never_spoken() {
+    let mut h = Harness::start("", vec![], Duration::from_secs(2));
+    // Three typed turns back to back, before the reply task gets to run
+    for text in ["第一個問題", "第二個問題", "第三個問題"] {
+        h.inbound
+            .send(Inbound::Message(ClientMessage::Listen {
+                session_id: h.session_id.clone(),
+                state: "text".to_string(),
+                mode: None,
+                text: Some(text.to_string()),
+                text_only: false,
+            }))
+            .await
+            .unwrap();
+    }
+    settle().await;
+
+    let mut spoken = h.until_tts_stop().await;
+    while spoken.is_empty() {
+        spoken = h.until_tts_stop().await;
+    }
+    assert_eq!(spoken, vec!["好的"]);
+    let calls = h.llm_calls();
+    assert_eq!(calls.last().unwrap().last.trim(), "第三個問題");
+    assert!(calls.iter().all(|c| c.last.trim() != "第二個問題"));
+
+    // Nothing else is queued behind it
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    assert!(h.pending_messages().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn interrupted_turn_does_not_end_the_next_one() {
+    let mut h = Harness::start("", vec![], Duration::from_secs(2));
+    h.listen("text", None, Some("第一個問題")).await;
+    // A second typed turn cuts the first one short
+    h.listen("text", None, Some("第二個問題")).await;
+    assert!(h.until_tts_stop().await.is_empty());
+
+    // Still processing the second turn: microphone audio is not a new turn
+    h.speak_into_mic().await;
+    assert_eq!(h.heard(), 0);
+
+    assert_eq!(h.until_tts_stop().await, vec!["好的"]);
+    assert_eq!(h.llm_calls().len(), 2);
+}
@@ -1,12 +1,19 @@
-use crate::config::ServerConfig;
+use crate::config::{ServerConfig, TtsSettings};
 use crate::services::{
     db::{memory::InMemoryDb, sql::SqlDb},
-    llm::{gemini::GeminiLlm, ollama::OllamaLlm, openai::OpenAiLlm},
-    stt::sensevoice::SenseVoiceStt,
-    tts::{edge::EdgeTts, gemini::GeminiTts, opus::OpusTts},
+    firmware::FirmwareRepository,
+    llm::{gemini::GeminiLlm, ollama::OllamaLlm, openai::OpenAiLlm, LANGUAGE_INSTRUCTION},
+    rate_limit::FailureLimiter,
+    stt::{sensevoice::SenseVoiceStt, vad_gate::VadGatedStt},
+    tts::{
+        cache::CachedTts, edge::EdgeTts, gemini::GeminiTts, openai::OpenAiTts, opus::OpusTts,
+        piper::PiperTts,
+    },
 };
 use crate::traits::{DbTrait, LlmTrait, SttTrait, TtsTrait};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, warn};
 
 #[derive(Clone)]
@@ -16,6 +23,9 @@ pub struct AppState {
     pub llm: Arc<dyn LlmTrait + Send + Sync>,
     pub stt: Arc<dyn SttTrait + Send + Sync>,
     pub tts: Arc<dyn TtsTrait + Send + Sync>,
+    pub firmware: Arc<FirmwareRepository>,
+    // Wrong activation codes per client on the public bind route
+    pub bind_limiter: Arc<FailureLimiter>,
     pub history_limit: usize,
 }
 
@@ -41,7 +51,14 @@ impl AppState {
             _ => Arc::new(InMemoryDb::new()),
         };
 
-        let system_instruction = config.llm.system_instruction.clone();
+        let system_instruction = if config.llm.auto_language {
+            Some(match &config.llm.system_instruction {
+                Some(user_inst) => format!("{} {}", user_inst, LANGUAGE_INSTRUCTION),
+                None => LANGUAGE_INSTRUCTION.to_string(),
+            })
+        } else {
+            config.llm.system_instruction.clone()
+        };
 
         let llm: Arc<dyn LlmTrait + Send + Sync> = match config.llm.provider.as_str() {
             "gemini" => {
@@ -87,7 +104,14 @@ impl AppState {
             }
         };
 
-        let stt = Arc::new(SenseVoiceStt::new(config.vad.clone()));
+        let sense_voice = Arc::new(SenseVoiceStt::new(config.vad.clone()));
+        let stt: Arc<dyn SttTrait + Send + Sync> = match config.vad.provider.as_str() {
+            "silero" => Arc::new(VadGatedStt::new(sense_voice, config.vad.clone())),
+            "sensevoice" => sense_voice,
+            provider => {
+                panic!("Unknown VAD provider: {}", provider);
+            }
+        };
 
         let tts: Arc<dyn TtsTrait + Send + Sync> = match config.tts.provider.as_str() {
             "edge" => {
@@ -97,6 +121,9 @@ impl AppState {
                         edge_config.rate.clone(),
                         edge_config.pitch.clone(),
                         edge_config.volume.clone(),
+                        edge_config.voices.clone(),
+                        edge_config.styles.clone(),
+                        edge_config.style_degree,
                     ))
                 } else {
                     warn!("Edge TTS selected but no specific config found. Using defaults.");
@@ -105,6 +132,9 @@ impl AppState {
                         "+0%".to_string(),
                         "+0Hz".to_string(),
                         "+0%".to_string(),
+                        HashMap::new(),
+                        HashMap::new(),
+                        None,
                     ))
                 }
             }
@@ -123,24 +153,138 @@ impl AppState {
                         api_key,
                         gemini_config.model.clone(),
                         gemini_config.voice_name.clone(),
+                        gemini_config.voices.clone(),
                     ))
                 } else {
                     panic!("Gemini TTS selected but [tts.gemini] config missing.");
                 }
             }
+            "piper" => {
+                if let Some(piper_config) = &config.tts.piper {
+                    match PiperTts::new(piper_config) {
+                        Ok(piper) => Arc::new(piper),
+                        Err(e) => panic!("Failed to initialize Piper TTS: {:#}", e),
+                    }
+                } else {
+                    panic!("Piper TTS selected but [tts.piper] config missing.");
+                }
+            }
+            "openai" => {
+                if let Some(openai_config) = &config.tts.openai {
+                    Arc::new(OpenAiTts::new(openai_config.clone()))
+                } else {
+                    panic!("OpenAI TTS selected but [tts.openai] config missing.");
+                }
+            }
             "opus" => Arc::new(OpusTts::new()),
             &_ => todo!(),
         };
 
+        let tts: Arc<dyn TtsTrait + Send + Sync> = if config.tts.cache.enable {
+            let cache_settings = &config.tts.cache;
+            match CachedTts::new(
+                tts.clone(),
+                tts_fingerprint(&config.tts),
+                cache_settings.dir.clone().into(),
+                cache_settings.max_size_mb * 1024 * 1024,
+                cache_settings.max_text_chars,
+            )
+            .await
+            {
+                Ok(cached) => {
+                    let cached = Arc::new(cached);
+                    let mut prompts = vec![
+                        config.chat.standby_prompt.clone(),
+                        config.chat.wake_greeting.clone(),
+                    ];
+                    prompts.extend(cache_settings.prewarm.iter().cloned());
+                    let prewarm = cached.clone();
+                    tokio::spawn(async move { prewarm.prewarm(&prompts).await });
+                    cached
+                }
+                Err(e) => {
+                    warn!("TTS cache disabled: {}", e);
+                    tts
+                }
+            }
+        } else {
+            tts
+        };
+
         let history_limit = config.llm.history_limit;
 
+        let firmware = Arc::new(FirmwareRepository::new(&config.ota.firmware.dir));
+        if let Err(e) = firmware.reload().await {
+            warn!("Failed to load firmware repository: {}", e);
+        }
+
         Self {
             config: Arc::new(config),
             db,
             llm,
             stt,
             tts,
+            firmware,
+            bind_limiter: Arc::new(FailureLimiter::new(5, Duration::from_secs(600))),
             history_limit,
         }
     }
 }
+
+// Everything besides text, emotion and language that changes the synthesized audio
+fn tts_fingerprint(settings: &TtsSettings) -> String {
+    // Sorted so the fingerprint is stable across runs
+    let sorted = |voices: &HashMap<String, String>| {
+        format!("{:?}", voices.iter().collect::<BTreeMap<_, _>>())
+    };
+    match settings.provider.as_str() {
+        "edge" => match &settings.edge {
+            Some(edge) => format!(
+                "edge|{}|{}|{}|{}|{}|{}|{:?}",
+                edge.voice,
+                edge.rate,
+                edge.pitch,
+                edge.volume,
+                sorted(&edge.voices),
+                sorted(&edge.styles),
+                edge.style_degree
+            ),
+            None => "edge|default".to_string(),
+        },
+        "gemini" => match &settings.gemini {
+            Some(gemini) => format!(
+                "gemini|{}|{}|{}",
+                gemini.model,
+                gemini.voice_name,
+                sorted(&gemini.voices)
+            ),
+            None => "gemini".to_string(),
+        },
+        "piper" => match &settings.piper {
+            Some(piper) => format!(
+                "piper|{}|{:?}|{:?}|{:?}|{:?}|{:?}",
+                piper.model,
+                piper.config,
+                piper.speaker_id,
+                piper.length_scale,
+                piper.noise_scale,
+                piper.noise_w
+            ),
+            None => "piper".to_string(),
+        },
+        "openai" => match &settings.openai {
+            Some(openai) => format!(
+                "openai|{}|{}|{}|{:?}|{}|{}|{}",
+                openai.base_url,
+                openai.model,
+                openai.voice,
+                openai.speed,
+                openai.response_format,
+                openai.pcm_sample_rate,
+                sorted(&openai.voices)
+            ),
+            None => "openai".to_string(),
+        },
+        provider => provider.to_string(),
+    }
+}
@@ -4,10 +4,33 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 pub enum SttEvent {
-    Text(String),
+    Text {
+        text: String,
+        // ISO 639 code detected by the recognizer (e.g. "zh", "en"), if known
+        language: Option<String>,
+    },
+    // The VAD heard the start of an utterance
+    SpeechStart,
     NoSpeech,
 }
 
+// A stretch of a batch transcription, times in seconds
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptSegment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transcription {
+    pub text: String,
+    pub language: Option<String>,
+    pub duration: f64,
+    pub segments: Vec<TranscriptSegment>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolCall {
     pub id: String,
@@ -31,6 +54,45 @@ pub struct Message {
     pub tool_call_id: Option<String>,
 }
 
+// One entry of a device's flash partition table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Partition {
+    pub label: String,
+    #[serde(rename = "type")]
+    pub type_: u32,
+    pub subtype: u32,
+    pub address: u64,
+    pub size: u64,
+}
+
+/// A device as last reported in its OTA request.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceRecord {
+    pub device_id: String,
+    // Kept from the last report before activation; later reports cannot change it
+    pub client_id: String,
+    pub board_type: Option<String>,
+    pub board_name: Option<String>,
+    pub chip_model: Option<String>,
+    pub mac_address: Option<String>,
+    pub flash_size: Option<u64>,
+    pub app_version: Option<String>,
+    pub partition_table: Vec<Partition>,
+    pub ssid: Option<String>,
+    pub rssi: Option<i32>,
+    // Unix seconds, kept by the database
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+// Inventory query; unset fields match everything
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceFilter {
+    pub board: Option<String>,
+    pub firmware_version: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolDefinition {
     pub name: String,
@@ -60,6 +122,33 @@ pub trait SttTrait: Send + Sync {
     // Simplified for now: assume we might process a chunk or a whole buffer.
     async fn recognize(&self, audio: &[u8]) -> anyhow::Result<String>;
 
+    /// Transcribes a complete 16kHz mono recording.
+    ///
+    /// Providers that cannot segment return the whole recording as one segment.
+    async fn transcribe(&self, pcm: &[i16]) -> anyhow::Result<Transcription> {
+        let bytes: Vec<u8> = pcm.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let text = self.recognize(&bytes).await?;
+        let language = crate::services::language::detect_language(&text).map(|l| l.to_string());
+        let duration = pcm.len() as f64 / 16000.0;
+
+        let segments = if text.trim().is_empty() {
+            vec![]
+        } else {
+            vec![TranscriptSegment {
+                start: 0.0,
+                end: duration,
+                text: text.clone(),
+                language: language.clone(),
+            }]
+        };
+        Ok(Transcription {
+            text,
+            language,
+            duration,
+            segments,
+        })
+    }
+
     // New streaming method
     async fn stream_speech(
         &self,
@@ -67,11 +156,24 @@ pub trait SttTrait: Send + Sync {
     ) -> anyhow::Result<BoxStream<'static, anyhow::Result<SttEvent>>>;
 }
 
+/// Synthesized speech: mono PCM at the provider's native rate. Sessions
+/// resample and encode it once, into whatever format the device negotiated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Speech {
+    pub pcm: Vec<i16>,
+    pub sample_rate: u32,
+}
+
 #[async_trait]
 pub trait TtsTrait: Send + Sync {
-    // Returns a list of Opus encoded frames (each frame is a Vec<u8>)
     // emotion: Optional emotion string extracted from text (e.g. "happy", "sad")
-    async fn speak(&self, text: &str, emotion: Option<&str>) -> anyhow::Result<Vec<Vec<u8>>>;
+    // language: Optional language code of the text (e.g. "zh", "en"), used to pick a voice
+    async fn speak(
+        &self,
+        text: &str,
+        emotion: Option<&str>,
+        language: Option<&str>,
+    ) -> anyhow::Result<Speech>;
 }
 
 #[async_trait]
@@ -86,6 +188,31 @@ pub trait DbTrait: Send + Sync {
     ) -> anyhow::Result<()>;
     async fn get_challenge(&self, device_id: &str) -> anyhow::Result<Option<String>>;
 
+    // Six-digit codes the device shows so its owner can bind it
+    async fn get_activation_code(&self, device_id: &str) -> anyhow::Result<Option<String>>;
+    /// `false` if another device holds the same unexpired code.
+    async fn add_activation_code(
+        &self,
+        device_id: &str,
+        code: &str,
+        ttl_secs: u64,
+    ) -> anyhow::Result<bool>;
+    /// Consumes the code, activates its device and records the owner.
+    /// Returns the device id, or `None` for an unknown or expired code.
+    async fn bind_device(&self, code: &str, owner: &str) -> anyhow::Result<Option<String>>;
+
+    // Revoked devices are refused regardless of any token they hold
+    async fn is_revoked(&self, device_id: &str) -> anyhow::Result<bool>;
+    async fn revoke_device(&self, device_id: &str) -> anyhow::Result<()>;
+    async fn restore_device(&self, device_id: &str) -> anyhow::Result<()>;
+
+    /// Records an OTA report. Fields the device left out keep their last
+    /// known value; `first_seen` and `last_seen` are stamped here.
+    async fn upsert_device(&self, device: &DeviceRecord) -> anyhow::Result<()>;
+    async fn get_device(&self, device_id: &str) -> anyhow::Result<Option<DeviceRecord>>;
+    // Most recently seen first
+    async fn list_devices(&self, filter: &DeviceFilter) -> anyhow::Result<Vec<DeviceRecord>>;
+
     async fn add_chat_history(
         &self,
         device_id: &str,